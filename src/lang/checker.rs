@@ -0,0 +1,720 @@
+use crate::expr::{Expr, LiteralValue};
+use crate::scanner::{Token, TokenType};
+use crate::statements::Statement;
+use crate::type_::{Type, TypeAnnotation, TypeParam};
+use crate::diagnostic::{Diagnostic, Span};
+
+use std::collections::HashMap;
+
+struct Variable {
+    ty: Type,
+    /// Unannotated variables take the type of their initializer until something else is assigned.
+    annotated: bool,
+}
+struct ClassInfo {
+    superclass: Option<String>,
+    /// Type parameters with their bounds, replaced by the type arguments of each instance.
+    generics: Vec<(String, Type)>,
+    methods: HashMap<String, Type>,
+}
+/// Checks types before the program runs. Types are inferred from literals, annotations and
+/// the operations between them; anything else is `any`, which is never reported.
+pub struct TypeChecker {
+    /// The first scope holds the globals.
+    scopes: Vec<HashMap<String, Variable>>,
+    classes: HashMap<String, ClassInfo>,
+    /// Type parameters in scope with their bounds, which annotations may refer to.
+    type_params: Vec<Vec<(String, Type)>>,
+    /// Declared return types of the functions being checked, innermost last.
+    returns: Vec<Type>,
+    errors: Vec<Diagnostic>,
+}
+impl TypeChecker {
+    pub fn new() -> Self {
+        Self {
+            scopes: vec![HashMap::new()], classes: HashMap::new(),
+            type_params: vec![], returns: vec![], errors: vec![],
+        }
+    }
+    /// Reports every type error found.
+    pub fn check(mut self, statements: &Vec<&Statement>) -> Result<(), Vec<Diagnostic>> {
+        // Classes may be used in annotations before they are declared
+        let mut class_statements = vec![];
+        for statement in statements { collect_classes(statement, &mut class_statements); }
+        for class in &class_statements {
+            if let Statement::Class { name, .. } = class {
+                let info = ClassInfo { superclass: None, generics: vec![], methods: HashMap::new() };
+                self.classes.insert(name.lexeme.clone(), info);
+            }
+        }
+        // Type parameters go first, since method signatures may instantiate any class
+        for class in &class_statements {
+            if let Statement::Class { name, generics, .. } = class {
+                let generics = self.resolve_type_params(generics);
+                if let Some(info) = self.classes.get_mut(&name.lexeme) { info.generics = generics; }
+            }
+        }
+        for class in &class_statements { self.declare_class(class); }
+
+        for statement in statements { self.check_statement(statement); }
+        if self.errors.is_empty() { Ok(()) } else { Err(self.errors) }
+    }
+    fn error(&mut self, code: &'static str, span: Span, message: &str) {
+        self.errors.push(Diagnostic::error(code, message).at(span, ""));
+    }
+    fn declare_class(&mut self, class: &Statement) {
+        if let Statement::Class { name, generics: _, methods, superclass } = class {
+            let generics = self.class_generics(&name.lexeme);
+            self.type_params.push(generics);
+            let mut method_types = HashMap::new();
+            for method in methods {
+                if let Statement::Function { name, param_types, generics, return_type, .. } = method.as_ref() {
+                    let ty = self.function_type(param_types, generics, return_type);
+                    method_types.insert(name.lexeme.clone(), ty);
+                }
+            }
+            self.type_params.pop();
+            let superclass = match superclass {
+                Some(Expr::Variable { id: _, name }) => Some(name.lexeme.clone()),
+                _ => None,
+            };
+            if let Some(info) = self.classes.get_mut(&name.lexeme) {
+                info.superclass = superclass;
+                info.methods = method_types;
+            }
+        }
+    }
+    fn class_generics(&self, class: &str) -> Vec<(String, Type)> {
+        self.classes.get(class).map_or(vec![], |info| info.generics.clone())
+    }
+    fn resolve_type_params(&mut self, params: &[TypeParam]) -> Vec<(String, Type)> {
+        params.iter().map(|param| {
+            let bound = match &param.bound {
+                Some(bound) => self.resolve_annotation(bound),
+                None => Type::Any,
+            };
+            (param.name.lexeme.clone(), bound)
+        }).collect()
+    }
+    /// The bound of a type parameter in scope, or `None` if it is not in scope.
+    fn bound_of(&self, name: &str) -> Option<Type> {
+        self.type_params.iter().rev().flatten().find(|(param, _)| param == name).map(|(_, bound)| bound.clone())
+    }
+    /// Matches the type arguments of a generic call or construction to its type parameters,
+    /// inferring them from the arguments when none are written, and checks their bounds.
+    fn instantiate(
+        &mut self, call: Span, generics: &[(String, Type)], explicit: &[Type],
+        params: &Option<Vec<Type>>, args: &[Type]
+    ) -> HashMap<String, Type> {
+        let mut bindings = HashMap::new();
+        if !explicit.is_empty() {
+            if explicit.len() != generics.len() {
+                self.error("E0302", call, &format!(
+                    "Expected {} type arguments but got {}", generics.len(), explicit.len()
+                ));
+            } else {
+                for ((name, _), ty) in generics.iter().zip(explicit) { bindings.insert(name.clone(), ty.clone()); }
+            }
+        } else if let Some(params) = params {
+            for (param, arg) in params.iter().zip(args) { infer_type_args(param, arg, &mut bindings); }
+        }
+        for (name, bound) in generics {
+            let ty = bindings.entry(name.clone()).or_insert(Type::Any).clone();
+            if !self.is_assignable(&ty, bound) {
+                self.error("E0307", call, &format!(
+                    "Type error: {} does not satisfy the bound {} of {}", ty, bound, name
+                ));
+            }
+        }
+        bindings
+    }
+    fn resolve_annotation(&mut self, annotation: &TypeAnnotation) -> Type {
+        match annotation {
+            TypeAnnotation::Named { name, args } => {
+                let lexeme = name.lexeme.as_str();
+                if let Some(builtin) = Type::from_str(lexeme) {
+                    return match (builtin, args.as_slice()) {
+                        (Type::List(_), [element]) => Type::List(Box::new(self.resolve_annotation(element))),
+                        (builtin, []) => builtin,
+                        (builtin, _) => {
+                            self.error("E0303", name.span, &format!("Type {} does not take type arguments", builtin));
+                            Type::Any
+                        },
+                    };
+                }
+                if self.bound_of(lexeme).is_some() {
+                    if !args.is_empty() {
+                        self.error("E0303", name.span, &format!("Type {} does not take type arguments", lexeme));
+                    }
+                    return Type::Generic(lexeme.to_string());
+                }
+                if self.classes.contains_key(lexeme) {
+                    let args: Vec<Type> = args.iter().map(|a| self.resolve_annotation(a)).collect();
+                    let generics = self.class_generics(lexeme);
+                    if args.is_empty() { return Type::Instance(lexeme.to_string(), args); }
+                    if args.len() != generics.len() {
+                        self.error("E0303", name.span, &format!(
+                            "Type {} expects {} type arguments but got {}", lexeme, generics.len(), args.len()
+                        ));
+                        return Type::Instance(lexeme.to_string(), vec![]);
+                    }
+                    for ((param, bound), arg) in generics.iter().zip(&args) {
+                        if !self.is_assignable(arg, bound) {
+                            self.error("E0307", name.span, &format!(
+                                "Type error: {} does not satisfy the bound {} of {}",
+                                arg, bound, param
+                            ));
+                        }
+                    }
+                    return Type::Instance(lexeme.to_string(), args);
+                }
+                self.error("E0303", name.span, &format!("Unknown type '{}'", lexeme));
+                Type::Any
+            },
+            TypeAnnotation::Function { params, ret } => {
+                let params = params.as_ref().map(|params| params.iter().map(|p| self.resolve_annotation(p)).collect());
+                let ret = match ret {
+                    Some(ret) => self.resolve_annotation(ret),
+                    None => Type::Any,
+                };
+                Type::Function { generics: vec![], params, ret: Box::new(ret) }
+            },
+            TypeAnnotation::Union(members) => {
+                let members = members.iter().map(|m| self.resolve_annotation(m)).collect();
+                Type::union(members)
+            },
+        }
+    }
+    fn function_type(
+        &mut self, param_types: &[Option<TypeAnnotation>], generics: &[TypeParam], return_type: &Option<TypeAnnotation>
+    ) -> Type {
+        // Functions without any annotation stay fully dynamic, including their arity
+        if generics.is_empty() && return_type.is_none() && param_types.iter().all(|p| p.is_none()) {
+            return Type::Function { generics: vec![], params: None, ret: Box::new(Type::Any) };
+        }
+        let generics = self.resolve_type_params(generics);
+        self.type_params.push(generics.clone());
+        let params = param_types.iter().map(|p| match p {
+            Some(annotation) => self.resolve_annotation(annotation),
+            None => Type::Any,
+        }).collect();
+        let ret = match return_type {
+            Some(annotation) => self.resolve_annotation(annotation),
+            None => Type::Any,
+        };
+        self.type_params.pop();
+        Type::Function { generics, params: Some(params), ret: Box::new(ret) }
+    }
+    fn declare(&mut self, name: &str, ty: Type, annotated: bool) {
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.insert(name.to_string(), Variable { ty, annotated });
+        }
+    }
+    fn lookup(&mut self, name: &str) -> Option<&mut Variable> {
+        self.scopes.iter_mut().rev().find_map(|scope| scope.get_mut(name))
+    }
+    fn is_subclass(&self, class: &str, ancestor: &str) -> bool {
+        let mut current = Some(class.to_string());
+        while let Some(name) = current {
+            if name == ancestor { return true; }
+            current = self.classes.get(&name).and_then(|info| info.superclass.clone());
+        }
+        false
+    }
+    /// Looks a method up through the superclass chain. The type arguments apply to `class`
+    /// itself; type parameters of a superclass are not known and become `any`.
+    fn find_method(&self, class: &str, args: &[Type], method: &str) -> Option<Type> {
+        let mut current = Some(class.to_string());
+        while let Some(name) = current {
+            let info = self.classes.get(&name)?;
+            if let Some(ty) = info.methods.get(method) {
+                let bindings = info.generics.iter().enumerate().map(|(i, (param, _))| {
+                    let arg = if name == class { args.get(i).cloned() } else { None };
+                    (param.clone(), arg.unwrap_or(Type::Any))
+                }).collect();
+                return Some(substitute(ty, &bindings));
+            }
+            current = info.superclass.clone();
+        }
+        None
+    }
+    /// Whether a value of type `from` can be used where `to` is expected.
+    fn is_assignable(&self, from: &Type, to: &Type) -> bool {
+        match (from, to) {
+            (Type::Any, _) | (_, Type::Any) => true,
+            (Type::Generic(a), Type::Generic(b)) if a == b => true,
+            // Ints are accepted where floats or decimals are expected, as arithmetic promotes them
+            (Type::Int, Type::Float | Type::Decimal) => true,
+            // A type parameter can be anything its bound allows, so only the bound is known
+            (Type::Generic(name), _) => self.bound_of(name).is_none_or(|bound| self.is_assignable(&bound, to)),
+            (_, Type::Generic(name)) => self.bound_of(name).is_none(),
+            (Type::Union(members), _) => members.iter().all(|m| self.is_assignable(m, to)),
+            (_, Type::Union(members)) => members.iter().any(|m| self.is_assignable(from, m)),
+            (Type::List(a), Type::List(b)) => self.is_assignable(a, b),
+            (
+                Type::Function { generics: _, params: from_params, ret: from_ret },
+                Type::Function { generics: _, params: to_params, ret: to_ret }
+            ) => {
+                let params_match = match (from_params, to_params) {
+                    (Some(a), Some(b)) => a.len() == b.len()
+                        && a.iter().zip(b.iter()).all(|(a, b)| self.is_assignable(b, a)),
+                    _ => true,
+                };
+                params_match && self.is_assignable(from_ret, to_ret)
+            },
+            (Type::Instance(a, a_args), Type::Instance(b, b_args)) => self.is_subclass(a, b) && (
+                a != b || a_args.is_empty() || b_args.is_empty()
+                    || a_args.iter().zip(b_args).all(|(a, b)| self.is_assignable(a, b))
+            ),
+            (a, b) => a == b,
+        }
+    }
+    fn check_block(&mut self, statements: &[Box<Statement>]) {
+        self.scopes.push(HashMap::new());
+        for statement in statements { self.check_statement(statement); }
+        self.scopes.pop();
+    }
+    /// Checks a function body with its parameters in scope, returning the function's type.
+    fn check_function(&mut self, params: &[Token], ty: &Type, body: &[Box<Statement>], this: Option<Type>) {
+        let (generics, param_tys, ret) = match ty {
+            Type::Function { generics, params: Some(params), ret } => (generics.clone(), params.clone(), (**ret).clone()),
+            _ => (vec![], vec![Type::Any; params.len()], Type::Any),
+        };
+        self.type_params.push(generics);
+        self.scopes.push(HashMap::new());
+        if let Some(this) = this { self.declare("this", this, true); }
+        for (param, param_ty) in params.iter().zip(param_tys) {
+            self.declare(&param.lexeme, param_ty, true);
+        }
+        self.returns.push(ret);
+        for statement in body { self.check_statement(statement); }
+        self.returns.pop();
+        self.scopes.pop();
+        self.type_params.pop();
+    }
+    fn check_statement(&mut self, statement: &Statement) {
+        match statement {
+            Statement::Expression { expression } | Statement::Print { expression } => { self.infer(expression); },
+            Statement::Var { name, var_type, initializer } => {
+                let actual = self.infer(initializer);
+                match var_type {
+                    Some(annotation) => {
+                        let expected = self.resolve_annotation(annotation);
+                        if !self.is_assignable(&actual, &expected) {
+                            let message = format!(
+                                "Type error: expected {}, found {} for variable {}",
+                                expected, actual, name.lexeme
+                            );
+                            self.errors.push(Diagnostic::error("E0301", &message)
+                                .at(initializer.span(), &format!("found {}", actual))
+                                .with_label(name.span, &format!("declared as {}", expected)));
+                        }
+                        self.declare(&name.lexeme, expected, true);
+                    },
+                    None => {
+                        // A variable starting out as nil is usually filled in later
+                        let ty = if actual == Type::Nil { Type::Any } else { actual };
+                        self.declare(&name.lexeme, ty, false);
+                    },
+                }
+            },
+            Statement::Block { statements } => self.check_block(statements),
+            Statement::Function { name, params, param_types, generics, return_type, body } => {
+                let ty = self.function_type(param_types, generics, return_type);
+                // Declared first so the body can call itself
+                self.declare(&name.lexeme, ty.clone(), true);
+                self.check_function(params, &ty, body, None);
+            },
+            Statement::Class { name, generics: _, methods, superclass } => {
+                if let Some(superclass) = superclass { self.infer(superclass); }
+                self.declare(&name.lexeme, Type::Class(name.lexeme.clone()), true);
+                let generics = self.class_generics(&name.lexeme);
+                let this = Type::Instance(name.lexeme.clone(), generics.iter().map(|(g, _)| Type::Generic(g.clone())).collect());
+                self.type_params.push(generics);
+                let superclass_name = self.classes.get(&name.lexeme).and_then(|info| info.superclass.clone());
+                self.scopes.push(HashMap::new());
+                if let Some(superclass_name) = superclass_name {
+                    self.declare("super", Type::Class(superclass_name), true);
+                }
+                for method in methods {
+                    if let Statement::Function { name: method, params, param_types: _, generics: _, return_type: _, body } = method.as_ref() {
+                        let ty = self.classes.get(&name.lexeme).and_then(|info| info.methods.get(&method.lexeme).cloned());
+                        self.check_function(params, &ty.unwrap_or(Type::Any), body, Some(this.clone()));
+                    }
+                }
+                self.scopes.pop();
+                self.type_params.pop();
+            },
+            Statement::IfStmt { predicate, then, els } => {
+                self.infer(predicate);
+                self.check_statement(then);
+                if let Some(els) = els { self.check_statement(els); }
+            },
+            Statement::WhileStmt { condition, body, increment, label: _ } => {
+                self.infer(condition);
+                self.check_statement(body);
+                if let Some(increment) = increment { self.infer(increment); }
+            },
+            Statement::Break { .. } | Statement::Continue { .. } => (),
+            Statement::ReturnStmt { keyword, value } => {
+                let (actual, span) = match value {
+                    Some(value) => (self.infer(value), value.span()),
+                    None => (Type::Nil, keyword.span),
+                };
+                let expected = self.returns.last().cloned().unwrap_or(Type::Any);
+                if !self.is_assignable(&actual, &expected) {
+                    let message = format!(
+                        "Type error: expected return type {}, found {}", expected, actual
+                    );
+                    self.errors.push(Diagnostic::error("E0301", &message).at(span, &format!("found {}", actual)));
+                }
+            },
+            Statement::Throw { keyword: _, value } => { self.infer(value); },
+            Statement::TryStmt { try_block, catch_name, catch_block, finally_block } => {
+                self.check_statement(try_block);
+                if let (Some(name), Some(catch_block)) = (catch_name, catch_block) {
+                    self.scopes.push(HashMap::new());
+                    self.declare(&name.lexeme, Type::Any, false);
+                    self.check_statement(catch_block);
+                    self.scopes.pop();
+                }
+                if let Some(finally_block) = finally_block { self.check_statement(finally_block); }
+            },
+            Statement::CmdFunction { name, params: _, param_types, command: _ } => {
+                let ty = self.function_type(param_types, &[], &None);
+                self.declare(&name.lexeme, ty, true);
+            },
+            // Modules are checked on their own when loaded, so what they export is not known here
+            Statement::Import { keyword: _, path: _, alias, names } => {
+                for name in alias.iter().chain(names) { self.declare(&name.lexeme, Type::Any, false); }
+            },
+        }
+    }
+    /// Whether a value of this type could be one of `allowed` at runtime.
+    fn may_be(&self, ty: &Type, allowed: &[Type]) -> bool {
+        match ty {
+            Type::Any => true,
+            Type::Generic(name) => self.bound_of(name).is_none_or(|bound| self.may_be(&bound, allowed)),
+            Type::Union(members) => members.iter().any(|m| self.may_be(m, allowed)),
+            other => allowed.contains(other),
+        }
+    }
+    fn check_call(
+        &mut self, paren: &Token, params: &Option<Vec<Type>>, args: &[Type], arguments: &[Expr], arity_message: &str
+    ) {
+        let Some(params) = params else { return };
+        if params.len() != args.len() {
+            self.error("E0302", paren.span, arity_message);
+            return;
+        }
+        for (i, ((param, arg), argument)) in params.iter().zip(args).zip(arguments).enumerate() {
+            if !self.is_assignable(arg, param) {
+                let message = format!(
+                    "Type error: argument {} expected {}, found {}", i + 1, param, arg
+                );
+                self.errors.push(Diagnostic::error("E0301", &message)
+                    .at(argument.span(), &format!("expected {}", param)));
+            }
+        }
+    }
+    fn infer(&mut self, expr: &Expr) -> Type {
+        match expr {
+            Expr::Literal { id: _, span: _, value } => match value {
+                LiteralValue::Int(_) => Type::Int,
+                LiteralValue::Float(_) => Type::Float,
+                LiteralValue::Decimal(_) => Type::Decimal,
+                LiteralValue::StringValue(_) => Type::String,
+                LiteralValue::True | LiteralValue::False => Type::Bool,
+                LiteralValue::Nil => Type::Nil,
+                _ => Type::Any,
+            },
+            Expr::Variable { id: _, name } => match self.lookup(&name.lexeme) {
+                Some(variable) => variable.ty.clone(),
+                None => {
+                    if self.bound_of(&name.lexeme).is_some() {
+                        self.error("E0308", name.span, &format!("Type parameter '{}' cannot be used as a value", name.lexeme));
+                    }
+                    Type::Any
+                },
+            },
+            Expr::Assign { id: _, name, value } => {
+                let actual = self.infer(value);
+                let expected = match self.lookup(&name.lexeme) {
+                    Some(Variable { ty, annotated: true }) => ty.clone(),
+                    Some(variable) => {
+                        if variable.ty != actual { variable.ty = Type::Any; }
+                        return actual;
+                    },
+                    None => return actual,
+                };
+                if !self.is_assignable(&actual, &expected) {
+                    let message = format!(
+                        "Type error: cannot assign {} to variable {} of type {}",
+                        actual, name.lexeme, expected
+                    );
+                    self.errors.push(Diagnostic::error("E0301", &message)
+                        .at(value.span(), &format!("found {}", actual))
+                        .with_label(name.span, &format!("has type {}", expected)));
+                }
+                actual
+            },
+            Expr::AnonFunction { id: _, paren: _, generics, arguments, param_types, return_type, body } => {
+                let ty = self.function_type(param_types, generics, return_type);
+                self.check_function(arguments, &ty, body, None);
+                ty
+            },
+            Expr::Binary { id: _, left, operator, right } => {
+                let spans = (left.span(), right.span());
+                let (left, right) = (self.infer(left), self.infer(right));
+                self.binary(operator, &left, &right, spans)
+            },
+            Expr::Unary { id: _, operator, right } => {
+                let right = self.infer(right);
+                match operator.token_type {
+                    TokenType::Minus | TokenType::Tilde => {
+                        let allowed = if operator.token_type == TokenType::Tilde { vec![Type::Int] } else { vec![Type::Int, Type::Float, Type::Decimal] };
+                        if !self.may_be(&right, &allowed) {
+                            self.error("E0306", operator.span, &format!(
+                                "Operator {} cannot be applied to {}", operator.lexeme, right
+                            ));
+                        }
+                        match right {
+                            Type::Int | Type::Float | Type::Decimal => right,
+                            _ if operator.token_type == TokenType::Tilde => Type::Int,
+                            _ => Type::Any,
+                        }
+                    },
+                    TokenType::Bang => Type::Bool,
+                    _ => Type::Any,
+                }
+            },
+            Expr::Update { id: _, operator, prefix: _, target } => {
+                let target = self.infer(target);
+                if !self.may_be(&target, &[Type::Int, Type::Float, Type::Decimal]) {
+                    self.error("E0306", operator.span, &format!(
+                        "Operator {} cannot be applied to {}", operator.lexeme, target
+                    ));
+                }
+                match target {
+                    Type::Int | Type::Float | Type::Decimal => target,
+                    _ => Type::Any,
+                }
+            },
+            Expr::Call { id: _, callee, paren, arguments, generics } => {
+                let callee_span = callee.span();
+                let callee = self.infer(callee);
+                let args: Vec<Type> = arguments.iter().map(|a| self.infer(a)).collect();
+                let explicit: Vec<Type> = generics.iter().map(|g| self.resolve_annotation(g)).collect();
+                match callee {
+                    Type::Function { generics, params, ret } => {
+                        let bindings = self.instantiate(callee_span.to(paren.span), &generics, &explicit, &params, &args);
+                        let params = params.map(|params| params.iter().map(|p| substitute(p, &bindings)).collect());
+                        let expected = params.as_ref().map_or(0, |p: &Vec<Type>| p.len());
+                        let message = format!("Expected {} arguments but got {}", expected, args.len());
+                        self.check_call(paren, &params, &args, arguments, &message);
+                        substitute(&ret, &bindings)
+                    },
+                    Type::Class(name) => {
+                        let generics = self.class_generics(&name);
+                        let init = self.classes.get(&name).and_then(|info| info.methods.get("init").cloned());
+                        let params = match init {
+                            Some(Type::Function { generics: _, params, ret: _ }) => params,
+                            _ => None,
+                        };
+                        let bindings = self.instantiate(callee_span.to(paren.span), &generics, &explicit, &params, &args);
+                        if params.is_some() {
+                            let params = params.map(|params| params.iter().map(|p| substitute(p, &bindings)).collect());
+                            self.check_call(paren, &params, &args, arguments, "Invalid number of arguments in constructor");
+                        }
+                        let type_args = generics.iter().map(|(g, _)| bindings.get(g).cloned().unwrap_or(Type::Any)).collect();
+                        Type::Instance(name, type_args)
+                    },
+                    Type::Any | Type::Generic(_) | Type::Union(_) => Type::Any,
+                    other => {
+                        self.error("E0304", callee_span, &format!("{} is not callable", other));
+                        Type::Any
+                    },
+                }
+            },
+            Expr::Get { id: _, object, name } => match self.infer(object) {
+                Type::Instance(class, args) => self.find_method(&class, &args, &name.lexeme).unwrap_or(Type::Any),
+                ty if ty.is_known() && !matches!(ty, Type::Union(_)) => {
+                    self.error("E0305", name.span, &format!("Cannot access property {} on {}", name.lexeme, ty));
+                    Type::Any
+                },
+                _ => Type::Any,
+            },
+            Expr::Set { id: _, object, name, operator, value } => {
+                let value_span = value.span();
+                let object = self.infer(object);
+                let value = self.infer(value);
+                if object.is_known() && !matches!(object, Type::Instance(..) | Type::Union(_)) {
+                    self.error("E0305", name.span, &format!("Cannot set property {} on {}", name.lexeme, object));
+                }
+                if let Some(operator) = operator {
+                    let current = match &object {
+                        Type::Instance(class, args) => self.find_method(class, args, &name.lexeme).unwrap_or(Type::Any),
+                        _ => Type::Any,
+                    };
+                    self.binary(operator, &current, &value, (name.span, value_span));
+                }
+                Type::Nil
+            },
+            Expr::Grouping { id: _, expression } => self.infer(expression),
+            // A command on the left of a pipe hands over its running output instead of itself
+            Expr::Piped { id: _, pipe: _, value } => match self.infer(value) {
+                Type::Function { .. } => Type::Any,
+                ty => ty,
+            },
+            Expr::Index { id: _, object, bracket: _, index } => {
+                let object = self.infer(object);
+                self.infer(index);
+                match object {
+                    Type::List(element) => *element,
+                    Type::String => Type::String,
+                    _ => Type::Any,
+                }
+            },
+            Expr::IndexSet { id: _, object, bracket: _, index, operator, value } => {
+                let (object_span, value_span) = (object.span(), value.span());
+                let object = self.infer(object);
+                self.infer(index);
+                let value = self.infer(value);
+                match (operator, object) {
+                    (Some(operator), Type::List(element)) => self.binary(operator, &element, &value, (object_span, value_span)),
+                    (Some(operator), _) => self.binary(operator, &Type::Any, &value, (object_span, value_span)),
+                    (None, _) => value,
+                }
+            },
+            Expr::Slice { id: _, object, bracket: _, start, end } => {
+                let object = self.infer(object);
+                for bound in [start, end].into_iter().flatten() { self.infer(bound); }
+                match object {
+                    list @ Type::List(_) => list,
+                    Type::String => Type::String,
+                    Type::Table => Type::Table,
+                    _ => Type::Any,
+                }
+            },
+            Expr::Interpolated { id: _, span: _, parts } => {
+                for part in parts { self.infer(part); }
+                Type::String
+            },
+            Expr::List { id: _, bracket: _, elements } => {
+                let elements: Vec<Type> = elements.iter().map(|e| self.infer(e)).collect();
+                if elements.is_empty() { Type::List(Box::new(Type::Any)) }
+                else { Type::List(Box::new(Type::union(elements))) }
+            },
+            Expr::Map { id: _, brace: _, entries } => {
+                for (key, value) in entries {
+                    self.infer(key);
+                    self.infer(value);
+                }
+                Type::Map
+            },
+            Expr::Conditional { id: _, keyword: _, condition, then_branch, else_branch } => {
+                self.infer(condition);
+                Type::union(vec![self.infer(then_branch), self.infer(else_branch)])
+            },
+            Expr::Logical { id: _, left, operator, right } => {
+                let (left, right) = (self.infer(left), self.infer(right));
+                match operator.token_type {
+                    TokenType::And => Type::union(vec![Type::Bool, right]),
+                    _ => Type::union(vec![left, right]),
+                }
+            },
+            Expr::This { id: _, keyword: _ } => self.lookup("this").map_or(Type::Any, |v| v.ty.clone()),
+            Expr::Super { id: _, keyword: _, method } => match self.lookup("super").map(|v| v.ty.clone()) {
+                Some(Type::Class(superclass)) => self.find_method(&superclass, &[], &method.lexeme).unwrap_or(Type::Any),
+                _ => Type::Any,
+            },
+        }
+    }
+    fn binary(&mut self, operator: &Token, left: &Type, right: &Type, spans: (Span, Span)) -> Type {
+        let (int, float, decimal, string) = (Type::Int, Type::Float, Type::Decimal, Type::String);
+        let (allowed, result) = match operator.token_type {
+            TokenType::EqualEqual | TokenType::BangEqual => return Type::Bool,
+            TokenType::Plus => (vec![int.clone(), float.clone(), decimal.clone(), string.clone()], None),
+            TokenType::Minus | TokenType::Star | TokenType::Slash => (vec![int.clone(), float.clone(), decimal.clone()], None),
+            TokenType::StarStar | TokenType::TildeSlash | TokenType::Percent => (vec![int.clone(), float.clone()], None),
+            TokenType::Ampersand | TokenType::Bar | TokenType::Caret | TokenType::LessLess | TokenType::GreaterGreater
+                => (vec![int.clone()], Some(int.clone())),
+            TokenType::Greater | TokenType::GreaterEqual | TokenType::Less | TokenType::LessEqual
+                => (vec![int.clone(), float.clone(), decimal.clone(), string.clone()], Some(Type::Bool)),
+            _ => return Type::Any,
+        };
+        // Ints mix with floats and decimals, but floats and decimals do not mix with each other
+        let promoted = match (left, right) {
+            (Type::Int, other @ (Type::Float | Type::Decimal)) | (other @ (Type::Float | Type::Decimal), Type::Int) => Some(other),
+            _ => None,
+        };
+        let mixed = left != right && allowed.contains(left) && allowed.contains(right) && promoted.is_none();
+        if !self.may_be(left, &allowed) || !self.may_be(right, &allowed) || mixed {
+            let message = format!(
+                "Operator {} cannot be applied to {} and {}", operator.lexeme, left, right
+            );
+            self.errors.push(Diagnostic::error("E0306", &message).at(operator.span, "")
+                .with_label(spans.0, &left.to_string()).with_label(spans.1, &right.to_string()));
+            return result.unwrap_or(Type::Any);
+        }
+        match result {
+            Some(result) => result,
+            // Arithmetic keeps the operand type when both sides agree, except that `/` on ints gives a float
+            None if *left == Type::Int && *right == Type::Int && operator.token_type == TokenType::Slash => Type::Float,
+            None if left == right => left.clone(),
+            None => promoted.cloned().unwrap_or(Type::Any),
+        }
+    }
+}
+fn collect_classes<'a>(statement: &'a Statement, classes: &mut Vec<&'a Statement>) {
+    match statement {
+        Statement::Class { .. } => classes.push(statement),
+        Statement::Block { statements } => for s in statements { collect_classes(s, classes); },
+        Statement::Function { body, .. } => for s in body { collect_classes(s, classes); },
+        Statement::IfStmt { predicate: _, then, els } => {
+            collect_classes(then, classes);
+            if let Some(els) = els { collect_classes(els, classes); }
+        },
+        Statement::WhileStmt { condition: _, body, increment: _, label: _ } => collect_classes(body, classes),
+        Statement::TryStmt { try_block, catch_name: _, catch_block, finally_block } => {
+            collect_classes(try_block, classes);
+            for block in [catch_block, finally_block].into_iter().flatten() { collect_classes(block, classes); }
+        },
+        _ => (),
+    }
+}
+/// Replaces type parameters with the types bound to them, leaving unbound ones in place.
+fn substitute(ty: &Type, bindings: &HashMap<String, Type>) -> Type {
+    match ty {
+        Type::Generic(name) => bindings.get(name).cloned().unwrap_or_else(|| ty.clone()),
+        Type::List(element) => Type::List(Box::new(substitute(element, bindings))),
+        Type::Function { generics, params, ret } => Type::Function {
+            generics: generics.clone(),
+            params: params.as_ref().map(|params| params.iter().map(|p| substitute(p, bindings)).collect()),
+            ret: Box::new(substitute(ret, bindings)),
+        },
+        Type::Instance(name, args) => Type::Instance(name.clone(), args.iter().map(|a| substitute(a, bindings)).collect()),
+        Type::Union(members) => Type::union(members.iter().map(|m| substitute(m, bindings)).collect()),
+        other => other.clone(),
+    }
+}
+/// Binds the type parameters in `param` to the matching parts of `arg`. The first binding wins,
+/// so a later argument of another type is reported as a mismatch.
+fn infer_type_args(param: &Type, arg: &Type, bindings: &mut HashMap<String, Type>) {
+    match (param, arg) {
+        (_, Type::Any) => (),
+        (Type::Generic(name), arg) => { bindings.entry(name.clone()).or_insert_with(|| arg.clone()); },
+        (Type::List(param), Type::List(arg)) => infer_type_args(param, arg, bindings),
+        (Type::Instance(param, params), Type::Instance(arg, args)) if param == arg => {
+            for (param, arg) in params.iter().zip(args) { infer_type_args(param, arg, bindings); }
+        },
+        (
+            Type::Function { generics: _, params: Some(params), ret: param_ret },
+            Type::Function { generics: _, params: Some(args), ret: arg_ret }
+        ) => {
+            for (param, arg) in params.iter().zip(args) { infer_type_args(param, arg, bindings); }
+            infer_type_args(param_ret, arg_ret, bindings);
+        },
+        _ => (),
+    }
+}
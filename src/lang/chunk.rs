@@ -0,0 +1,75 @@
+use crate::expr::LiteralValue;
+use crate::scanner::Token;
+
+use std::rc::Rc;
+
+/// A single VM instruction. Jump targets are absolute positions in the chunk.
+/// Instructions that can fail report the token stored next to them in `Chunk::tokens`,
+/// which is also where name-based instructions (globals, properties) take their name from.
+#[derive(Debug, Clone, Copy)]
+pub enum Op {
+    Constant(usize),
+    Nil, True, False,
+    Pop,
+    /// Pushes copies of that many values from the top of the stack, in the same order.
+    Dup(usize),
+    /// Moves the top value below that many of the values under it.
+    Bury(usize),
+    /// Closes upvalues and drops locals until the frame holds this many slots again.
+    Truncate(usize),
+    GetLocal(usize), SetLocal(usize),
+    GetGlobal, SetGlobal, DefineGlobal,
+    GetUpvalue(usize), SetUpvalue(usize),
+    CloseUpvalue,
+    GetProperty, SetProperty, GetSuper,
+    Index, IndexSet, Slice,
+    List(usize), Map(usize),
+    /// Joins the text of that many values into one string.
+    Interpolate(usize),
+    Binary, Unary,
+    Print,
+    Jump(usize), JumpIfFalse(usize),
+    And(usize), Or(usize),
+    Call(usize),
+    /// Starts a command on the left of `|>`, see `command::pipe_source`.
+    PipeSource,
+    /// Index into `Chunk::functions`.
+    Closure(usize),
+    /// Class template constant, number of method closures on the stack, whether `super` is below them.
+    Class(usize, usize, bool),
+    /// Pushes the module whose path is the instruction's token, running it first if needed.
+    Import,
+    Return, StashReturn, ReturnStashed,
+    Throw,
+    PushHandler(usize), PopHandler,
+    PopPending, Rethrow,
+}
+#[derive(Debug, Clone, Copy)]
+pub struct UpvalueDesc {
+    pub index: usize,
+    pub is_local: bool,
+}
+#[derive(Default)]
+pub struct Chunk {
+    pub code: Vec<Op>,
+    pub tokens: Vec<Option<Token>>,
+    pub constants: Vec<LiteralValue>,
+    pub functions: Vec<Rc<Function>>,
+}
+impl Chunk {
+    pub fn emit(&mut self, op: Op, token: Option<&Token>) -> usize {
+        self.code.push(op);
+        self.tokens.push(token.cloned());
+        self.code.len() - 1
+    }
+    pub fn add_constant(&mut self, value: LiteralValue) -> usize {
+        self.constants.push(value);
+        self.constants.len() - 1
+    }
+}
+pub struct Function {
+    pub name: String,
+    pub arity: usize,
+    pub chunk: Chunk,
+    pub upvalues: Vec<UpvalueDesc>,
+}
@@ -1,50 +1,22 @@
-mod scanner;
-mod expr;
-mod parser;
-mod interpreter;
-mod statements;
-mod environment;
-mod resolver;
-mod type_;
+use fluxar::{Engine, Permissions};
 
-use crate::scanner::*;
-use crate::parser::*;
-use crate::interpreter::*;
-use crate::resolver::*;
-
-// use std::cell::RefCell;
-// use std::rc::Rc;
-
-use std::{env, fs};
+use std::env;
 use std::process::exit;
 use std::io::{self, BufRead, Write};
 
-pub fn run_file(path: &str) -> Result<(), String> {
-    // let mut interpreter = Interpreter::new();
-    match fs::read_to_string(path) {
-        Err(msg) => return Err(msg.to_string()),
-        Ok(contents) => return run_string(&contents),
-    }
+fn engine(use_vm: bool, permissions: Permissions) -> Engine {
+    let mut engine = if use_vm { Engine::with_vm() } else { Engine::new() };
+    engine.set_permissions(permissions);
+    engine
 }
-pub fn run_string(contents: &str) -> Result<(), String> {
-    let mut interpreter = Interpreter::new();
-    run(&mut interpreter, contents)
+pub fn run_file(path: &str, use_vm: bool, permissions: Permissions) -> Result<(), String> {
+    engine(use_vm, permissions).run_file(path).map(|_| ())
 }
-fn run(interpreter:  &mut Interpreter, contents: &str) -> Result<(), String> {
-    let mut scanner = Scanner::new(contents);
-    let tokens = scanner.scan_tokens()?;
-    
-    let mut parser = Parser::new(tokens);
-    let stmts = parser.parse()?;
-    let resolver = Resolver::new();
-    let locals = resolver.resolve(&stmts.iter().collect())?;
-
-    interpreter.resolve(locals);
-    interpreter.interpret(stmts.iter().collect())?;
-    return Ok(());
+pub fn run_string(contents: &str, use_vm: bool, permissions: Permissions) -> Result<(), String> {
+    engine(use_vm, permissions).eval(contents).map(|_| ())
 }
-fn run_prompt() -> Result<(), String> {
-    let mut interpreter = Interpreter::new();
+fn run_prompt(use_vm: bool, permissions: Permissions) -> Result<(), String> {
+    let mut engine = engine(use_vm, permissions);
     loop {
         print!("> ");
         match io::stdout().flush() {
@@ -64,40 +36,49 @@ fn run_prompt() -> Result<(), String> {
             Err(_) => return Err("Couldnt read line".to_string()),
         }
         println!("Echo: {}", buffer);
-        match run(&mut interpreter, &buffer) {
+        match engine.eval(&buffer) {
             Ok(_) => (),
             Err(msg) => println!("{}", msg),
         }
     }
 }
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+    let use_vm = args.iter().any(|arg| arg == "--vm");
+    args.retain(|arg| arg != "--vm");
+    let permissions = match Permissions::from_args(&mut args) {
+        Ok(permissions) => permissions,
+        Err(msg) => {
+            println!("{}", msg);
+            exit(64);
+        }
+    };
     if args.len() == 2 {
-        match run_file(&args[1]) {
+        match run_file(&args[1], use_vm, permissions) {
             Ok(_) => exit(0),
             Err(msg) => {
-                println!("Error:\n{}", msg);
+                println!("{}", msg);
                 exit(1);
             }
         }
     } else if args.len() == 3 && args[1] == "e" {
-        match run_string(&args[2]) {
+        match run_string(&args[2], use_vm, permissions) {
             Ok(_) => exit(0),
             Err(msg) => {
-                println!("Error:\n{msg}");
+                println!("{msg}");
                 exit(1);
             }
         }
     } else if args.len() == 1 {
-        match run_prompt() {
+        match run_prompt(use_vm, permissions) {
             Ok(_) => exit(0),
             Err(msg) => {
-                println!("Error:\n{}", msg);
+                println!("{}", msg);
                 exit(1);
             }
         }
     } else {
-        println!("Usage: fluxar [script]");
+        println!("Usage: fluxar [--vm] [--deny-all] [--allow-run=<programs>] [--allow-read=<paths>] [--allow-write=<paths>] [script]");
         exit(64);
     }
 }
\ No newline at end of file
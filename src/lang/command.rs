@@ -0,0 +1,273 @@
+use crate::error::RuntimeError;
+use crate::expr::{CallableImpl, LiteralValue};
+use crate::permissions::Permissions;
+use crate::scanner::Token;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::iter::Peekable;
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::rc::Rc;
+use std::str::Chars;
+use std::thread::{self, JoinHandle};
+
+/// A piece of a command word: literal text or the value of a parameter.
+#[derive(Debug, Clone)]
+enum Part {
+    Text(String),
+    Param(usize),
+}
+/// The command run by a command function, split into argv words when the function is declared
+/// so arguments never go through a shell. `{name}` inserts a parameter, quotes (`'a b'`) keep
+/// spaces inside a word, and a final `< {name}` feeds a parameter to the command's stdin.
+#[derive(Debug, Clone)]
+pub struct CommandTemplate {
+    words: Vec<Vec<Part>>,
+    stdin: Option<usize>,
+}
+impl CommandTemplate {
+    pub fn parse(source: &str, params: &[Token]) -> Result<Self, String> {
+        let mut chars = source.chars().peekable();
+        let (mut words, mut stdin) = (vec![], None);
+        loop {
+            while chars.next_if(|c| c.is_whitespace()).is_some() {}
+            if chars.peek().is_none() { break; }
+            if stdin.is_some() { return Err("Nothing may follow the stdin redirect".to_string()); }
+            if chars.next_if_eq(&'<').is_some() {
+                while chars.next_if(|c| c.is_whitespace()).is_some() {}
+                match word(&mut chars, params)?.as_slice() {
+                    [Part::Param(index)] => stdin = Some(*index),
+                    _ => return Err("Expected a parameter like '{input}' after '<'".to_string()),
+                }
+            } else {
+                words.push(word(&mut chars, params)?);
+            }
+        }
+        if words.is_empty() { return Err("Command is empty".to_string()); }
+        Ok(Self { words, stdin })
+    }
+    fn argv(&self, args: &[LiteralValue]) -> Vec<String> {
+        let mut argv = vec![];
+        for word in &self.words {
+            // A list given as a whole word becomes one argument per item
+            if let [Part::Param(index)] = word.as_slice() {
+                if let LiteralValue::List(items) = &args[*index] {
+                    argv.extend(items.borrow().iter().map(LiteralValue::to_text));
+                    continue;
+                }
+            }
+            argv.push(word.iter().map(|part| match part {
+                Part::Text(text) => text.clone(),
+                Part::Param(index) => args[*index].to_text(),
+            }).collect::<String>());
+        }
+        argv
+    }
+    /// Runs the command with `args` filled in. Given a stream on stdin it is started as the next
+    /// stage of a pipeline and returns a stream of its own; otherwise it is waited for. Exiting
+    /// with an error code is not a runtime error; failing to start or printing invalid UTF-8 is.
+    fn run(&self, args: &[LiteralValue], permissions: &Permissions) -> Result<LiteralValue, RuntimeError> {
+        let input = self.stdin.map(|index| &args[index]);
+        if let Some(LiteralValue::Stream(stream)) = input {
+            return self.stream(args, Some(&mut stream.borrow_mut()), permissions);
+        }
+        let argv = self.argv(args);
+        permissions.check_run(&argv[0])?;
+        let mut child = spawn(&argv, if input.is_some() { Stdio::piped() } else { Stdio::null() }, Stdio::piped())?;
+        let writer = input.and_then(|input| feed(&mut child, stdin_text(input)));
+        let output = child.wait_with_output()
+            .map_err(|e| RuntimeError::from(format!("Failed to run command '{}': {}", argv[0], e)))?;
+        if let Some(writer) = writer { let _ = writer.join(); }
+        let text = |bytes: Vec<u8>, stream: &str| String::from_utf8(bytes).map_err(|_| RuntimeError::from(format!(
+            "Command '{}' wrote invalid UTF-8 to {}", argv[0], stream
+        )));
+        Ok(command_result(
+            text(output.stdout, "stdout")?,
+            text(output.stderr, "stderr")?,
+            output.status.code(),
+        ))
+    }
+    /// Starts the command without waiting for it, returning its stdout as a stream. Its stderr
+    /// goes to ours, like in a shell pipeline, since nothing would read it while it runs.
+    fn stream(&self, args: &[LiteralValue], source: Option<&mut LineStream>, permissions: &Permissions) -> Result<LiteralValue, RuntimeError> {
+        let argv = self.argv(args);
+        permissions.check_run(&argv[0])?;
+        let (mut children, stdin, rest) = match source {
+            // The previous process writes straight into this one, unless some lines were already read
+            Some(source) => match source.stdout.take() {
+                Some(stdout) => (std::mem::take(&mut source.children), Stdio::from(stdout), None),
+                None => (std::mem::take(&mut source.children), Stdio::piped(), Some(source.read_rest()?)),
+            },
+            None => (vec![], Stdio::null(), None),
+        };
+        let mut child = spawn(&argv, stdin, Stdio::inherit())?;
+        let writer = rest.and_then(|rest| feed(&mut child, rest));
+        let stdout = child.stdout.take();
+        children.push(child);
+        Ok(LiteralValue::Stream(Rc::new(RefCell::new(LineStream {
+            program: argv[0].clone(), stdout, reader: None, children, writer,
+        }))))
+    }
+}
+fn spawn(argv: &[String], stdin: Stdio, stderr: Stdio) -> Result<Child, RuntimeError> {
+    Command::new(&argv[0]).args(&argv[1..])
+        .stdin(stdin).stdout(Stdio::piped()).stderr(stderr)
+        .spawn()
+        .map_err(|e| RuntimeError::from(format!("Failed to run command '{}': {}", argv[0], e)))
+}
+/// Writes `input` to the child's stdin from another thread, so a child filling its stdout
+/// pipe cannot deadlock us. A command may exit without reading all of it, which is not an error.
+fn feed(child: &mut Child, input: String) -> Option<JoinHandle<()>> {
+    let mut pipe = child.stdin.take()?;
+    Some(thread::spawn(move || { let _ = pipe.write_all(input.as_bytes()); }))
+}
+/// What a value gives a command's stdin: a list as one line per item, anything else as an argument.
+fn stdin_text(value: &LiteralValue) -> String {
+    match value {
+        LiteralValue::List(items) => items.borrow().iter().map(|item| item.to_text() + "\n").collect(),
+        other => other.to_text(),
+    }
+}
+/// The output of a pipeline stage, read line by line as the program asks for it.
+pub struct LineStream {
+    program: String,
+    /// Kept unread for as long as possible, so a following command can take it as its stdin.
+    stdout: Option<ChildStdout>,
+    reader: Option<BufReader<ChildStdout>>,
+    /// Every process of the pipeline so far, waited for once the stream is done with.
+    children: Vec<Child>,
+    writer: Option<JoinHandle<()>>,
+}
+impl LineStream {
+    /// The next line without its line ending, or `None` once the output ended.
+    pub fn next_line(&mut self) -> Result<Option<String>, RuntimeError> {
+        if let Some(stdout) = self.stdout.take() { self.reader = Some(BufReader::new(stdout)); }
+        let Some(reader) = self.reader.as_mut() else { return Ok(None) };
+        let mut line = vec![];
+        let read = reader.read_until(b'\n', &mut line)
+            .map_err(|e| RuntimeError::from(format!("Could not read from command '{}': {}", self.program, e)))?;
+        if read == 0 {
+            self.finish();
+            return Ok(None);
+        }
+        if line.ends_with(b"\n") { line.pop(); }
+        if line.ends_with(b"\r") { line.pop(); }
+        String::from_utf8(line).map(Some).map_err(|_| RuntimeError::from(format!(
+            "Command '{}' wrote invalid UTF-8 to stdout", self.program
+        )))
+    }
+    fn read_rest(&mut self) -> Result<String, RuntimeError> {
+        let mut rest = String::new();
+        while let Some(line) = self.next_line()? { rest.push_str(&line); rest.push('\n'); }
+        Ok(rest)
+    }
+    /// Closes the output and waits for the processes. Closing first makes a process that is
+    /// still writing stop, as it would in a shell when a later stage exits early.
+    fn finish(&mut self) {
+        self.stdout = None;
+        self.reader = None;
+        for mut child in self.children.drain(..) { let _ = child.wait(); }
+        if let Some(writer) = self.writer.take() { let _ = writer.join(); }
+    }
+}
+impl Drop for LineStream {
+    fn drop(&mut self) { self.finish(); }
+}
+/// One word of a template, up to unquoted whitespace.
+fn word(chars: &mut Peekable<Chars>, params: &[Token]) -> Result<Vec<Part>, String> {
+    let (mut parts, mut text, mut quote) = (vec![], String::new(), None);
+    while let Some(c) = chars.next_if(|c| quote.is_some() || !c.is_whitespace()) {
+        match c {
+            '\'' | '"' if quote.is_none() => quote = Some(c),
+            c if quote == Some(c) => quote = None,
+            '{' => {
+                let mut name = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => name.push(c),
+                        None => return Err("Unclosed '{' in command".to_string()),
+                    }
+                }
+                let index = params.iter().position(|param| param.lexeme == name.trim())
+                    .ok_or_else(|| format!("Unknown parameter '{}' in command", name.trim()))?;
+                if !text.is_empty() { parts.push(Part::Text(std::mem::take(&mut text))); }
+                parts.push(Part::Param(index));
+            },
+            c => text.push(c),
+        }
+    }
+    if quote.is_some() { return Err("Unterminated quote in command".to_string()); }
+    // `''` is an empty argument rather than no argument
+    if !text.is_empty() || parts.is_empty() { parts.push(Part::Text(text)); }
+    Ok(parts)
+}
+fn command_result_class() -> LiteralValue {
+    LiteralValue::FluxarClass {
+        name: "CommandResult".to_string(), generics: vec![],
+        methods: HashMap::new(), superclass: None,
+    }
+}
+/// What a command function returns. `code` is nil when the process was killed by a signal.
+fn command_result(stdout: String, stderr: String, code: Option<i32>) -> LiteralValue {
+    LiteralValue::FluxarInstance {
+        class: Box::new(command_result_class()),
+        fields: Rc::new(RefCell::new(vec![
+            ("stdout".to_string(), LiteralValue::StringValue(stdout)),
+            ("stderr".to_string(), LiteralValue::StringValue(stderr)),
+            ("code".to_string(), code.map_or(LiteralValue::Nil, |code| LiteralValue::Int(code as i64))),
+        ])),
+    }
+}
+/// A function declared with `fun name(params) <- "command"`.
+#[derive(Clone)]
+pub struct CommandFunction {
+    pub name: String,
+    pub arity: usize,
+    command: Rc<CommandTemplate>,
+    permissions: Rc<RefCell<Permissions>>,
+}
+impl CommandFunction {
+    pub fn call(&self, args: &[LiteralValue]) -> Result<LiteralValue, RuntimeError> {
+        if args.len() != self.arity {
+            return Err(format!("Callable {} expected {} arguments but got {}", self.name, self.arity, args.len()).into());
+        }
+        self.command.run(args, &self.permissions.borrow())
+    }
+}
+/// The program's `permissions` decide, when it is called, whether the command may run.
+pub fn cmd_function(name: &str, arity: usize, command: &CommandTemplate, permissions: Rc<RefCell<Permissions>>) -> LiteralValue {
+    LiteralValue::Callable(CallableImpl::Command(CommandFunction {
+        name: name.to_string(), arity, command: Rc::new(command.clone()), permissions,
+    }))
+}
+/// The value on the left of `|>`: a command without parameters is started there,
+/// so the next stage receives its output as a stream.
+pub fn pipe_source(value: LiteralValue) -> Result<LiteralValue, RuntimeError> {
+    match value {
+        LiteralValue::Callable(CallableImpl::Command(command)) if command.arity == 0 => {
+            command.command.stream(&[], None, &command.permissions.borrow())
+        },
+        value => Ok(value),
+    }
+}
+fn stream_arg<'a>(args: &'a [LiteralValue], native: &str) -> Result<&'a Rc<RefCell<LineStream>>, RuntimeError> {
+    match args.first() {
+        Some(LiteralValue::Stream(stream)) => Ok(stream),
+        Some(other) => Err(format!("{}() is not defined for {}", native, other.to_type()).into()),
+        None => Err(format!("{}() expects 1 argument", native).into()),
+    }
+}
+/// `next(stream)` is the next line of a pipeline's output, or nil once it ended.
+pub fn next_impl(args: &[LiteralValue]) -> Result<LiteralValue, RuntimeError> {
+    let line = stream_arg(args, "next")?.borrow_mut().next_line()?;
+    Ok(line.map_or(LiteralValue::Nil, LiteralValue::StringValue))
+}
+/// `collect(stream)` reads the rest of a pipeline's output into a list of lines.
+pub fn collect_impl(args: &[LiteralValue]) -> Result<LiteralValue, RuntimeError> {
+    let mut stream = stream_arg(args, "collect")?.borrow_mut();
+    let mut lines = vec![];
+    while let Some(line) = stream.next_line()? { lines.push(LiteralValue::StringValue(line)); }
+    Ok(LiteralValue::List(Rc::new(RefCell::new(lines))))
+}
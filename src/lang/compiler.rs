@@ -0,0 +1,561 @@
+use crate::chunk::{Chunk, Function, Op, UpvalueDesc};
+use crate::expr::{Expr, LiteralValue};
+use crate::command;
+use crate::permissions::Permissions;
+use crate::scanner::Token;
+use crate::statements::Statement;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+struct Local {
+    name: String,
+    depth: usize,
+    captured: bool,
+}
+struct LoopState {
+    label: Option<String>,
+    /// Number of locals alive when the loop started, restored by `break` and `continue`.
+    locals: usize,
+    tries: usize,
+    breaks: Vec<usize>,
+    continues: Vec<usize>,
+}
+#[derive(Clone, Copy, PartialEq)]
+enum TryPhase { Body, Catch, Finally }
+/// A try statement the code being compiled is nested in. Jumping out of it has to drop its
+/// handler and run its finally block first, so the finally block is kept around to be inlined.
+struct TryState {
+    finally: Option<Statement>,
+    phase: TryPhase,
+    locals: usize,
+    loops: usize,
+}
+#[derive(Clone, Copy, PartialEq)]
+enum FunctionKind { Script, Function, Method }
+struct FunctionState {
+    function: Function,
+    locals: Vec<Local>,
+    upvalues: Vec<UpvalueDesc>,
+    scope_depth: usize,
+    loops: Vec<LoopState>,
+    tries: Vec<TryState>,
+    /// Ranges of locals invisible to name lookups while a finally block is inlined at a jump.
+    hidden: Vec<(usize, usize)>,
+}
+impl FunctionState {
+    fn new(name: &str, arity: usize, kind: FunctionKind) -> Self {
+        // Slot 0 holds the callee, or the instance for methods
+        let slot_zero = if kind == FunctionKind::Method { "this" } else { "" };
+        Self {
+            function: Function { name: name.to_string(), arity, chunk: Chunk::default(), upvalues: vec![] },
+            locals: vec![Local { name: slot_zero.to_string(), depth: 0, captured: false }],
+            upvalues: vec![],
+            scope_depth: if kind == FunctionKind::Script { 0 } else { 1 },
+            loops: vec![], tries: vec![], hidden: vec![],
+        }
+    }
+}
+/// Compiles resolved statements into bytecode for the `vm`.
+pub struct Compiler {
+    current: FunctionState,
+    enclosing: Vec<FunctionState>,
+    /// Given to the command functions the program declares.
+    permissions: Rc<RefCell<Permissions>>,
+}
+impl Compiler {
+    pub fn new(permissions: Rc<RefCell<Permissions>>) -> Self {
+        Self { current: FunctionState::new("script", 0, FunctionKind::Script), enclosing: vec![], permissions }
+    }
+    /// Compiles a file's top level. A trailing expression statement is the script's result.
+    pub fn compile(mut self, statements: &Vec<&Statement>) -> Result<Function, String> {
+        match statements.split_last() {
+            Some((Statement::Expression { expression }, rest)) => {
+                for statement in rest { self.statement(statement)?; }
+                self.expression(expression)?;
+            },
+            _ => {
+                for statement in statements { self.statement(statement)?; }
+                self.emit(Op::Nil, None);
+            },
+        }
+        self.emit(Op::Return, None);
+        Ok(self.current.function)
+    }
+    fn chunk(&mut self) -> &mut Chunk { &mut self.current.function.chunk }
+    fn emit(&mut self, op: Op, token: Option<&Token>) -> usize { self.chunk().emit(op, token) }
+    /// Points the jump at `at` to the next instruction.
+    fn patch(&mut self, at: usize) {
+        let target = self.chunk().code.len();
+        let chunk = self.chunk();
+        chunk.code[at] = match chunk.code[at] {
+            Op::Jump(_) => Op::Jump(target),
+            Op::JumpIfFalse(_) => Op::JumpIfFalse(target),
+            Op::And(_) => Op::And(target),
+            Op::Or(_) => Op::Or(target),
+            Op::PushHandler(_) => Op::PushHandler(target),
+            other => other,
+        };
+    }
+    fn begin_scope(&mut self) { self.current.scope_depth += 1; }
+    fn end_scope(&mut self) {
+        self.current.scope_depth -= 1;
+        while let Some(local) = self.current.locals.last() {
+            if local.depth <= self.current.scope_depth { break; }
+            let op = if local.captured { Op::CloseUpvalue } else { Op::Pop };
+            self.current.locals.pop();
+            self.emit(op, None);
+        }
+    }
+    fn add_local(&mut self, name: &str) {
+        let depth = self.current.scope_depth;
+        self.current.locals.push(Local { name: name.to_string(), depth, captured: false });
+    }
+    fn state_at(&mut self, level: usize) -> &mut FunctionState {
+        if level == self.enclosing.len() { &mut self.current } else { &mut self.enclosing[level] }
+    }
+    fn resolve_local(&mut self, level: usize, name: &str) -> Option<usize> {
+        let state = self.state_at(level);
+        (0..state.locals.len()).rev().find(|&slot| {
+            state.locals[slot].name == name
+                && !state.hidden.iter().any(|&(from, to)| slot >= from && slot < to)
+        })
+    }
+    fn resolve_upvalue(&mut self, level: usize, name: &str) -> Option<usize> {
+        if level == 0 { return None; }
+        let desc = if let Some(slot) = self.resolve_local(level - 1, name) {
+            self.state_at(level - 1).locals[slot].captured = true;
+            UpvalueDesc { index: slot, is_local: true }
+        } else {
+            UpvalueDesc { index: self.resolve_upvalue(level - 1, name)?, is_local: false }
+        };
+        let upvalues = &mut self.state_at(level).upvalues;
+        if let Some(existing) = upvalues.iter().position(|u| u.index == desc.index && u.is_local == desc.is_local) {
+            return Some(existing);
+        }
+        upvalues.push(desc);
+        Some(upvalues.len() - 1)
+    }
+    fn get_variable(&mut self, name: &str, token: &Token) {
+        let level = self.enclosing.len();
+        if let Some(slot) = self.resolve_local(level, name) { self.emit(Op::GetLocal(slot), Some(token)); }
+        else if let Some(index) = self.resolve_upvalue(level, name) { self.emit(Op::GetUpvalue(index), Some(token)); }
+        else { self.emit(Op::GetGlobal, Some(token)); }
+    }
+    fn set_variable(&mut self, name: &Token) {
+        let level = self.enclosing.len();
+        if let Some(slot) = self.resolve_local(level, &name.lexeme) { self.emit(Op::SetLocal(slot), Some(name)); }
+        else if let Some(index) = self.resolve_upvalue(level, &name.lexeme) { self.emit(Op::SetUpvalue(index), Some(name)); }
+        else { self.emit(Op::SetGlobal, Some(name)); }
+    }
+    /// Binds the value on top of the stack to `name`, as a global at the top level and a local elsewhere.
+    fn define_variable(&mut self, name: &Token) {
+        if self.current.scope_depth == 0 { self.emit(Op::DefineGlobal, Some(name)); }
+        else { self.add_local(&name.lexeme); }
+    }
+    fn statement(&mut self, statement: &Statement) -> Result<(), String> {
+        match statement {
+            Statement::Expression { expression } => {
+                self.expression(expression)?;
+                self.emit(Op::Pop, None);
+            },
+            Statement::Print { expression } => {
+                self.expression(expression)?;
+                self.emit(Op::Print, None);
+            },
+            Statement::Var { name, var_type: _, initializer } => {
+                self.expression(initializer)?;
+                self.define_variable(name);
+            },
+            Statement::Block { statements } => {
+                self.begin_scope();
+                for statement in statements { self.statement(statement)?; }
+                self.end_scope();
+            },
+            Statement::Function { name, params, param_types: _, generics: _, return_type: _, body } => {
+                // Declared before the body is compiled so the function can call itself
+                if self.current.scope_depth > 0 { self.add_local(&name.lexeme); }
+                self.function(&name.lexeme, params, body, FunctionKind::Function)?;
+                if self.current.scope_depth == 0 { self.emit(Op::DefineGlobal, Some(name)); }
+            },
+            Statement::Class { name, generics, methods, superclass } => {
+                let slot = if self.current.scope_depth > 0 {
+                    self.emit(Op::Nil, None);
+                    self.add_local(&name.lexeme);
+                    Some(self.current.locals.len() - 1)
+                } else { None };
+                if let Some(superclass) = superclass {
+                    self.expression(superclass)?;
+                    self.begin_scope();
+                    self.add_local("super");
+                }
+                for method in methods {
+                    if let Statement::Function { name, params, param_types: _, generics: _, return_type: _, body } = method.as_ref() {
+                        self.function(&name.lexeme, params, body, FunctionKind::Method)?;
+                    } else { return Err(format!("Class {} contains something that is not a method", name.lexeme)); }
+                }
+                let template = self.chunk().add_constant(LiteralValue::FluxarClass {
+                    name: name.lexeme.clone(), generics: generics.iter().map(|g| g.name.clone()).collect(),
+                    methods: HashMap::new(), superclass: None,
+                });
+                self.emit(Op::Class(template, methods.len(), superclass.is_some()), Some(name));
+                match slot {
+                    Some(slot) => {
+                        self.emit(Op::SetLocal(slot), Some(name));
+                        self.emit(Op::Pop, None);
+                    },
+                    None => { self.emit(Op::DefineGlobal, Some(name)); },
+                }
+                if superclass.is_some() { self.end_scope(); }
+            },
+            Statement::IfStmt { predicate, then, els } => {
+                self.expression(predicate)?;
+                let to_else = self.emit(Op::JumpIfFalse(0), None);
+                self.statement(then)?;
+                let to_end = self.emit(Op::Jump(0), None);
+                self.patch(to_else);
+                if let Some(els) = els { self.statement(els)?; }
+                self.patch(to_end);
+            },
+            Statement::WhileStmt { condition, body, increment, label } => {
+                let start = self.chunk().code.len();
+                self.expression(condition)?;
+                let exit = self.emit(Op::JumpIfFalse(0), None);
+                self.current.loops.push(LoopState {
+                    label: label.as_ref().map(|l| l.lexeme.clone()),
+                    locals: self.current.locals.len(), tries: self.current.tries.len(),
+                    breaks: vec![], continues: vec![],
+                });
+                let result = self.statement(body);
+                let state = self.current.loops.pop();
+                result?;
+                let state = state.ok_or("Loop state went missing")?;
+                for jump in state.continues { self.patch(jump); }
+                if let Some(increment) = increment {
+                    self.expression(increment)?;
+                    self.emit(Op::Pop, None);
+                }
+                self.emit(Op::Jump(start), None);
+                self.patch(exit);
+                for jump in state.breaks { self.patch(jump); }
+            },
+            Statement::Break { keyword, label } => self.jump_out_of_loop(keyword, label, true)?,
+            Statement::Continue { keyword, label } => self.jump_out_of_loop(keyword, label, false)?,
+            Statement::ReturnStmt { keyword, value } => {
+                match value {
+                    Some(value) => self.expression(value)?,
+                    None => { self.emit(Op::Nil, None); },
+                }
+                if self.current.tries.is_empty() { self.emit(Op::Return, Some(keyword)); }
+                else {
+                    self.emit(Op::StashReturn, None);
+                    self.leave_tries(0)?;
+                    self.emit(Op::ReturnStashed, Some(keyword));
+                }
+            },
+            Statement::Throw { keyword, value } => {
+                self.expression(value)?;
+                self.emit(Op::Throw, Some(keyword));
+            },
+            Statement::TryStmt { try_block, catch_name, catch_block, finally_block } => {
+                self.try_statement(try_block, catch_name, catch_block, finally_block)?;
+            },
+            Statement::CmdFunction { name, params, param_types: _, command } => {
+                // Nothing about a command function depends on the scope it is declared in
+                let function = command::cmd_function(&name.lexeme, params.len(), command, self.permissions.clone());
+                let function = self.chunk().add_constant(function);
+                self.emit(Op::Constant(function), Some(name));
+                self.define_variable(name);
+            },
+            Statement::Import { keyword: _, path, alias, names } => match alias {
+                Some(alias) => {
+                    self.emit(Op::Import, Some(path));
+                    self.define_variable(alias);
+                },
+                // Later imports of the module are cached, so each name can import it again
+                None => for name in names {
+                    self.emit(Op::Import, Some(path));
+                    self.emit(Op::GetProperty, Some(name));
+                    self.define_variable(name);
+                },
+            },
+        }
+        Ok(())
+    }
+    fn try_statement(
+        &mut self, try_block: &Statement, catch_name: &Option<Token>,
+        catch_block: &Option<Box<Statement>>, finally_block: &Option<Box<Statement>>
+    ) -> Result<(), String> {
+        let (locals, loops) = (self.current.locals.len(), self.current.loops.len());
+        let finally = finally_block.as_ref().map(|f| (**f).clone());
+        self.current.tries.push(TryState { finally: finally.clone(), phase: TryPhase::Body, locals, loops });
+        let handler = self.emit(Op::PushHandler(0), None);
+        let result = self.statement(try_block);
+        self.current.tries.pop();
+        result?;
+        self.emit(Op::PopHandler, None);
+        if let Some(finally) = &finally { self.statement(finally)?; }
+        let mut to_end = vec![self.emit(Op::Jump(0), None)];
+
+        // The handler lands here with the error value on top of the stack
+        self.patch(handler);
+        if let (Some(name), Some(catch_block)) = (catch_name, catch_block) {
+            self.emit(Op::PopPending, None);
+            self.begin_scope();
+            self.add_local(&name.lexeme);
+            let rethrow = if finally.is_some() {
+                self.current.tries.push(TryState { finally: finally.clone(), phase: TryPhase::Catch, locals, loops });
+                Some(self.emit(Op::PushHandler(0), None))
+            } else { None };
+            let result = self.statement(catch_block);
+            if rethrow.is_some() { self.current.tries.pop(); }
+            result?;
+            if rethrow.is_some() { self.emit(Op::PopHandler, None); }
+            self.end_scope();
+            if let Some(finally) = &finally { self.statement(finally)?; }
+            to_end.push(self.emit(Op::Jump(0), None));
+            match rethrow {
+                Some(rethrow) => self.patch(rethrow),
+                None => {
+                    for jump in to_end { self.patch(jump); }
+                    return Ok(());
+                },
+            }
+        }
+        // Errors the catch block did not handle run the finally block and are raised again
+        self.emit(Op::Truncate(locals), None);
+        if let Some(finally) = &finally {
+            self.current.tries.push(TryState { finally: None, phase: TryPhase::Finally, locals, loops });
+            let result = self.statement(finally);
+            self.current.tries.pop();
+            result?;
+        }
+        self.emit(Op::Rethrow, None);
+        for jump in to_end { self.patch(jump); }
+        Ok(())
+    }
+    /// Emits what jumping out of every try statement above `depth` needs: dropping handlers
+    /// and pending errors, and running finally blocks innermost first.
+    fn leave_tries(&mut self, depth: usize) -> Result<(), String> {
+        for i in (depth..self.current.tries.len()).rev() {
+            match self.current.tries[i].phase {
+                TryPhase::Finally => { self.emit(Op::PopPending, None); },
+                TryPhase::Body | TryPhase::Catch => {
+                    self.emit(Op::PopHandler, None);
+                    if let Some(finally) = self.current.tries[i].finally.clone() {
+                        let outer_tries = self.current.tries.split_off(i);
+                        let outer_loops = self.current.loops.split_off(outer_tries[0].loops);
+                        self.current.hidden.push((outer_tries[0].locals, self.current.locals.len()));
+                        let result = self.statement(&finally);
+                        self.current.hidden.pop();
+                        self.current.loops.extend(outer_loops);
+                        self.current.tries.extend(outer_tries);
+                        result?;
+                    }
+                },
+            }
+        }
+        Ok(())
+    }
+    fn jump_out_of_loop(&mut self, keyword: &Token, label: &Option<Token>, is_break: bool) -> Result<(), String> {
+        let target = match label {
+            Some(label) => self.current.loops.iter().rposition(|l| l.label.as_ref() == Some(&label.lexeme)),
+            None => self.current.loops.len().checked_sub(1),
+        };
+        let target = target.ok_or_else(|| format!(
+            "Line {}: '{}' is not allowed outside of a loop", keyword.span.line, keyword.lexeme
+        ))?;
+        let (locals, tries) = (self.current.loops[target].locals, self.current.loops[target].tries);
+        self.leave_tries(tries)?;
+        self.emit(Op::Truncate(locals), None);
+        let jump = self.emit(Op::Jump(0), None);
+        let state = &mut self.current.loops[target];
+        if is_break { state.breaks.push(jump); } else { state.continues.push(jump); }
+        Ok(())
+    }
+    fn function(
+        &mut self, name: &str, params: &[Token], body: &[Box<Statement>], kind: FunctionKind
+    ) -> Result<(), String> {
+        let state = FunctionState::new(name, params.len(), kind);
+        self.enclosing.push(std::mem::replace(&mut self.current, state));
+        for param in params { self.add_local(&param.lexeme); }
+        let mut result = Ok(());
+        for statement in body {
+            result = self.statement(statement);
+            if result.is_err() { break; }
+        }
+        self.emit(Op::Nil, None);
+        self.emit(Op::Return, None);
+        let enclosing = self.enclosing.pop().ok_or("Function compiler state went missing")?;
+        let state = std::mem::replace(&mut self.current, enclosing);
+        result?;
+        let mut function = state.function;
+        function.upvalues = state.upvalues;
+        let chunk = self.chunk();
+        chunk.functions.push(Rc::new(function));
+        let index = chunk.functions.len() - 1;
+        self.emit(Op::Closure(index), None);
+        Ok(())
+    }
+    fn expression(&mut self, expr: &Expr) -> Result<(), String> {
+        match expr {
+            Expr::Assign { id: _, name, value } => {
+                self.expression(value)?;
+                self.set_variable(name);
+            },
+            Expr::AnonFunction { id: _, paren: _, generics: _, arguments, param_types: _, return_type: _, body } => {
+                self.function("anon_function", arguments, body, FunctionKind::Function)?;
+            },
+            Expr::Binary { id: _, left, operator, right } => {
+                self.expression(left)?;
+                self.expression(right)?;
+                self.emit(Op::Binary, Some(operator));
+            },
+            Expr::Call { id: _, callee, paren, arguments, generics: _ } => {
+                self.expression(callee)?;
+                for argument in arguments { self.expression(argument)?; }
+                self.emit(Op::Call(arguments.len()), Some(paren));
+            },
+            Expr::Get { id: _, object, name } => {
+                self.expression(object)?;
+                self.emit(Op::GetProperty, Some(name));
+            },
+            Expr::Grouping { id: _, expression } => self.expression(expression)?,
+            Expr::Piped { id: _, pipe, value } => {
+                self.expression(value)?;
+                self.emit(Op::PipeSource, Some(pipe));
+            },
+            Expr::Index { id: _, object, bracket, index } => {
+                self.expression(object)?;
+                self.expression(index)?;
+                self.emit(Op::Index, Some(bracket));
+            },
+            Expr::IndexSet { id: _, object, bracket, index, operator, value } => {
+                self.expression(object)?;
+                self.expression(index)?;
+                if operator.is_some() {
+                    self.emit(Op::Dup(2), None);
+                    self.emit(Op::Index, Some(bracket));
+                }
+                self.expression(value)?;
+                if let Some(operator) = operator { self.emit(Op::Binary, Some(operator)); }
+                self.emit(Op::IndexSet, Some(bracket));
+            },
+            Expr::Interpolated { id: _, span: _, parts } => {
+                for part in parts { self.expression(part)?; }
+                self.emit(Op::Interpolate(parts.len()), None);
+            },
+            Expr::List { id: _, bracket: _, elements } => {
+                for element in elements { self.expression(element)?; }
+                self.emit(Op::List(elements.len()), None);
+            },
+            Expr::Map { id: _, brace, entries } => {
+                for (key, value) in entries {
+                    self.expression(key)?;
+                    self.expression(value)?;
+                }
+                self.emit(Op::Map(entries.len()), Some(brace));
+            },
+            Expr::Literal { id: _, span: _, value } => {
+                match value {
+                    LiteralValue::Nil => self.emit(Op::Nil, None),
+                    LiteralValue::True => self.emit(Op::True, None),
+                    LiteralValue::False => self.emit(Op::False, None),
+                    other => {
+                        let constant = self.chunk().add_constant(other.clone());
+                        self.emit(Op::Constant(constant), None)
+                    },
+                };
+            },
+            Expr::Conditional { id: _, keyword, condition, then_branch, else_branch } => {
+                self.expression(condition)?;
+                let to_else = self.emit(Op::JumpIfFalse(0), Some(keyword));
+                self.expression(then_branch)?;
+                let to_end = self.emit(Op::Jump(0), None);
+                self.patch(to_else);
+                self.expression(else_branch)?;
+                self.patch(to_end);
+            },
+            Expr::Logical { id: _, left, operator, right } => {
+                self.expression(left)?;
+                let op = match operator.token_type {
+                    crate::scanner::TokenType::And => Op::And(0),
+                    _ => Op::Or(0),
+                };
+                let jump = self.emit(op, Some(operator));
+                self.expression(right)?;
+                self.patch(jump);
+            },
+            Expr::Set { id: _, object, name, operator, value } => {
+                self.expression(object)?;
+                if operator.is_some() {
+                    self.emit(Op::Dup(1), None);
+                    self.emit(Op::GetProperty, Some(name));
+                }
+                self.expression(value)?;
+                if let Some(operator) = operator { self.emit(Op::Binary, Some(operator)); }
+                self.emit(Op::SetProperty, Some(name));
+            },
+            Expr::Slice { id: _, object, bracket, start, end } => {
+                self.expression(object)?;
+                for bound in [start, end] {
+                    match bound {
+                        Some(bound) => self.expression(bound)?,
+                        None => { self.emit(Op::Nil, None); },
+                    }
+                }
+                self.emit(Op::Slice, Some(bracket));
+            },
+            Expr::This { id: _, keyword } => self.get_variable("this", keyword),
+            Expr::Super { id: _, keyword, method } => {
+                self.get_variable("this", keyword);
+                self.get_variable("super", keyword);
+                self.emit(Op::GetSuper, Some(method));
+            },
+            Expr::Unary { id: _, operator, right } => {
+                self.expression(right)?;
+                self.emit(Op::Unary, Some(operator));
+            },
+            Expr::Update { id: _, operator, prefix, target } => {
+                // The object and index stay on the stack for the store, so they are evaluated once
+                let kept = match target.as_ref() {
+                    Expr::Variable { id: _, name } => { self.get_variable(&name.lexeme, name); 0 },
+                    Expr::Get { id: _, object, name } => {
+                        self.expression(object)?;
+                        self.emit(Op::Dup(1), None);
+                        self.emit(Op::GetProperty, Some(name));
+                        1
+                    },
+                    Expr::Index { id: _, object, bracket, index } => {
+                        self.expression(object)?;
+                        self.expression(index)?;
+                        self.emit(Op::Dup(2), None);
+                        self.emit(Op::Index, Some(bracket));
+                        2
+                    },
+                    _ => return Err("Invalid assignment target".to_string()),
+                };
+                // A copy of the result, the old value for postfix and the new one for prefix, waits below them
+                let keep = |compiler: &mut Self| {
+                    compiler.emit(Op::Dup(1), None);
+                    if kept > 0 { compiler.emit(Op::Bury(kept + 1), None); }
+                };
+                if !prefix { keep(self); }
+                let one = self.chunk().add_constant(LiteralValue::Int(1));
+                self.emit(Op::Constant(one), None);
+                self.emit(Op::Binary, Some(operator));
+                if *prefix { keep(self); }
+                match target.as_ref() {
+                    Expr::Get { id: _, object: _, name } => { self.emit(Op::SetProperty, Some(name)); },
+                    Expr::Index { id: _, object: _, bracket, index: _ } => { self.emit(Op::IndexSet, Some(bracket)); },
+                    Expr::Variable { id: _, name } => self.set_variable(name),
+                    _ => (),
+                }
+                self.emit(Op::Pop, None);
+            },
+            Expr::Variable { id: _, name } => self.get_variable(&name.lexeme, name),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,259 @@
+use std::fs;
+use std::rc::Rc;
+
+use crate::expr::LiteralValue;
+use crate::error::RuntimeError;
+use crate::permissions::Permissions;
+use crate::decimal::Decimal;
+use crate::table::{Column, ColumnType, Table};
+
+struct Field {
+    text: String,
+    line: usize,
+    column: usize,
+}
+struct Record {
+    fields: Vec<Field>,
+    /// Position just past the last character of the record, used to report missing fields.
+    end: (usize, usize),
+}
+struct Options {
+    delimiter: char,
+    header: Option<bool>,
+    /// Read numbers with a fractional part as exact decimals rather than floats.
+    decimals: bool,
+}
+fn csv_error(line: usize, column: usize, msg: &str) -> String {
+    format!("CSV error at line {}, column {}: {}", line, column, msg)
+}
+/// Splits CSV source into records, handling quoted fields, doubled quotes and newlines inside quotes.
+fn parse_records(source: &str, delimiter: char) -> Result<Vec<Record>, String> {
+    let mut records = vec![];
+    let mut chars = source.chars().peekable();
+    let (mut line, mut column) = (1, 1);
+    while chars.peek().is_some() {
+        let mut fields = vec![];
+        loop {
+            let (start_line, start_column) = (line, column);
+            let mut text = String::new();
+            if chars.peek() == Some(&'"') {
+                chars.next(); column += 1;
+                loop {
+                    match chars.next() {
+                        None => return Err(csv_error(start_line, start_column, "unterminated quoted field")),
+                        Some('"') => {
+                            column += 1;
+                            if chars.peek() == Some(&'"') { chars.next(); column += 1; text.push('"'); }
+                            else { break; }
+                        },
+                        Some('\n') => { line += 1; column = 1; text.push('\n'); },
+                        Some(c) => { column += 1; text.push(c); },
+                    }
+                }
+                match chars.peek() {
+                    None | Some('\n') | Some('\r') => (),
+                    Some(c) if *c == delimiter => (),
+                    Some(_) => return Err(csv_error(line, column, "unexpected character after closing quote")),
+                }
+            } else {
+                while let Some(&c) = chars.peek() {
+                    if c == delimiter || c == '\n' || c == '\r' { break; }
+                    if c == '"' { return Err(csv_error(line, column, "quote inside an unquoted field")); }
+                    text.push(c); chars.next(); column += 1;
+                }
+            }
+            fields.push(Field { text, line: start_line, column: start_column });
+            match chars.peek() {
+                Some(c) if *c == delimiter => { chars.next(); column += 1; },
+                _ => break,
+            }
+        }
+        let end = (line, column);
+        if chars.peek() == Some(&'\r') { chars.next(); }
+        if chars.peek() == Some(&'\n') { chars.next(); line += 1; column = 1; }
+        // Skip blank lines rather than turning them into rows of nil
+        if fields.len() == 1 && fields[0].text.is_empty() { continue; }
+        records.push(Record { fields, end });
+    }
+    Ok(records)
+}
+fn parse_number(text: &str) -> Option<LiteralValue> {
+    let text = text.trim();
+    if text.is_empty() || text.chars().any(|c| c.is_alphabetic() && c != 'e' && c != 'E') { return None; }
+    text.parse::<i64>().map(LiteralValue::Int).or_else(|_| text.parse::<f64>().map(LiteralValue::Float)).ok()
+}
+fn parse_bool(text: &str) -> Option<bool> {
+    match text.trim().to_lowercase().as_str() {
+        "true" => Some(true), "false" => Some(false), _ => None,
+    }
+}
+/// Picks the narrowest type every non-empty cell fits: number, then boolean, otherwise string.
+fn infer_column(name: &str, cells: &[&str], decimals: bool) -> Result<Column, String> {
+    let filled: Vec<&&str> = cells.iter().filter(|c| !c.is_empty()).collect();
+    let kind = if !filled.is_empty() && filled.iter().all(|c| parse_number(c).is_some()) { ColumnType::Number }
+        else if !filled.is_empty() && filled.iter().all(|c| parse_bool(c).is_some()) { ColumnType::Boolean }
+        else { ColumnType::String };
+    let values = cells.iter().map(|cell| {
+        if cell.is_empty() { return LiteralValue::Nil; }
+        match kind {
+            ColumnType::Number => match parse_number(cell) {
+                // Cells with an exponent stay floats
+                Some(LiteralValue::Float(x)) if decimals => Decimal::parse(cell).map_or(LiteralValue::Float(x), LiteralValue::Decimal),
+                number => number.unwrap_or(LiteralValue::Nil),
+            },
+            ColumnType::Boolean => LiteralValue::from_bool(parse_bool(cell).unwrap_or_default()),
+            _ => LiteralValue::StringValue(cell.to_string()),
+        }
+    }).collect();
+    Column::new(name, values)
+}
+/// A first row counts as a header when all of its cells are distinct, non-empty and not numbers or booleans.
+fn looks_like_header(record: &Record) -> bool {
+    record.fields.iter().enumerate().all(|(i, field)| {
+        !field.text.is_empty() && parse_number(&field.text).is_none() && parse_bool(&field.text).is_none()
+            && !record.fields[..i].iter().any(|other| other.text == field.text)
+    })
+}
+fn parse_table(source: &str, options: &Options) -> Result<Table, String> {
+    let records = parse_records(source, options.delimiter)?;
+    if records.is_empty() { return Table::new(vec![]); }
+    let has_header = options.header.unwrap_or_else(|| looks_like_header(&records[0]));
+    let width = records[0].fields.len();
+    for record in records.iter() {
+        if record.fields.len() > width {
+            let extra = &record.fields[width];
+            return Err(csv_error(extra.line, extra.column, &format!(
+                "expected {} fields but found {}", width, record.fields.len()
+            )));
+        } else if record.fields.len() < width {
+            return Err(csv_error(record.end.0, record.end.1, &format!(
+                "expected {} fields but found {}", width, record.fields.len()
+            )));
+        }
+    }
+    let (names, rows): (Vec<String>, &[Record]) = if has_header {
+        (records[0].fields.iter().map(|f| f.text.clone()).collect(), &records[1..])
+    } else {
+        ((1..=width).map(|i| format!("column{}", i)).collect(), &records[..])
+    };
+    let mut columns = vec![];
+    for (i, name) in names.iter().enumerate() {
+        let cells: Vec<&str> = rows.iter().map(|r| r.fields[i].text.as_str()).collect();
+        columns.push(infer_column(name, &cells, options.decimals)?);
+    }
+    Table::new(columns)
+}
+fn parse_options(value: Option<&LiteralValue>, fname: &str) -> Result<Options, String> {
+    let mut options = Options { delimiter: ',', header: None, decimals: false };
+    match value {
+        None | Some(LiteralValue::Nil) => (),
+        Some(LiteralValue::Map(entries)) => for (key, value) in entries.borrow().iter() {
+            match (key, value) {
+                (LiteralValue::StringValue(key), LiteralValue::StringValue(d)) if key == "delimiter" && d.chars().count() == 1 => {
+                    options.delimiter = d.chars().next().unwrap_or(',');
+                },
+                (LiteralValue::StringValue(key), LiteralValue::True) if key == "header" => options.header = Some(true),
+                (LiteralValue::StringValue(key), LiteralValue::False) if key == "header" => options.header = Some(false),
+                (LiteralValue::StringValue(key), LiteralValue::Nil) if key == "header" => options.header = None,
+                (LiteralValue::StringValue(key), LiteralValue::True | LiteralValue::False) if key == "decimals" => {
+                    options.decimals = *value == LiteralValue::True;
+                },
+                (key, value) => return Err(format!(
+                    "{}() got invalid option {}: {}", fname, key.to_string(), value.to_string()
+                )),
+            }
+        },
+        Some(other) => return Err(format!("{}() expects a map of options, not {}", fname, other.to_type())),
+    }
+    if options.delimiter == '"' || options.delimiter == '\n' || options.delimiter == '\r' {
+        return Err(format!("{}() cannot use {:?} as a delimiter", fname, options.delimiter));
+    }
+    Ok(options)
+}
+fn quote_field(text: &str, delimiter: char) -> String {
+    if text.contains(delimiter) || text.contains('"') || text.contains('\n') || text.contains('\r')
+        || text.starts_with(' ') || text.ends_with(' ') {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else { text.to_string() }
+}
+fn format_cell(value: &LiteralValue) -> String {
+    match value {
+        LiteralValue::Nil => String::new(),
+        LiteralValue::StringValue(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+fn format_table(table: &Table, delimiter: char) -> String {
+    let separator = delimiter.to_string();
+    let mut out = table.columns.iter().map(|c| quote_field(&c.name, delimiter))
+        .collect::<Vec<String>>().join(&separator);
+    out.push('\n');
+    for row in 0..table.row_count() {
+        out.push_str(&table.columns.iter().map(|c| quote_field(&format_cell(&c.values[row]), delimiter))
+            .collect::<Vec<String>>().join(&separator));
+        out.push('\n');
+    }
+    out
+}
+/// `read_csv(path, options)` where options may set "delimiter", "header" (true, false or nil to detect)
+/// and "decimals" (true to read fractional numbers as decimals).
+pub fn read_csv_impl(args: &[LiteralValue], permissions: &Permissions) -> Result<LiteralValue, RuntimeError> {
+    let path = match args.first() {
+        Some(LiteralValue::StringValue(path)) => path.clone(),
+        _ => return Err("read_csv() expects a file path".into()),
+    };
+    permissions.check_read(&path)?;
+    let options = parse_options(args.get(1), "read_csv")?;
+    let source = fs::read_to_string(&path).map_err(|e| format!("Could not read '{}': {}", path, e))?;
+    let table = parse_table(&source, &options).map_err(|msg| format!("{}: {}", path, msg))?;
+    Ok(LiteralValue::Table(Rc::new(table)))
+}
+/// `write_csv(table, path, options)` writes a header row followed by every row; nil becomes an empty field.
+pub fn write_csv_impl(args: &[LiteralValue], permissions: &Permissions) -> Result<LiteralValue, RuntimeError> {
+    let table = match args.first() {
+        Some(LiteralValue::Table(table)) => table.clone(),
+        _ => return Err("write_csv() expects a Table as its first argument".into()),
+    };
+    let path = match args.get(1) {
+        Some(LiteralValue::StringValue(path)) => path.clone(),
+        _ => return Err("write_csv() expects a file path".into()),
+    };
+    permissions.check_write(&path)?;
+    let options = parse_options(args.get(2), "write_csv")?;
+    fs::write(&path, format_table(&table, options.delimiter))
+        .map_err(|e| format!("Could not write '{}': {}", path, e))?;
+    Ok(LiteralValue::Nil)
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn options() -> Options { Options { delimiter: ',', header: None, decimals: false } }
+    #[test]
+    fn infers_header_and_column_types() {
+        let table = parse_table("name,age,member\nann,31,true\nbob,,false\n", &options()).unwrap();
+        assert_eq!(table.column_names(), vec!["name", "age", "member"]);
+        assert_eq!(table.columns[1].kind, ColumnType::Number);
+        assert_eq!(table.columns[1].values[1], LiteralValue::Nil);
+        assert_eq!(table.columns[2].kind, ColumnType::Boolean);
+    }
+    #[test]
+    fn reads_fractions_as_decimals_when_asked() {
+        let table = parse_table("amount\n0.10\n2\n1e3\n", &Options { decimals: true, ..options() }).unwrap();
+        assert!(matches!(&table.columns[0].values[0], LiteralValue::Decimal(x) if x.to_string() == "0.10"));
+        assert_eq!(table.columns[0].values[1..], [LiteralValue::Int(2), LiteralValue::Float(1000.0)]);
+    }
+    #[test]
+    fn handles_quotes_and_embedded_newlines() {
+        let table = parse_table("a;b\n\"x;\"\"y\"\"\";\"line1\nline2\"\n", &Options { delimiter: ';', header: None, decimals: false }).unwrap();
+        assert_eq!(table.columns[0].values[0], LiteralValue::StringValue("x;\"y\"".to_string()));
+        assert_eq!(table.columns[1].values[0], LiteralValue::StringValue("line1\nline2".to_string()));
+        assert_eq!(format_table(&table, ';'), "a;b\n\"x;\"\"y\"\"\";\"line1\nline2\"\n");
+    }
+    #[test]
+    fn reports_position_of_malformed_input() {
+        let err = parse_table("a,b\n1,2\n3,\"4\"x\n", &options()).err().unwrap();
+        assert_eq!(err, "CSV error at line 3, column 6: unexpected character after closing quote");
+        let err = parse_table("a,b\n1,2,3\n", &options()).err().unwrap();
+        assert_eq!(err, "CSV error at line 2, column 5: expected 2 fields but found 3");
+    }
+}
@@ -0,0 +1,367 @@
+use crate::expr::LiteralValue;
+use crate::error::RuntimeError;
+
+use std::cell::Cell;
+use std::cmp::Ordering;
+
+/// Digits kept after the point when a division does not come out even, unless changed with
+/// `set_decimal_rounding`.
+const DIVISION_SCALE: u32 = 20;
+/// The most places `set_decimal_rounding` accepts, since every division works out that many digits.
+const MAX_DIVISION_SCALE: i64 = 1000;
+thread_local! {
+    /// The places and rounding mode of divisions that do not come out even.
+    static DIVISION: Cell<(u32, Rounding)> = const { Cell::new((DIVISION_SCALE, Rounding::HalfEven)) };
+}
+/// The largest exponent `parse_scientific` spells out, since `1e1000000` would take a million digits.
+const MAX_EXPONENT: i64 = 1000;
+
+/// An exact decimal number: `digits` read as an integer, divided by `10^scale`. Digits are
+/// stored least significant first without leading zeros, so zero has none. The scale is kept
+/// as written, so `12.50d` prints as `12.50`.
+#[derive(Debug, Clone)]
+pub struct Decimal {
+    negative: bool,
+    digits: Vec<u8>,
+    scale: u32,
+}
+/// How to drop digits that do not fit, named as in `round(x, places, mode)`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rounding {
+    /// To the nearest, ties to the even neighbour, so rounding errors do not add up in sums.
+    HalfEven,
+    HalfUp,
+    HalfDown,
+    /// Away from zero.
+    Up,
+    /// Towards zero.
+    Down,
+    Floor,
+    Ceiling,
+}
+impl Rounding {
+    pub fn from_str(name: &str) -> Option<Rounding> {
+        match name {
+            "half_even" => Some(Rounding::HalfEven),
+            "half_up" => Some(Rounding::HalfUp),
+            "half_down" => Some(Rounding::HalfDown),
+            "up" => Some(Rounding::Up),
+            "down" => Some(Rounding::Down),
+            "floor" => Some(Rounding::Floor),
+            "ceiling" => Some(Rounding::Ceiling),
+            _ => None,
+        }
+    }
+}
+impl Decimal {
+    fn new(negative: bool, mut digits: Vec<u8>, scale: u32) -> Self {
+        trim(&mut digits);
+        Decimal { negative: negative && !digits.is_empty(), digits, scale }
+    }
+    /// Reads `-12.50` and the like, without exponents.
+    pub fn parse(text: &str) -> Option<Self> {
+        let text = text.trim();
+        let (negative, text) = match text.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, text.strip_prefix('+').unwrap_or(text)),
+        };
+        let (whole, fraction) = text.split_once('.').unwrap_or((text, ""));
+        if whole.is_empty() && fraction.is_empty() { return None; }
+        if !whole.chars().chain(fraction.chars()).all(|c| c.is_ascii_digit()) { return None; }
+        let digits = whole.bytes().chain(fraction.bytes()).rev().map(|b| b - b'0').collect();
+        Some(Decimal::new(negative, digits, fraction.len() as u32))
+    }
+    /// Reads a number with an optional exponent, like `-1.5e-3` in JSON.
+    pub fn parse_scientific(text: &str) -> Option<Self> {
+        let (mantissa, exponent) = match text.split_once(['e', 'E']) {
+            Some((mantissa, exponent)) => (mantissa, exponent.parse::<i64>().ok()?),
+            None => (text, 0),
+        };
+        if exponent.abs() > MAX_EXPONENT { return None; }
+        let Decimal { negative, digits, scale } = Decimal::parse(mantissa)?;
+        let scale = scale as i64 - exponent;
+        if scale >= 0 { return Some(Decimal::new(negative, digits, scale as u32)); }
+        let mut shifted = vec![0; -scale as usize];
+        shifted.extend(digits);
+        Some(Decimal::new(negative, shifted, 0))
+    }
+    pub fn from_i64(x: i64) -> Self {
+        let digits = x.unsigned_abs().to_string().bytes().rev().map(|b| b - b'0').collect();
+        Decimal::new(x < 0, digits, 0)
+    }
+    /// The shortest decimal that reads back as `x`, which is what was written for a float
+    /// parsed from source or a file. Infinities and NaN have none.
+    pub fn from_f64(x: f64) -> Option<Self> {
+        if !x.is_finite() { return None; }
+        Decimal::parse(&x.to_string())
+    }
+    pub fn to_f64(&self) -> f64 {
+        self.to_string().parse().unwrap_or(f64::NAN)
+    }
+    pub fn is_zero(&self) -> bool { self.digits.is_empty() }
+    pub fn neg(&self) -> Self { Decimal::new(!self.negative, self.digits.clone(), self.scale) }
+    pub fn add(&self, other: &Decimal) -> Self {
+        let scale = self.scale.max(other.scale);
+        let (x, y) = (self.rescaled(scale), other.rescaled(scale));
+        if self.negative == other.negative { return Decimal::new(self.negative, add(&x, &y), scale); }
+        match compare(&x, &y) {
+            Ordering::Less => Decimal::new(other.negative, subtract(&y, &x), scale),
+            _ => Decimal::new(self.negative, subtract(&x, &y), scale),
+        }
+    }
+    pub fn sub(&self, other: &Decimal) -> Self { self.add(&other.neg()) }
+    pub fn mul(&self, other: &Decimal) -> Self {
+        Decimal::new(self.negative != other.negative, multiply(&self.digits, &other.digits), self.scale + other.scale)
+    }
+    /// Exact when the quotient ends within the division scale, 20 places unless changed with
+    /// `set_decimal_rounding`, otherwise rounded there by its mode. `None` when dividing by zero.
+    pub fn div(&self, other: &Decimal) -> Option<Self> {
+        if other.is_zero() { return None; }
+        let (places, mode) = DIVISION.get();
+        let scale = self.scale.max(other.scale).max(places);
+        let numerator = self.rescaled(scale + other.scale);
+        let (quotient, remainder) = divide(&numerator, &other.digits);
+        let negative = self.negative != other.negative;
+        let quotient = round_quotient(quotient, &remainder, &other.digits, negative, mode);
+        // Zeros the exact result does not need are dropped, down to the scale of the operands
+        let mut result = Decimal::new(negative, quotient, scale);
+        let keep = self.scale.max(other.scale);
+        while result.scale > keep && result.digits.first() == Some(&0) {
+            result.digits.remove(0);
+            result.scale -= 1;
+        }
+        if result.scale > keep && result.digits.is_empty() { result.scale = keep; }
+        Some(result)
+    }
+    /// Rounds to `places` digits after the point, padding with zeros when there are fewer.
+    pub fn round(&self, places: u32, mode: Rounding) -> Self {
+        if self.scale <= places { return Decimal::new(self.negative, self.rescaled(places), places); }
+        let dropped = (self.scale - places) as usize;
+        let divisor = power_of_ten(dropped);
+        let (quotient, remainder) = divide(&self.digits, &divisor);
+        Decimal::new(self.negative, round_quotient(quotient, &remainder, &divisor, self.negative, mode), places)
+    }
+    /// The digits with `scale - self.scale` zeros appended, as an integer at the larger scale.
+    fn rescaled(&self, scale: u32) -> Vec<u8> {
+        if self.digits.is_empty() { return vec![]; }
+        let mut digits = vec![0; (scale - self.scale) as usize];
+        digits.extend(&self.digits);
+        digits
+    }
+}
+impl std::fmt::Display for Decimal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let scale = self.scale as usize;
+        let mut text: String = self.digits.iter().rev().map(|d| (b'0' + d) as char).collect();
+        if text.len() <= scale { text = "0".repeat(scale + 1 - text.len()) + &text; }
+        if scale > 0 { text.insert(text.len() - scale, '.'); }
+        write!(f, "{}{}", if self.negative { "-" } else { "" }, text)
+    }
+}
+impl PartialEq for Decimal {
+    fn eq(&self, other: &Self) -> bool { self.cmp(other) == Ordering::Equal }
+}
+impl Eq for Decimal {}
+impl PartialOrd for Decimal {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> { Some(self.cmp(other)) }
+}
+impl Ord for Decimal {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self.negative, other.negative) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (negative, _) => {
+                let scale = self.scale.max(other.scale);
+                let ordering = compare(&self.rescaled(scale), &other.rescaled(scale));
+                if negative { ordering.reverse() } else { ordering }
+            },
+        }
+    }
+}
+fn trim(digits: &mut Vec<u8>) {
+    while digits.last() == Some(&0) { digits.pop(); }
+}
+fn power_of_ten(n: usize) -> Vec<u8> {
+    let mut digits = vec![0; n];
+    digits.push(1);
+    digits
+}
+fn compare(x: &[u8], y: &[u8]) -> Ordering {
+    x.len().cmp(&y.len()).then_with(|| x.iter().rev().cmp(y.iter().rev()))
+}
+fn add(x: &[u8], y: &[u8]) -> Vec<u8> {
+    let mut sum = vec![];
+    let mut carry = 0;
+    for i in 0..x.len().max(y.len()) {
+        let digit = x.get(i).unwrap_or(&0) + y.get(i).unwrap_or(&0) + carry;
+        sum.push(digit % 10);
+        carry = digit / 10;
+    }
+    if carry > 0 { sum.push(carry); }
+    sum
+}
+/// `x - y` for `x >= y`.
+fn subtract(x: &[u8], y: &[u8]) -> Vec<u8> {
+    let mut difference = vec![];
+    let mut borrow = 0;
+    for (i, digit) in x.iter().enumerate() {
+        let subtrahend = y.get(i).unwrap_or(&0) + borrow;
+        borrow = if *digit < subtrahend { 1 } else { 0 };
+        difference.push(digit + borrow * 10 - subtrahend);
+    }
+    trim(&mut difference);
+    difference
+}
+fn multiply(x: &[u8], y: &[u8]) -> Vec<u8> {
+    let mut product = vec![0u32; x.len() + y.len()];
+    for (i, a) in x.iter().enumerate() {
+        for (j, b) in y.iter().enumerate() { product[i + j] += (*a as u32) * (*b as u32); }
+    }
+    let mut digits = vec![];
+    let mut carry = 0;
+    for column in product {
+        let value = column + carry;
+        digits.push((value % 10) as u8);
+        carry = value / 10;
+    }
+    trim(&mut digits);
+    digits
+}
+/// Long division of magnitudes, `divisor` being nonzero.
+fn divide(dividend: &[u8], divisor: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let mut quotient = vec![0; dividend.len()];
+    let mut remainder: Vec<u8> = vec![];
+    for i in (0..dividend.len()).rev() {
+        remainder.insert(0, dividend[i]);
+        trim(&mut remainder);
+        while compare(&remainder, divisor) != Ordering::Less {
+            remainder = subtract(&remainder, divisor);
+            quotient[i] += 1;
+        }
+    }
+    trim(&mut quotient);
+    (quotient, remainder)
+}
+/// Adds one to a truncated quotient when `mode` says the dropped `remainder / divisor` rounds up.
+fn round_quotient(quotient: Vec<u8>, remainder: &[u8], divisor: &[u8], negative: bool, mode: Rounding) -> Vec<u8> {
+    if remainder.is_empty() { return quotient; }
+    let half = compare(&add(remainder, remainder), divisor);
+    let odd = quotient.first().is_some_and(|d| d % 2 == 1);
+    let up = match mode {
+        Rounding::HalfEven => half == Ordering::Greater || (half == Ordering::Equal && odd),
+        Rounding::HalfUp => half != Ordering::Less,
+        Rounding::HalfDown => half == Ordering::Greater,
+        Rounding::Up => true,
+        Rounding::Down => false,
+        Rounding::Floor => negative,
+        Rounding::Ceiling => !negative,
+    };
+    if up { add(&quotient, &[1]) } else { quotient }
+}
+/// `decimal(x)` converts an int, a float or a string like "12.50" to a decimal.
+pub fn decimal_impl(args: &[LiteralValue]) -> Result<LiteralValue, RuntimeError> {
+    let decimal = match args.first() {
+        Some(LiteralValue::Decimal(x)) => Some(x.clone()),
+        Some(LiteralValue::Int(x)) => Some(Decimal::from_i64(*x)),
+        Some(LiteralValue::Float(x)) => Decimal::from_f64(*x),
+        Some(LiteralValue::StringValue(s)) => Decimal::parse(s),
+        _ => None,
+    };
+    decimal.map(LiteralValue::Decimal).ok_or_else(|| match args.first() {
+        Some(other) => format!("Cannot convert {} to a decimal", other.to_string()).into(),
+        None => "decimal() expects 1 argument".into(),
+    })
+}
+/// `float(x)` converts an int, a decimal or a numeric string to the nearest float.
+pub fn float_impl(args: &[LiteralValue]) -> Result<LiteralValue, RuntimeError> {
+    let float = match args.first() {
+        Some(LiteralValue::Float(x)) => Some(*x),
+        Some(LiteralValue::Int(x)) => Some(*x as f64),
+        Some(LiteralValue::Decimal(x)) => Some(x.to_f64()),
+        Some(LiteralValue::StringValue(s)) => s.trim().parse::<f64>().ok(),
+        _ => None,
+    };
+    float.map(LiteralValue::Float).ok_or_else(|| match args.first() {
+        Some(other) => format!("Cannot convert {} to a float", other.to_string()).into(),
+        None => "float() expects 1 argument".into(),
+    })
+}
+/// `round(x, places, mode)` rounds to `places` digits after the point, none by default, using
+/// one of half_even (the default), half_up, half_down, up, down, floor or ceiling. Floats are
+/// rounded as they are printed, so `round(2.675, 2)` is `2.68`.
+pub fn round_impl(args: &[LiteralValue]) -> Result<LiteralValue, RuntimeError> {
+    let places = match args.get(1) {
+        None | Some(LiteralValue::Nil) => 0,
+        Some(LiteralValue::Int(n)) if (0..=u32::MAX as i64).contains(n) => *n as u32,
+        Some(other) => return Err(format!("round() expects a number of places, not {}", other.to_string()).into()),
+    };
+    let mode = expect_rounding(args.get(2), "round")?;
+    match args.first() {
+        Some(LiteralValue::Decimal(x)) => Ok(LiteralValue::Decimal(x.round(places, mode))),
+        Some(LiteralValue::Int(x)) => Ok(LiteralValue::Int(*x)),
+        Some(LiteralValue::Float(x)) => match Decimal::from_f64(*x) {
+            Some(decimal) => Ok(LiteralValue::Float(decimal.round(places, mode).to_f64())),
+            None => Ok(LiteralValue::Float(*x)),
+        },
+        Some(other) => Err(format!("round() is not defined for {}", other.to_type()).into()),
+        None => Err("round() expects 1 argument".into()),
+    }
+}
+/// `set_decimal_rounding(mode, places)` changes how decimal divisions that do not come out
+/// even are rounded from then on: to `places` digits after the point, 20 by default but never
+/// fewer than the operands have, by one of the modes `round` takes. The setting is per thread.
+pub fn set_decimal_rounding_impl(args: &[LiteralValue]) -> Result<LiteralValue, RuntimeError> {
+    let mode = expect_rounding(args.first(), "set_decimal_rounding")?;
+    let places = match args.get(1) {
+        None | Some(LiteralValue::Nil) => DIVISION_SCALE,
+        Some(LiteralValue::Int(n)) if (0..=MAX_DIVISION_SCALE).contains(n) => *n as u32,
+        Some(other) => return Err(format!(
+            "set_decimal_rounding() expects between 0 and {} places, not {}", MAX_DIVISION_SCALE, other.to_string()
+        ).into()),
+    };
+    DIVISION.set((places, mode));
+    Ok(LiteralValue::Nil)
+}
+fn expect_rounding(value: Option<&LiteralValue>, fname: &str) -> Result<Rounding, RuntimeError> {
+    match value {
+        None | Some(LiteralValue::Nil) => Ok(Rounding::HalfEven),
+        Some(LiteralValue::StringValue(name)) => Rounding::from_str(name)
+            .ok_or_else(|| format!("Unknown rounding mode '{}'", name).into()),
+        Some(other) => Err(format!("{}() expects a rounding mode, not {}", fname, other.to_string()).into()),
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn d(text: &str) -> Decimal { Decimal::parse(text).unwrap() }
+    #[test]
+    fn calculates_exactly() {
+        assert_eq!(d("0.1").add(&d("0.2")).to_string(), "0.3");
+        assert_eq!(d("12.50").sub(&d("20")).to_string(), "-7.50");
+        assert_eq!(d("-1.5").mul(&d("0.25")).to_string(), "-0.375");
+        assert_eq!(d("1.00").div(&d("4")).unwrap().to_string(), "0.25");
+        assert_eq!(d("1").div(&d("3")).unwrap().to_string(), "0.33333333333333333333");
+        assert_eq!(d("2").div(&d("3")).unwrap().to_string(), "0.66666666666666666667");
+        assert!(d("1").div(&d("0.00")).is_none());
+        assert_eq!(d("1.10"), d("1.1"));
+        assert!(d("-2") < d("-1.5"));
+    }
+    #[test]
+    fn reads_exponents() {
+        let scientific = |text: &str| Decimal::parse_scientific(text).map(|x| x.to_string());
+        assert_eq!(scientific("1.5e3").as_deref(), Some("1500"));
+        assert_eq!(scientific("-25E-3").as_deref(), Some("-0.025"));
+        assert_eq!(scientific("0e5").as_deref(), Some("0"));
+        assert_eq!(scientific("1e1001"), None);
+    }
+    #[test]
+    fn rounds_by_mode() {
+        let rounded = |text: &str, mode| d(text).round(0, mode).to_string();
+        assert_eq!([rounded("2.5", Rounding::HalfEven), rounded("3.5", Rounding::HalfEven)], ["2", "4"]);
+        assert_eq!([rounded("2.5", Rounding::HalfUp), rounded("2.5", Rounding::HalfDown)], ["3", "2"]);
+        assert_eq!([rounded("-2.1", Rounding::Floor), rounded("-2.9", Rounding::Ceiling)], ["-3", "-2"]);
+        assert_eq!([rounded("2.1", Rounding::Up), rounded("-2.9", Rounding::Down)], ["3", "-2"]);
+        assert_eq!(d("0.004").round(2, Rounding::HalfEven).to_string(), "0.00");
+        assert_eq!(d("7").round(2, Rounding::HalfEven).to_string(), "7.00");
+    }
+}
@@ -0,0 +1,144 @@
+use crate::error::RuntimeError;
+
+/// A region of a source file. `start` and `end` are offsets into the file, `line` and `column`
+/// (both starting at 1) locate `start`, and `file` indexes the program's `SourceMap`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Span {
+    pub start: u32,
+    pub end: u32,
+    pub line: u32,
+    pub column: u32,
+    pub file: u32,
+}
+impl Span {
+    /// The smallest span covering both `self` and `other`.
+    pub fn to(self, other: Span) -> Span {
+        if other.start < self.start { return other.to(self); }
+        Span { end: self.end.max(other.end), ..self }
+    }
+}
+/// The text of every file making up a program, so diagnostics can quote any of them.
+#[derive(Default)]
+pub struct SourceMap {
+    files: Vec<SourceFile>,
+}
+struct SourceFile {
+    /// `None` for programs that did not come from a file, e.g. `fluxar e '...'`.
+    name: Option<String>,
+    text: String,
+}
+impl SourceMap {
+    /// Adds a file, returning the index its spans refer to it by.
+    pub fn add(&mut self, name: Option<String>, text: &str) -> u32 {
+        self.files.push(SourceFile { name, text: text.to_string() });
+        (self.files.len() - 1) as u32
+    }
+}
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    #[allow(dead_code)]
+    Warning,
+}
+#[derive(Debug, Clone)]
+pub struct Label {
+    pub span: Span,
+    pub message: String,
+}
+/// A problem found in a program, pointing at the source it is about.
+///
+/// Codes are grouped by the stage that reports them: `E00xx` scanning, `E01xx` parsing,
+/// `E02xx` name resolution, `E03xx` type checking and `E04xx` running.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: &'static str,
+    pub message: String,
+    /// Where the problem is. Diagnostics without one, e.g. from natives, only print the message.
+    /// Boxed to keep `Result`s carrying a diagnostic small.
+    pub primary: Option<Box<Label>>,
+    /// Other places that explain the problem, such as an earlier declaration.
+    pub secondary: Vec<Label>,
+}
+impl Diagnostic {
+    pub fn error(code: &'static str, message: &str) -> Self {
+        Self { severity: Severity::Error, code, message: message.to_string(), primary: None, secondary: vec![] }
+    }
+    pub fn at(mut self, span: Span, label: &str) -> Self {
+        self.primary = Some(Box::new(Label { span, message: label.to_string() }));
+        self
+    }
+    pub fn with_label(mut self, span: Span, label: &str) -> Self {
+        self.secondary.push(Label { span, message: label.to_string() });
+        self
+    }
+    /// Orders diagnostics by where they point, so those of several stages read top to bottom.
+    pub fn sort(diagnostics: &mut [Diagnostic]) {
+        diagnostics.sort_by_key(|d| d.primary.as_ref().map(|label| (label.span.line, label.span.column)));
+    }
+    /// Renders the diagnostic with the source lines it points at, e.g.
+    ///
+    /// ```text
+    /// error[E0301]: Type error: expected string, found number for variable s
+    ///  --> 2:17
+    ///   |
+    /// 2 | var s: string = add(1, 2);
+    ///   |                 ^^^^^^^^^ found number
+    ///   |     - declared as string
+    /// ```
+    pub fn render(&self, sources: &SourceMap) -> String {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        let mut out = format!("{}[{}]: {}", severity, self.code, self.message);
+        let Some(primary) = &self.primary else { return out };
+
+        let Some(file) = sources.files.get(primary.span.file as usize) else { return out };
+        let lines: Vec<&str> = file.text.split('\n').collect();
+        let mut labels: Vec<(&Label, char)> = vec![(primary.as_ref(), '^')];
+        // Labels in other files would need a snippet of their own, so they are left out
+        labels.extend(self.secondary.iter().filter(|label| label.span.file == primary.span.file).map(|label| (label, '-')));
+        let mut line_numbers: Vec<u32> = labels.iter().map(|(label, _)| label.span.line).collect();
+        line_numbers.sort();
+        line_numbers.dedup();
+        let width = line_numbers.last().map_or(1, |line| line.to_string().len());
+        let gutter = " ".repeat(width);
+
+        let location = format!("{}:{}", primary.span.line, primary.span.column);
+        match &file.name {
+            Some(name) => out.push_str(&format!("\n{}--> {}:{}", gutter, name, location)),
+            None => out.push_str(&format!("\n{}--> {}", gutter, location)),
+        }
+        out.push_str(&format!("\n{} |", gutter));
+        for line_number in line_numbers {
+            let Some(text) = lines.get(line_number as usize - 1) else { continue };
+            let text = text.trim_end_matches('\r');
+            out.push_str(&format!("\n{:>width$} | {}", line_number, text, width = width));
+            // One underline row per label, the rightmost first so labels read left to right
+            let mut on_line: Vec<&(&Label, char)> = labels.iter().filter(|(l, _)| l.span.line == line_number).collect();
+            on_line.sort_by_key(|(label, _)| std::cmp::Reverse(label.span.column));
+            for (label, marker) in on_line {
+                let line_length = text.chars().count();
+                let column = (label.span.column as usize).saturating_sub(1).min(line_length);
+                let length = ((label.span.end - label.span.start) as usize).clamp(1, (line_length - column).max(1));
+                let underline = format!("{}{}", " ".repeat(column), marker.to_string().repeat(length));
+                let row = if label.message.is_empty() { underline } else { format!("{} {}", underline, label.message) };
+                out.push_str(&format!("\n{} | {}", gutter, row));
+            }
+        }
+        out
+    }
+    pub fn render_all(diagnostics: &[Diagnostic], sources: &SourceMap) -> String {
+        diagnostics.iter().map(|d| d.render(sources)).collect::<Vec<String>>().join("\n")
+    }
+}
+impl From<&RuntimeError> for Diagnostic {
+    fn from(error: &RuntimeError) -> Self {
+        let diagnostic = Diagnostic::error("E0400", &error.message);
+        match &error.token {
+            Some(token) => diagnostic.at(token.span, ""),
+            None => diagnostic,
+        }
+    }
+}
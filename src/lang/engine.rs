@@ -0,0 +1,172 @@
+use crate::compiler::Compiler;
+use crate::diagnostic::Diagnostic;
+use crate::environment;
+use crate::error::RuntimeError;
+use crate::expr::LiteralValue;
+use crate::interpreter::Interpreter;
+use crate::module::{self, ModuleLoader};
+use crate::permissions::Permissions;
+use crate::vm::{Globals, VM};
+
+use std::cell::RefCell;
+use std::fs;
+use std::rc::Rc;
+
+/// Which engine runs the program: the tree-walking interpreter or the bytecode VM.
+enum Backend {
+    TreeWalker(Interpreter),
+    Vm(VM),
+}
+/// Runs Fluxar programs. Globals, including registered natives, outlive each `eval`,
+/// so later programs see what earlier ones defined.
+///
+/// ```no_run
+/// use fluxar::{Engine, LiteralValue};
+///
+/// let mut engine = Engine::new();
+/// engine.register_fn("double", 1, |args| match args {
+///     [LiteralValue::Int(x)] => Ok(LiteralValue::Int(x * 2)),
+///     _ => Err("double() expects a number".into()),
+/// });
+/// engine.set_global("base", LiteralValue::Int(20));
+/// assert_eq!(engine.eval("double(base) + 2;").unwrap(), LiteralValue::Int(42));
+/// ```
+pub struct Engine {
+    backend: Backend,
+    loader: Rc<RefCell<ModuleLoader>>,
+    permissions: Rc<RefCell<Permissions>>,
+}
+impl Engine {
+    /// An engine running programs on the tree-walking interpreter.
+    pub fn new() -> Self { Self::with_backend(false) }
+    /// An engine running programs on the bytecode VM.
+    pub fn with_vm() -> Self { Self::with_backend(true) }
+    fn with_backend(use_vm: bool) -> Self {
+        let permissions = Rc::new(RefCell::new(Permissions::allow_all()));
+        let loader = Rc::new(RefCell::new(ModuleLoader::new(permissions.clone())));
+        let backend = if use_vm { Backend::Vm(VM::new(loader.clone(), permissions.clone())) }
+            else { Backend::TreeWalker(Interpreter::new(loader.clone(), permissions.clone())) };
+        Self { backend, loader, permissions }
+    }
+    /// Limits what programs may do outside the engine from now on, including functions
+    /// that earlier programs defined. Everything is allowed until this is called.
+    pub fn set_permissions(&mut self, permissions: Permissions) {
+        *self.permissions.borrow_mut() = permissions;
+    }
+    fn globals(&self) -> Globals {
+        match &self.backend {
+            Backend::TreeWalker(interpreter) => interpreter.environment.globals(),
+            Backend::Vm(vm) => vm.globals(),
+        }
+    }
+    /// Makes a Rust function callable from Fluxar as `name`. Calls with another number of arguments fail.
+    pub fn register_fn(
+        &mut self, name: &str, arity: usize,
+        fun: impl Fn(&[LiteralValue]) -> Result<LiteralValue, RuntimeError> + 'static
+    ) {
+        environment::define_native(&mut self.globals().borrow_mut(), name, arity, fun);
+    }
+    pub fn set_global(&mut self, name: &str, value: LiteralValue) {
+        self.globals().borrow_mut().insert(name.to_string(), value);
+    }
+    pub fn get_global(&self, name: &str) -> Option<LiteralValue> {
+        self.globals().borrow().get(name).cloned()
+    }
+    /// Runs a program, returning the value of its last statement if that is an expression, nil otherwise.
+    /// Errors come back as rendered diagnostics.
+    pub fn eval(&mut self, source: &str) -> Result<LiteralValue, String> { self.run(None, source) }
+    /// Runs the file at `path`, resolving its imports next to it.
+    pub fn run_file(&mut self, path: &str) -> Result<LiteralValue, String> {
+        let source = fs::read_to_string(path).map_err(|msg| format!("error: {}: {}", path, msg))?;
+        self.loader.borrow_mut().set_main(path);
+        self.run(Some(path), &source)
+    }
+    fn run(&mut self, name: Option<&str>, source: &str) -> Result<LiteralValue, String> {
+        let loader = self.loader.clone();
+        let file = loader.borrow_mut().sources.add(name.map(str::to_string), source);
+        let render = |diagnostics: Vec<Diagnostic>| Diagnostic::render_all(&diagnostics, &loader.borrow().sources);
+        let (stmts, locals) = module::analyze(source, file).map_err(render)?;
+
+        let result = match &mut self.backend {
+            Backend::TreeWalker(interpreter) => {
+                interpreter.resolve(locals);
+                interpreter.run(&stmts)
+            },
+            Backend::Vm(vm) => {
+                let function = Compiler::new(self.permissions.clone()).compile(&stmts.iter().collect())?;
+                vm.interpret(function)
+            },
+        };
+        // A module that failed to load has its own diagnostics to show after the failed import
+        result.map_err(|e| {
+            let mut diagnostics = vec![Diagnostic::from(&e)];
+            diagnostics.extend(loader.borrow_mut().take_diagnostics());
+            render(diagnostics)
+        })
+    }
+}
+impl Default for Engine {
+    fn default() -> Self { Self::new() }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::permissions::Access;
+    fn engines() -> [Engine; 2] { [Engine::new(), Engine::with_vm()] }
+    #[test]
+    fn calls_registered_natives() {
+        for mut engine in engines() {
+            engine.register_fn("double", 1, |args| match args {
+                [LiteralValue::Int(x)] => Ok(LiteralValue::Int(x * 2)),
+                _ => Err("double() expects a number".into()),
+            });
+            assert_eq!(engine.eval("double(21);").unwrap(), LiteralValue::Int(42));
+            let error = engine.eval("double(\"x\");").unwrap_err();
+            assert!(error.starts_with("error[E0400]: double() expects a number"), "{}", error);
+            let error = engine.eval("double(1, 2);").unwrap_err();
+            assert!(error.starts_with("error[E0400]: Callable double expected 1 arguments but got 2"), "{}", error);
+        }
+    }
+    #[test]
+    fn shares_globals_between_evals() {
+        for mut engine in engines() {
+            engine.set_global("base", LiteralValue::Int(40));
+            assert_eq!(engine.eval("var answer = base + 2;").unwrap(), LiteralValue::Nil);
+            assert_eq!(engine.get_global("answer"), Some(LiteralValue::Int(42)));
+            assert_eq!(engine.eval("answer - base;").unwrap(), LiteralValue::Int(2));
+            assert_eq!(engine.get_global("missing"), None);
+        }
+    }
+    #[test]
+    fn denies_what_permissions_leave_out() {
+        for mut engine in engines() {
+            engine.eval("fun greet <- \"echo hi\"; fun list(dir) <- \"ls {dir}\";").unwrap();
+            engine.set_permissions(Permissions { run: Access::Only(vec!["echo".to_string()]), ..Permissions::deny_all() });
+            assert!(engine.eval("greet().code;").is_ok());
+            let error = engine.eval("list(\".\");").unwrap_err();
+            assert!(error.starts_with("error[E0400]: Permission denied: run access to 'ls'"), "{}", error);
+            let error = engine.eval("fun listing <- \"ls\"; listing |> collect;").unwrap_err();
+            assert!(error.starts_with("error[E0400]: Permission denied: run access to 'ls'"), "{}", error);
+            let error = engine.eval("read_csv(\"data.csv\");").unwrap_err();
+            assert!(error.starts_with("error[E0400]: Permission denied: read access to 'data.csv'"), "{}", error);
+        }
+    }
+    #[test]
+    fn denies_imports_without_read_access() {
+        let dir = std::env::temp_dir().join(format!("fluxar-import-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let secret = dir.join("secret.txt");
+        fs::write(&secret, "root:x:0:0:root:/root:/bin/bash\n").unwrap();
+        let import = format!("import \"{}\" as secret;", secret.display());
+        for mut engine in engines() {
+            engine.set_permissions(Permissions::deny_all());
+            let error = engine.eval(&import).unwrap_err();
+            assert!(error.starts_with("error[E0400]: Permission denied: read access to"), "{}", error);
+            assert!(!error.contains("root:x"), "{}", error);
+            engine.set_permissions(Permissions { read: Access::Only(vec![dir.display().to_string()]), ..Permissions::deny_all() });
+            let error = engine.eval(&import).unwrap_err();
+            assert!(error.contains("Module '") && error.contains("has errors"), "{}", error);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
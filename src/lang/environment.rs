@@ -1,125 +1,235 @@
 use std::collections::HashMap;
-use crate::expr::{LiteralValue, NativeFunctionImpl, CallableImpl};
+use crate::expr::{LiteralValue, NativeFunctionImpl, CallableImpl, VARIADIC};
+use crate::table;
+use crate::csv;
+use crate::json;
+use crate::command;
+use crate::decimal;
+use crate::error::{self, RuntimeError};
+use crate::permissions::Permissions;
 use std::rc::Rc;
 use std::cell::RefCell;
 
+/// A scope at runtime. Locals live in `slots`, in the order the resolver numbered them,
+/// and are found by `(depth, slot)`; only the outermost environment keeps names, for globals.
 #[derive(Clone)]
 pub struct Environment {
-    pub values: Rc<RefCell<HashMap<String, LiteralValue>>>,
-    locals: Rc<RefCell<HashMap<usize, usize>>>,
+    globals: Rc<RefCell<HashMap<String, LiteralValue>>>,
+    slots: Rc<RefCell<Vec<LiteralValue>>>,
+    locals: Rc<RefCell<HashMap<usize, (usize, usize)>>>,
+    permissions: Rc<RefCell<Permissions>>,
     pub enclosing: Option<Box<Environment>>,
 }
-fn clock_impl(_args: &Vec<LiteralValue>) -> LiteralValue {
+fn clock_impl(_args: &[LiteralValue]) -> Result<LiteralValue, RuntimeError> {
     let now = std::time::SystemTime::now()
         .duration_since(std::time::SystemTime::UNIX_EPOCH)
-        .expect("Could not get system time")
+        .map_err(|e| RuntimeError::from(format!("Could not get system time: {}", e)))?
         .as_millis();
-    LiteralValue::Number(now as f64 / 1000.0)
+    Ok(LiteralValue::Float(now as f64 / 1000.0))
 }
-fn get_globals() -> Rc<RefCell<HashMap<String, LiteralValue>>> {
-    let mut env = HashMap::new();
+fn len_impl(args: &[LiteralValue]) -> Result<LiteralValue, RuntimeError> {
+    match args.first() {
+        Some(LiteralValue::List(items)) => Ok(LiteralValue::Int(items.borrow().len() as i64)),
+        Some(LiteralValue::StringValue(s)) => Ok(LiteralValue::Int(s.chars().count() as i64)),
+        Some(LiteralValue::Map(entries)) => Ok(LiteralValue::Int(entries.borrow().len() as i64)),
+        Some(LiteralValue::Table(table)) => Ok(LiteralValue::Int(table.row_count() as i64)),
+        Some(other) => Err(format!("len() is not defined for {}", other.to_type()).into()),
+        None => Err("len() expects 1 argument".into()),
+    }
+}
+fn push_impl(args: &[LiteralValue]) -> Result<LiteralValue, RuntimeError> {
+    match (args.first(), args.get(1)) {
+        (Some(LiteralValue::List(items)), Some(value)) => {
+            items.borrow_mut().push(value.clone());
+            Ok(LiteralValue::Nil)
+        },
+        (Some(LiteralValue::List(_)), None) => Err("push() expects 2 arguments".into()),
+        (Some(other), _) => Err(format!("Cannot push onto {}", other.to_type()).into()),
+        (None, _) => Err("push() expects 2 arguments".into()),
+    }
+}
+fn keys_impl(args: &[LiteralValue]) -> Result<LiteralValue, RuntimeError> {
+    match args.first() {
+        Some(LiteralValue::Map(entries)) => Ok(LiteralValue::List(Rc::new(RefCell::new(
+            entries.borrow().iter().map(|(key, _)| key.clone()).collect()
+        )))),
+        Some(other) => Err(format!("keys() is not defined for {}", other.to_type()).into()),
+        None => Err("keys() expects 1 argument".into()),
+    }
+}
+fn values_impl(args: &[LiteralValue]) -> Result<LiteralValue, RuntimeError> {
+    match args.first() {
+        Some(LiteralValue::Map(entries)) => Ok(LiteralValue::List(Rc::new(RefCell::new(
+            entries.borrow().iter().map(|(_, value)| value.clone()).collect()
+        )))),
+        Some(other) => Err(format!("values() is not defined for {}", other.to_type()).into()),
+        None => Err("values() expects 1 argument".into()),
+    }
+}
+fn has_impl(args: &[LiteralValue]) -> Result<LiteralValue, RuntimeError> {
+    match (args.first(), args.get(1)) {
+        (Some(LiteralValue::Map(entries)), Some(key)) => Ok(LiteralValue::from_bool(
+            entries.borrow().iter().any(|(existing, _)| existing == key)
+        )),
+        (Some(LiteralValue::List(items)), Some(value)) => Ok(LiteralValue::from_bool(
+            items.borrow().contains(value)
+        )),
+        (Some(other), Some(_)) => Err(format!("has() is not defined for {}", other.to_type()).into()),
+        _ => Err("has() expects 2 arguments".into()),
+    }
+}
+fn remove_impl(args: &[LiteralValue]) -> Result<LiteralValue, RuntimeError> {
+    match (args.first(), args.get(1)) {
+        (Some(LiteralValue::Map(entries)), Some(key)) => {
+            let mut entries = entries.borrow_mut();
+            match entries.iter().position(|(existing, _)| existing == key) {
+                Some(i) => Ok(entries.remove(i).1),
+                None => Ok(LiteralValue::Nil),
+            }
+        },
+        (Some(other), Some(_)) => Err(format!("remove() is not defined for {}", other.to_type()).into()),
+        _ => Err("remove() expects 2 arguments".into()),
+    }
+}
+pub fn define_native(
+    env: &mut HashMap<String, LiteralValue>, name: &str, arity: usize,
+    fun: impl Fn(&[LiteralValue]) -> Result<LiteralValue, RuntimeError> + 'static
+) {
+    define_native_with_optional(env, name, arity, 0, fun);
+}
+/// Like `define_native`, for natives that also take up to `optional` trailing arguments.
+fn define_native_with_optional(
+    env: &mut HashMap<String, LiteralValue>, name: &str, arity: usize, optional: usize,
+    fun: impl Fn(&[LiteralValue]) -> Result<LiteralValue, RuntimeError> + 'static
+) {
     let fun_impl = NativeFunctionImpl {
-        name: "clock".to_string(),
-        arity: 0, fun: Rc::new(clock_impl)
+        name: name.to_string(),
+        arity, optional, fun: Rc::new(fun)
     };
     let callable_impl = CallableImpl::NativeFunction(fun_impl);
-    env.insert("clock".to_string(), LiteralValue::Callable(callable_impl));
+    env.insert(name.to_string(), LiteralValue::Callable(callable_impl));
+}
+/// The globals every file starts with. Natives touching files check `permissions` when called.
+pub fn get_globals(permissions: &Rc<RefCell<Permissions>>) -> Rc<RefCell<HashMap<String, LiteralValue>>> {
+    let mut env = HashMap::new();
+    env.insert("Error".to_string(), error::error_class());
+    define_native(&mut env, "clock", 0, clock_impl);
+    define_native(&mut env, "len", 1, len_impl);
+    define_native(&mut env, "push", 2, push_impl);
+    define_native(&mut env, "keys", 1, keys_impl);
+    define_native(&mut env, "values", 1, values_impl);
+    define_native(&mut env, "has", 2, has_impl);
+    define_native(&mut env, "remove", 2, remove_impl);
+    define_native(&mut env, "decimal", 1, decimal::decimal_impl);
+    define_native(&mut env, "float", 1, decimal::float_impl);
+    define_native_with_optional(&mut env, "round", 1, 2, decimal::round_impl);
+    define_native_with_optional(&mut env, "set_decimal_rounding", 1, 1, decimal::set_decimal_rounding_impl);
+    define_native(&mut env, "table", 1, table::table_impl);
+    define_native(&mut env, "rows", 1, table::rows_impl);
+    define_native(&mut env, "columns", 1, table::columns_impl);
+    define_native(&mut env, "filter", 2, table::filter_impl);
+    define_native_with_optional(&mut env, "select", 2, VARIADIC, table::select_impl);
+    define_native_with_optional(&mut env, "sort_by", 2, 1, table::sort_by_impl);
+    define_native_with_optional(&mut env, "group_by", 2, 1, table::group_by_impl);
+    define_native_with_optional(&mut env, "join", 3, 1, table::join_impl);
+    define_native_with_optional(&mut env, "head", 1, 1, table::head_impl);
+    define_native_with_optional(&mut env, "distinct", 1, 1, table::distinct_impl);
+    let read = permissions.clone();
+    define_native_with_optional(&mut env, "read_csv", 1, 1, move |args| csv::read_csv_impl(args, &read.borrow()));
+    let write = permissions.clone();
+    define_native_with_optional(&mut env, "write_csv", 2, 1, move |args| csv::write_csv_impl(args, &write.borrow()));
+    define_native(&mut env, "json_parse", 1, json::json_parse_impl);
+    define_native_with_optional(&mut env, "json_stringify", 1, 1, json::json_stringify_impl);
+    define_native(&mut env, "next", 1, command::next_impl);
+    define_native(&mut env, "collect", 1, command::collect_impl);
     Rc::new(RefCell::new(env))
 }
 impl Environment {
-    pub fn new(locals: HashMap<usize, usize>) -> Self {
+    pub fn new(locals: HashMap<usize, (usize, usize)>, permissions: Rc<RefCell<Permissions>>) -> Self {
         Self {
-            values: get_globals(),
+            globals: get_globals(&permissions),
+            slots: Rc::new(RefCell::new(vec![])),
             locals: Rc::new(RefCell::new(locals)),
+            permissions,
             enclosing: None,
         }
     }
-    pub fn resolve(&self, locals: HashMap<usize, usize>) {
+    /// The names defined at the top level, which is what a module exports.
+    pub fn globals(&self) -> Rc<RefCell<HashMap<String, LiteralValue>>> { self.globals.clone() }
+    pub fn permissions(&self) -> Rc<RefCell<Permissions>> { self.permissions.clone() }
+    pub fn resolve(&self, locals: HashMap<usize, (usize, usize)>) {
         for (key, val) in locals.iter() {
             self.locals.borrow_mut().insert(*key, *val);
         }
     }
     pub fn enclose(&self) -> Environment {
         Self {
-            values: Rc::new(RefCell::new(HashMap::new())),
+            globals: self.globals.clone(),
+            slots: Rc::new(RefCell::new(vec![])),
             locals: self.locals.clone(),
+            permissions: self.permissions.clone(),
             enclosing: Some(Box::new(self.clone())),
         }
     }
+    /// Defines a global in the outermost environment, or the next slot of a local scope.
     pub fn define(&mut self, name: String, value: LiteralValue) {
-        self.values.borrow_mut().insert(name, value);
+        match self.enclosing {
+            None => { self.globals.borrow_mut().insert(name, value); },
+            Some(_) => self.slots.borrow_mut().push(value),
+        }
     }
     pub fn get(&self, name: &str, expr_id: usize) -> Option<LiteralValue> {
-        let distance = self.locals.borrow().get(&expr_id).cloned();
-        self.get_internal(name, distance)
+        match self.locals.borrow().get(&expr_id).cloned() {
+            Some((distance, slot)) => self.ancestor(distance)?.slots.borrow().get(slot).cloned(),
+            None => self.globals.borrow().get(name).cloned(),
+        }
     }
+    /// `this` is the first slot of the scope just inside the one holding `super`.
     pub fn get_this_instance(&self, super_id: usize) -> Option<LiteralValue> {
-        let distance = self.locals.borrow().get(&super_id)
-            .cloned().expect("Could not find 'this' even though 'super' was defined");
-        self.get_internal("this", Some(distance - 1))
+        let (distance, _) = self.locals.borrow().get(&super_id).cloned()?;
+        self.ancestor(distance.checked_sub(1)?)?.slots.borrow().first().cloned()
     }
     pub fn get_distance(&self, expr_id: usize) -> Option<usize> {
-        self.locals.borrow().get(&expr_id).cloned()
+        self.locals.borrow().get(&expr_id).map(|(distance, _)| *distance)
     }
-    fn get_internal(&self, name: &str, distance: Option<usize>) -> Option<LiteralValue> {
-        if let None = distance {
-            match &self.enclosing {
-                None => self.values.borrow().get(name).cloned(),
-                Some(env) => env.get_internal(name, distance),
-            }
-        } else {
-            let distance = distance.unwrap();
-            if distance == 0 { self.values.borrow().get(name).cloned() }
-            else {
-                match &self.enclosing {
-                    None => panic!("Tried to resolve a variable that was defined deeper than the current environment depth"),
-                    Some(env) => {
-                        assert!(distance > 0);
-                        env.get_internal(name, Some(distance - 1))
-                    }
-                }
-            }
+    fn ancestor(&self, distance: usize) -> Option<&Environment> {
+        let mut environment = self;
+        for _ in 0..distance {
+            // The resolver put the variable deeper than this environment goes
+            environment = environment.enclosing.as_ref()?;
         }
-    }
-    pub fn assign_global(&self, name: &str, value: LiteralValue) -> bool {
-        self.assign_internal(name, value, None)
+        Some(environment)
     }
     pub fn assign(&self, name: &str, value: LiteralValue, expr_id: usize) -> bool {
         // ! Important that this ID matches with the resolver
-        let distance = self.locals.borrow().get(&expr_id).cloned();
-        self.assign_internal(name, value, distance)
-    }
-    fn assign_internal(&self, name: &str, value: LiteralValue, distance: Option<usize>) -> bool {
-        if let None = distance {
-            match &self.enclosing {
-                Some(env) => env.assign_internal(name, value, distance),
-                None => match self.values.borrow_mut().insert(name.to_string(), value) {
-                    Some(_) => true, None => false,
-                },
-            }
-        } else {
-            let distance = distance.unwrap();
-            if distance == 0 { self.values.borrow_mut().insert(name.to_string(), value); true }
-            else {
-                match &self.enclosing {
-                    None => panic!("Tried to define a variable in a too deep level"),
-                    Some(env) => env.assign_internal(
-                        name, value, Some(distance - 1)
-                    ),
-                };
-                true
-            }
+        match self.locals.borrow().get(&expr_id).cloned() {
+            Some((distance, slot)) => {
+                let Some(environment) = self.ancestor(distance) else { return false };
+                match environment.slots.borrow_mut().get_mut(slot) {
+                    Some(existing) => { *existing = value; true },
+                    None => false,
+                }
+            },
+            None => match self.globals.borrow_mut().get_mut(name) {
+                Some(existing) => { *existing = value; true },
+                None => false,
+            },
         }
     }
     #[allow(dead_code)]
     pub fn dump(&self, indent: usize) -> String {
         let mut result = String::new();
-        for (key, val) in self.values.borrow().iter() {
+        let lines: Vec<String> = match self.enclosing {
+            None => self.globals.borrow().iter().map(|(key, val)| format!("{}: {:?}", key, val)).collect(),
+            Some(_) => self.slots.borrow().iter().enumerate().map(|(slot, val)| format!("{}: {:?}", slot, val)).collect(),
+        };
+        for line in lines {
             for _ in 0..indent { result.push_str("  "); }
-            result.push_str(&format!("{}: {:?}\n", key, val));
+            result.push_str(&line);
+            result.push('\n');
         }
         if let Some(env) = &self.enclosing {
             result.push_str(&env.dump(indent + 2));
         } result
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,73 @@
+use crate::expr::LiteralValue;
+use crate::scanner::Token;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// An error raised while running a program. It remembers the token it was raised at
+/// (when there is one) so the line can be reported instead of aborting the process.
+#[derive(Debug, Clone)]
+pub struct RuntimeError {
+    pub message: String,
+    pub token: Option<Token>,
+    /// The value given to a `throw` statement, `None` for errors raised by the interpreter itself.
+    pub value: Option<Box<LiteralValue>>,
+}
+impl RuntimeError {
+    pub fn new(token: &Token, message: &str) -> Self {
+        Self { message: message.to_string(), token: Some(token.clone()), value: None }
+    }
+    pub fn thrown(token: &Token, value: LiteralValue) -> Self {
+        Self {
+            message: format!("Uncaught {}", value.to_string()),
+            token: Some(token.clone()), value: Some(Box::new(value)),
+        }
+    }
+    /// Attaches a location to errors that were raised without one, e.g. inside a native function.
+    pub fn or_at(mut self, token: &Token) -> Self {
+        if self.token.is_none() { self.token = Some(token.clone()); }
+        self
+    }
+    pub fn line(&self) -> Option<u32> { self.token.as_ref().map(|token| token.span.line) }
+    /// The value bound by a `catch` clause: whatever was thrown, or an `Error` instance
+    /// with `message` and `line` fields for errors raised by the interpreter or a native.
+    pub fn to_value(&self) -> LiteralValue {
+        if let Some(value) = &self.value { return (**value).clone(); }
+        let line = match self.line() {
+            Some(line) => LiteralValue::Int(line as i64),
+            None => LiteralValue::Nil,
+        };
+        LiteralValue::FluxarInstance {
+            class: Box::new(error_class()),
+            fields: Rc::new(RefCell::new(vec![
+                ("message".to_string(), LiteralValue::StringValue(self.message.clone())),
+                ("line".to_string(), line),
+            ])),
+        }
+    }
+}
+pub fn error_class() -> LiteralValue {
+    LiteralValue::FluxarClass {
+        name: "Error".to_string(), generics: vec![],
+        methods: HashMap::new(), superclass: None,
+    }
+}
+impl From<String> for RuntimeError {
+    fn from(message: String) -> Self {
+        Self { message, token: None, value: None }
+    }
+}
+impl From<&str> for RuntimeError {
+    fn from(message: &str) -> Self {
+        Self::from(message.to_string())
+    }
+}
+impl std::fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.line() {
+            Some(line) => write!(f, "Line {}: {}", line, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
@@ -1,44 +1,79 @@
 use std::rc::Rc;
 use std::cell::RefCell;
 use std::hash::{Hash, Hasher};
-use std::cmp::{PartialEq, Eq};
+use std::cmp::{PartialEq, Eq, Ordering};
 use std::collections::HashMap;
 
 use crate::scanner::{self, Token, TokenType};
 use crate::environment::Environment;
-use crate::interpreter::Interpreter;
+use crate::interpreter::{Interpreter, ControlFlow};
+use crate::error::RuntimeError;
+use crate::table::Table;
+use crate::vm::{self, ClosureImpl};
+use crate::command::{self, CommandFunction, LineStream};
+use crate::number;
+use crate::decimal;
+use crate::type_::{TypeAnnotation, TypeParam};
+use crate::diagnostic::Span;
 
 #[derive(Clone)]
 pub enum CallableImpl {
     FluxarFunction(FluxarFunctionImpl),
     NativeFunction(NativeFunctionImpl),
+    /// A function compiled to bytecode, only created when running on the VM.
+    Closure(ClosureImpl),
+    Command(CommandFunction),
 }
 use CallableImpl::*;
 #[derive(Clone)]
 pub struct FluxarFunctionImpl {
     pub name: String, pub arity: usize,
     pub parent_env: Environment, pub params: Vec<Token>,
-    pub generics: Vec<Token>, pub return_type: Option<Token>,
+    pub generics: Vec<Token>, pub return_type: Option<TypeAnnotation>,
     pub body: Vec<Box<Statement>>,
 }
 #[derive(Clone)]
 pub struct NativeFunctionImpl {
     pub name: String, pub arity: usize,
-    pub fun: Rc<dyn Fn(&Vec<LiteralValue>) -> LiteralValue>,
+    /// How many arguments it takes beyond `arity`, `VARIADIC` for any number.
+    pub optional: usize,
+    pub fun: Rc<dyn Fn(&[LiteralValue]) -> Result<LiteralValue, RuntimeError>>,
+}
+pub const VARIADIC: usize = usize::MAX;
+impl NativeFunctionImpl {
+    pub fn check_arity(&self, argc: usize) -> Result<(), RuntimeError> {
+        if argc >= self.arity && argc - self.arity <= self.optional { return Ok(()); }
+        let expected = match self.optional {
+            0 => self.arity.to_string(),
+            VARIADIC => format!("at least {}", self.arity),
+            optional => format!("{} to {}", self.arity, self.arity + optional),
+        };
+        Err(format!("Callable {} expected {} arguments but got {}", self.name, expected, argc).into())
+    }
 }
 #[derive(Clone)]
 pub enum LiteralValue {
-    Number(f64), StringValue(String),
+    Int(i64), Float(f64), Decimal(decimal::Decimal), StringValue(String),
     True, False, Nil, Callable(CallableImpl),
+    List(Rc<RefCell<Vec<LiteralValue>>>),
+    Map(Rc<RefCell<Vec<(LiteralValue, LiteralValue)>>>),
+    Table(Rc<Table>),
     FluxarClass { 
         name: String, generics: Vec<Token>,
-        methods: HashMap<String, FluxarFunctionImpl>,
+        methods: HashMap<String, CallableImpl>,
         superclass: Option<Box<LiteralValue>>
     },
     FluxarInstance { 
         class: Box<LiteralValue>, 
         fields: Rc<RefCell<Vec<(String, LiteralValue)>>>
     },
+    /// An imported file, holding the globals it defined.
+    Module {
+        name: String,
+        globals: Rc<RefCell<HashMap<String, LiteralValue>>>,
+    },
+    /// The output of a command pipeline, read a line at a time.
+    Stream(Rc<RefCell<LineStream>>),
 }
 use LiteralValue::*;
 impl std::fmt::Debug for LiteralValue {
@@ -49,102 +84,169 @@ impl std::fmt::Debug for LiteralValue {
 impl PartialEq for LiteralValue {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
-            (Number(x), Number(y)) => x == y, (
+            (Int(x), Int(y)) => x == y,
+            (Int(_) | Float(_) | Decimal(_), Int(_) | Float(_) | Decimal(_)) => number::ordering(self, other) == Some(Ordering::Equal), (
                 Callable(CallableImpl::FluxarFunction(FluxarFunctionImpl { name, arity, .. })),
                 Callable(CallableImpl::FluxarFunction(FluxarFunctionImpl { name: name2, arity: arity2, .. })),
             ) => name == name2 && arity == arity2, (
                 Callable(CallableImpl::NativeFunction(NativeFunctionImpl { name, arity, .. })),
                 Callable(CallableImpl::NativeFunction(NativeFunctionImpl { name: name2, arity: arity2, .. })),
-            ) => name == name2 && arity == arity2,
+            ) => name == name2 && arity == arity2, (
+                Callable(CallableImpl::Closure(closure)), Callable(CallableImpl::Closure(closure2)),
+            ) => closure.function.name == closure2.function.name && closure.function.arity == closure2.function.arity, (
+                Callable(CallableImpl::Command(command)), Callable(CallableImpl::Command(command2)),
+            ) => command.name == command2.name && command.arity == command2.arity,
             (StringValue(x), StringValue(y)) => x == y,
-            (True, True) => true, (False, False) => false,
+            (List(x), List(y)) => Rc::ptr_eq(x, y) || *x.borrow() == *y.borrow(),
+            (Map(x), Map(y)) => {
+                if Rc::ptr_eq(x, y) { return true; }
+                let (x, y) = (x.borrow(), y.borrow());
+                x.len() == y.len() && x.iter().all(|(key, value)| {
+                    y.iter().any(|(key2, value2)| key == key2 && value == value2)
+                })
+            },
+            (Table(x), Table(y)) => Rc::ptr_eq(x, y) || **x == **y,
+            (Module { globals: x, .. }, Module { globals: y, .. }) => Rc::ptr_eq(x, y),
+            (Stream(x), Stream(y)) => Rc::ptr_eq(x, y),
+            (True, True) => true, (False, False) => true,
             (Nil, Nil) => true, _ => false
         }
     }
 }
-fn unwrap_as_f64(literal: Option<scanner::LiteralValue>) -> f64 {
-    match literal {
-        Some(scanner::LiteralValue::FValue(x)) => x as f64,
-        _ => panic!("Could not unwrap as f64"),
+fn unwrap_as_number(token: &Token) -> Result<LiteralValue, RuntimeError> {
+    match token.literal {
+        Some(scanner::LiteralValue::IValue(x)) => Ok(Int(x)),
+        Some(scanner::LiteralValue::FValue(x)) => Ok(Float(x)),
+        Some(scanner::LiteralValue::DValue(ref x)) => Ok(Decimal(x.clone())),
+        _ => Err(RuntimeError::new(token, "Could not unwrap as number")),
     }
 }
-fn unwrap_as_string(literal: Option<scanner::LiteralValue>) -> String {
-    match literal {
-        Some(scanner::LiteralValue::StringValue(s)) => s.clone(),
-        _ => panic!("Could not unwrap as string!"),
+fn unwrap_as_string(token: &Token) -> Result<String, RuntimeError> {
+    match &token.literal {
+        Some(scanner::LiteralValue::StringValue(s)) => Ok(s.clone()),
+        _ => Err(RuntimeError::new(token, "Could not unwrap as string!")),
     }
 }
 macro_rules! class_name {
     ($class:expr) => {{
         if let LiteralValue::FluxarClass { name, generics: _, 
-            methods: _, superclass: _ } = &**$class { name }
-        else { panic!("Unreachable") }
+            methods: _, superclass: _ } = &**$class { name.as_str() }
+        else { "<unknown class>" }
     }};
 }
 impl LiteralValue {
     pub fn to_string(&self) -> String {
         match self {
-            LiteralValue::Number(x) => x.to_string(),
+            LiteralValue::Int(x) => x.to_string(),
+            LiteralValue::Float(x) => number::format_float(*x),
+            LiteralValue::Decimal(x) => x.to_string(),
             LiteralValue::StringValue(x) => format!("\"{}\"", x),
             LiteralValue::True => "true".to_string(),
             LiteralValue::False => "false".to_string(),
             LiteralValue::Nil => "nil".to_string(),
+            LiteralValue::List(items) => format!(
+                "[{}]", items.borrow().iter().map(|v| v.to_string()).collect::<Vec<String>>().join(", ")
+            ),
+            LiteralValue::Map(entries) => format!(
+                "{{{}}}", entries.borrow().iter()
+                    .map(|(key, value)| format!("{}: {}", key.to_string(), value.to_string()))
+                    .collect::<Vec<String>>().join(", ")
+            ),
+            LiteralValue::Table(table) => table.to_string(),
             LiteralValue::Callable(CallableImpl::FluxarFunction(
                 FluxarFunctionImpl { name, arity, .. }
             )) => format!("{name}/{arity}"),
             LiteralValue::Callable(CallableImpl::NativeFunction(
                 NativeFunctionImpl { name, arity, .. }
             )) => format!("{name}/{arity}"),
+            LiteralValue::Callable(CallableImpl::Closure(closure))
+                => format!("{}/{}", closure.function.name, closure.function.arity),
+            LiteralValue::Callable(CallableImpl::Command(command))
+                => format!("{}/{}", command.name, command.arity),
             LiteralValue::FluxarClass { name, generics: _,
                 methods: _, superclass: _ } => format!("Class '{name}'"),
             LiteralValue::FluxarInstance { class, fields: _ }
                 => format!("Instance if '{}'", class_name!(class)),
+            LiteralValue::Module { name, .. } => format!("Module '{name}'"),
+            LiteralValue::Stream(_) => "<stream>".to_string(),
+        }
+    }
+    /// How the value reads inside other text, e.g. an interpolated string: strings as they are,
+    /// anything else as printed.
+    pub fn to_text(&self) -> String {
+        match self {
+            LiteralValue::StringValue(s) => s.clone(),
+            other => other.to_string(),
         }
     }
     pub fn to_type(&self) -> &str {
         match self {
-            LiteralValue::Number(_) => "Number",
+            LiteralValue::Int(_) => "Int",
+            LiteralValue::Float(_) => "Float",
+            LiteralValue::Decimal(_) => "Decimal",
             LiteralValue::StringValue(_) => "String",
             LiteralValue::True => "Boolean",
             LiteralValue::False => "Boolean",
             LiteralValue::Nil => "nil",
+            LiteralValue::List(_) => "List",
+            LiteralValue::Map(_) => "Map",
+            LiteralValue::Table(_) => "Table",
             LiteralValue::Callable(_) => "Callable",
             LiteralValue::FluxarClass { name: _, generics: _, 
                 methods: _, superclass: _ } => "Class",
-            LiteralValue::FluxarInstance { class, fields: _ } => &class_name!(class),
+            LiteralValue::FluxarInstance { class, fields: _ } => class_name!(class),
+            LiteralValue::Module { .. } => "Module",
+            LiteralValue::Stream(_) => "Stream",
         }
     }
-    pub fn from_token(token: Token) -> Self {
+    pub fn from_token(token: Token) -> Result<Self, RuntimeError> {
         match token.token_type {
-            TokenType::Number => Self::Number(unwrap_as_f64(token.literal)),
-            TokenType::StringLit => Self::StringValue(unwrap_as_string(token.literal)),
-            TokenType::False => Self::False, 
-            TokenType::True => Self::True,
-            TokenType::Nil => Self::Nil,
-            _ => panic!("Could not create LiteralValue from {:?}", token),
+            TokenType::Number => unwrap_as_number(&token),
+            TokenType::StringLit => Ok(Self::StringValue(unwrap_as_string(&token)?)),
+            TokenType::False => Ok(Self::False),
+            TokenType::True => Ok(Self::True),
+            TokenType::Nil => Ok(Self::Nil),
+            _ => Err(RuntimeError::new(&token, &format!("Could not create LiteralValue from {}", token.lexeme))),
         }
     }
     pub fn from_bool(b: bool) -> Self {
         if b {True} else {False}
     }
-    pub fn is_false(&self) -> LiteralValue {
+    pub fn new_map(entries: Vec<(LiteralValue, LiteralValue)>) -> Self {
+        Map(Rc::new(RefCell::new(entries)))
+    }
+    /// Map keys are restricted to values with a stable notion of equality.
+    pub fn is_valid_key(&self) -> bool {
+        matches!(self, Int(_) | Float(_) | Decimal(_) | StringValue(_) | True | False | Nil)
+    }
+    pub fn is_false(&self) -> Result<LiteralValue, RuntimeError> {
         match self {
-            Number(x) => if *x == 0 as f64 {True} else {False},
-            StringValue(s) => if s.len() == 0 {True} else {False},
-            True => False, False => True, Nil => True,
-            Callable(_) => panic!("Cannot use Callable as a false value"),
-            FluxarClass { .. } => panic!("Cannot use class as a false value"),
-            _ => panic!("Not valid as a boolean value"),
+            Int(x) => Ok(if *x == 0 {True} else {False}),
+            Float(x) => Ok(if *x == 0.0 {True} else {False}),
+            Decimal(x) => Ok(if x.is_zero() {True} else {False}),
+            StringValue(s) => Ok(if s.len() == 0 {True} else {False}),
+            True => Ok(False), False => Ok(True), Nil => Ok(True),
+            List(items) => Ok(if items.borrow().is_empty() {True} else {False}),
+            Map(entries) => Ok(if entries.borrow().is_empty() {True} else {False}),
+            Table(table) => Ok(if table.row_count() == 0 {True} else {False}),
+            Callable(_) => Err("Cannot use Callable as a false value".into()),
+            FluxarClass { .. } => Err("Cannot use class as a false value".into()),
+            _ => Err(format!("{} is not valid as a boolean value", self.to_type()).into()),
         }
     }
-    pub fn is_true(&self) -> LiteralValue {
+    pub fn is_true(&self) -> Result<LiteralValue, RuntimeError> {
         match self {
-            Number(x) => if *x == 0 as f64 {False} else {True},
-            StringValue(s) => if s.len() == 0 {False} else {True},
-            True => True, False => False, Nil => False,
-            Callable(_) => panic!("Cannot use callable as a true value"),
-            FluxarClass { .. } => panic!("Cannot use callable as a true value"),
-            _ => panic!("Not valid as a boolean value"),
+            Int(x) => Ok(if *x == 0 {False} else {True}),
+            Float(x) => Ok(if *x == 0.0 {False} else {True}),
+            Decimal(x) => Ok(if x.is_zero() {False} else {True}),
+            StringValue(s) => Ok(if s.len() == 0 {False} else {True}),
+            True => Ok(True), False => Ok(False), Nil => Ok(False),
+            List(items) => Ok(if items.borrow().is_empty() {False} else {True}),
+            Map(entries) => Ok(if entries.borrow().is_empty() {False} else {True}),
+            Table(table) => Ok(if table.row_count() == 0 {False} else {True}),
+            Callable(_) => Err("Cannot use callable as a true value".into()),
+            FluxarClass { .. } => Err("Cannot use class as a true value".into()),
+            _ => Err(format!("{} is not valid as a boolean value", self.to_type()).into()),
         }
     }
 }
@@ -153,21 +255,37 @@ use crate::statements::Statement;
 pub enum Expr {
     Assign { id: usize, name: Token, value: Box<Expr> },
     AnonFunction { 
-        id: usize, paren: Token, generics: Vec<Token>, 
-        arguments: Vec<Token>, return_type: Option<Token>,
+        id: usize, paren: Token, generics: Vec<TypeParam>, 
+        arguments: Vec<Token>, param_types: Vec<Option<TypeAnnotation>>,
+        return_type: Option<TypeAnnotation>,
         body: Vec<Box<Statement>> 
     },
     Binary { id: usize, left: Box<Expr>, operator: Token, right: Box<Expr> },
-    Call { id: usize, callee: Box<Expr>, paren: Token, arguments: Vec<Expr>, generics: Vec<Token> },
+    Call { id: usize, callee: Box<Expr>, paren: Token, arguments: Vec<Expr>, generics: Vec<TypeAnnotation> },
+    /// `condition ? then_branch : else_branch`, or the same written `if (...) a else b`; `keyword` is the `?` or `if`.
+    Conditional { id: usize, keyword: Token, condition: Box<Expr>, then_branch: Box<Expr>, else_branch: Box<Expr> },
     Get { id: usize, object: Box<Expr>, name: Token },
     Grouping { id: usize, expression: Box<Expr> },
-    Literal { id: usize, value: LiteralValue },
+    /// The value on the left of `|>`, passed as the first argument of the call on its right.
+    Piped { id: usize, pipe: Token, value: Box<Expr> },
+    Index { id: usize, object: Box<Expr>, bracket: Token, index: Box<Expr> },
+    /// `operator` is the arithmetic of a compound assignment like `+=`, applied to the old value.
+    IndexSet { id: usize, object: Box<Expr>, bracket: Token, index: Box<Expr>, operator: Option<Token>, value: Box<Expr> },
+    List { id: usize, bracket: Token, elements: Vec<Expr> },
+    Map { id: usize, brace: Token, entries: Vec<(Expr, Expr)> },
+    Literal { id: usize, span: Span, value: LiteralValue },
+    /// A string with `${...}` in it: its text and expressions in order, joined when evaluated.
+    Interpolated { id: usize, span: Span, parts: Vec<Expr> },
     Logical { id: usize, left: Box<Expr>, operator: Token, right: Box<Expr> },
-    Set { id: usize, object: Box<Expr>, name: Token, value: Box<Expr> },
+    Set { id: usize, object: Box<Expr>, name: Token, operator: Option<Token>, value: Box<Expr> },
+    Slice { id: usize, object: Box<Expr>, bracket: Token, start: Option<Box<Expr>>, end: Option<Box<Expr>> },
     This { id: usize, keyword: Token },
     Super { id: usize, keyword: Token, method: Token },
     Unary { id: usize, operator: Token, right: Box<Expr> },
-    Variable { id: usize, var_type: Option<Token>, name: Token },
+    /// `++` or `--` on the variable, property or index `target`, with `operator` the `+` or `-`
+    /// it applies. The prefix form evaluates to the new value and the postfix form to the old one.
+    Update { id: usize, operator: Token, prefix: bool, target: Box<Expr> },
+    Variable { id: usize, name: Token },
 }
 impl std::fmt::Debug for Expr {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -190,19 +308,63 @@ impl Eq for Expr {}
 impl Expr {
     pub fn get_id(&self) -> usize {
         match self {
-            Expr::AnonFunction { id, paren: _, generics: _, arguments: _, return_type: _, body: _ } => *id,
+            Expr::AnonFunction { id, paren: _, generics: _, arguments: _, param_types: _, return_type: _, body: _ } => *id,
             Expr::Assign { id, name: _, value: _ } => *id,
             Expr::Binary { id, left: _, operator: _, right: _ } => *id,
             Expr::Call { id, callee: _, paren: _, arguments: _, generics: _ } => *id,
+            Expr::Conditional { id, keyword: _, condition: _, then_branch: _, else_branch: _ } => *id,
             Expr::Get { id, object: _, name: _ } => *id,
             Expr::Grouping { id, expression: _ } => *id,
-            Expr::Literal { id, value: _ } => *id,
+            Expr::Piped { id, pipe: _, value: _ } => *id,
+            Expr::Index { id, object: _, bracket: _, index: _ } => *id,
+            Expr::IndexSet { id, object: _, bracket: _, index: _, operator: _, value: _ } => *id,
+            Expr::List { id, bracket: _, elements: _ } => *id,
+            Expr::Map { id, brace: _, entries: _ } => *id,
+            Expr::Literal { id, span: _, value: _ } => *id,
+            Expr::Interpolated { id, span: _, parts: _ } => *id,
             Expr::Logical { id, left: _, operator: _, right: _ } => *id,
-            Expr::Set { id, object: _, name: _, value: _ } => *id,
+            Expr::Set { id, object: _, name: _, operator: _, value: _ } => *id,
+            Expr::Slice { id, object: _, bracket: _, start: _, end: _ } => *id,
             Expr::This { id, keyword: _ } => *id,
             Expr::Super { id, keyword: _, method: _ } => *id,
             Expr::Unary { id, operator: _, right: _ } => *id,
-            Expr::Variable { id, var_type: _, name: _ } => *id,
+            Expr::Update { id, operator: _, prefix: _, target: _ } => *id,
+            Expr::Variable { id, name: _ } => *id,
+        }
+    }
+    /// The source the expression was parsed from, for pointing diagnostics at it.
+    pub fn span(&self) -> Span {
+        match self {
+            Expr::AnonFunction { paren, .. } => paren.span,
+            Expr::Assign { id: _, name, value } => name.span.to(value.span()),
+            Expr::Binary { id: _, left, operator: _, right } | Expr::Logical { id: _, left, operator: _, right } => {
+                left.span().to(right.span())
+            },
+            Expr::Call { id: _, callee, paren, arguments: _, generics: _ } => callee.span().to(paren.span),
+            Expr::Conditional { id: _, keyword, condition, then_branch: _, else_branch } => match keyword.token_type {
+                TokenType::If => keyword.span.to(else_branch.span()),
+                _ => condition.span().to(else_branch.span()),
+            },
+            Expr::Get { id: _, object, name } => object.span().to(name.span),
+            Expr::Grouping { id: _, expression } => expression.span(),
+            Expr::Piped { id: _, pipe: _, value } => value.span(),
+            Expr::Index { id: _, object, bracket: _, index } => object.span().to(index.span()),
+            Expr::IndexSet { id: _, object, bracket: _, index: _, operator: _, value } => object.span().to(value.span()),
+            Expr::List { id: _, bracket, elements } => elements.iter().fold(bracket.span, |span, e| span.to(e.span())),
+            Expr::Map { id: _, brace, entries } => entries.iter().fold(brace.span, |span, (_, value)| span.to(value.span())),
+            Expr::Literal { id: _, span, value: _ } => *span,
+            Expr::Interpolated { id: _, span, parts: _ } => *span,
+            Expr::Set { id: _, object, name: _, operator: _, value } => object.span().to(value.span()),
+            Expr::Slice { id: _, object, bracket, start: _, end } => match end {
+                Some(end) => object.span().to(end.span()),
+                None => object.span().to(bracket.span),
+            },
+            Expr::This { id: _, keyword } => keyword.span,
+            Expr::Super { id: _, keyword, method } => keyword.span.to(method.span),
+            Expr::Unary { id: _, operator, right } => operator.span.to(right.span()),
+            Expr::Update { id: _, operator, prefix: true, target } => operator.span.to(target.span()),
+            Expr::Update { id: _, operator, prefix: false, target } => target.span().to(operator.span),
+            Expr::Variable { id: _, name } => name.span,
         }
     }
 }
@@ -211,23 +373,48 @@ impl Expr {
     pub fn to_string(&self) -> String {
         match self {
             Expr::Assign { id: _, name, value } => format!("({name:?} = {})", value.to_string()),
-            Expr::AnonFunction { id: _, paren: _, generics: _, arguments, return_type: _, body: _ } => format!("anon/{}", arguments.len()),
+            Expr::AnonFunction { id: _, paren: _, generics: _, arguments, param_types: _, return_type: _, body: _ } => format!("anon/{}", arguments.len()),
             Expr::Binary { id: _, left, operator, right } => format!(
                 "({} {} {})", operator.lexeme,
                 left.to_string(), right.to_string()
             ),
             Expr::Call { id: _, callee, paren: _, arguments, generics: _ } => format!("({} {:?})", (*callee).to_string(), arguments),
+            Expr::Conditional { id: _, keyword: _, condition, then_branch, else_branch } => format!(
+                "(? {} {} {})", condition.to_string(), then_branch.to_string(), else_branch.to_string()
+            ),
             Expr::Get { id: _, object, name } => format!("(get {} {})", object.to_string(), name.lexeme),
             Expr::Grouping { id: _, expression } => format!("(group {})", (*expression).to_string()),
-            Expr::Literal { id: _, value } => format!("{}", value.to_string()),
+            Expr::Piped { id: _, pipe: _, value } => format!("(piped {})", value.to_string()),
+            Expr::Index { id: _, object, bracket: _, index } => format!("(index {} {})", object.to_string(), index.to_string()),
+            Expr::IndexSet { id: _, object, bracket: _, index, operator, value } => format!(
+                "(index-set{} {} {} {})", operator.as_ref().map_or("", |o| o.lexeme.as_str()), object.to_string(),
+                index.to_string(), value.to_string()
+            ),
+            Expr::List { id: _, bracket: _, elements } => format!(
+                "(list {})", elements.iter().map(|e| e.to_string()).collect::<Vec<String>>().join(" ")
+            ),
+            Expr::Map { id: _, brace: _, entries } => format!(
+                "(map {})", entries.iter()
+                    .map(|(key, value)| format!("({} {})", key.to_string(), value.to_string()))
+                    .collect::<Vec<String>>().join(" ")
+            ),
+            Expr::Literal { id: _, span: _, value } => format!("{}", value.to_string()),
+            Expr::Interpolated { id: _, span: _, parts } => format!(
+                "(interpolate {})", parts.iter().map(|e| e.to_string()).collect::<Vec<String>>().join(" ")
+            ),
             Expr::Logical { id: _, left, operator, right } => format!(
                 "({} {} {})", operator.to_string(), 
                 left.to_string(), right.to_string()
             ),
-            Expr::Set { id: _, object, name, value } => format!(
-                "(set {} {} {})", object.to_string(),
+            Expr::Set { id: _, object, name, operator, value } => format!(
+                "(set{} {} {} {})", operator.as_ref().map_or("", |o| o.lexeme.as_str()), object.to_string(),
                 name.to_string(), value.to_string()
             ),
+            Expr::Slice { id: _, object, bracket: _, start, end } => format!(
+                "(slice {} {} {})", object.to_string(),
+                start.as_ref().map_or("nil".to_string(), |e| e.to_string()),
+                end.as_ref().map_or("nil".to_string(), |e| e.to_string())
+            ),
             Expr::This { id: _, keyword: _ } => format!("(this)"),
             Expr::Super { id: _, keyword: _, method } => format!("(super {})", method.lexeme),
             Expr::Unary { id: _, operator, right } => {
@@ -235,24 +422,27 @@ impl Expr {
                 let right_str = (*right).to_string();
                 format!("({} {})", operator_str, right_str)
             },
-            Expr::Variable { id: _, var_type: _, name } => format!("(var {})", name.lexeme),
+            Expr::Update { id: _, operator, prefix, target } => format!(
+                "({}{} {})", if *prefix { "pre" } else { "post" }, operator.lexeme, target.to_string()
+            ),
+            Expr::Variable { id: _, name } => format!("(var {})", name.lexeme),
         }
     }
     pub fn evaluate(
         &self, environment: Environment
-    ) -> Result<LiteralValue, String> {
+    ) -> Result<LiteralValue, RuntimeError> {
         match self {
             Expr::Assign { id: _, name, value } => {
                 let new_value = (*value).evaluate(environment.clone())?;
                 let assign_success = environment.assign(&name.lexeme, new_value.clone(), self.get_id());
                 if assign_success { Ok(new_value) }
-                else { Err(format!("Variable '{}' has not been declared.", name.lexeme)) }
+                else { Err(RuntimeError::new(name, &format!("Variable '{}' has not been declared.", name.lexeme))) }
             },
-            Expr::AnonFunction { id: _, paren: _, generics, arguments, return_type, body } => {
+            Expr::AnonFunction { id: _, paren: _, generics, arguments, param_types: _, return_type, body } => {
                 let arity = arguments.len(); 
                 let arguments: Vec<Token> = arguments.iter().map(|t| (*t).clone()).collect();
                 let body: Vec<Box<Statement>> = body.iter().map(|b| (*b).clone()).collect();
-                let generics: Vec<Token> = generics.iter().map(|g| (*g).clone()).collect();
+                let generics: Vec<Token> = generics.iter().map(|g| g.name.clone()).collect();
 
                 let callable_impl = CallableImpl::FluxarFunction(
                     FluxarFunctionImpl { 
@@ -265,21 +455,29 @@ impl Expr {
             Expr::Binary { id: _, left, operator, right } => {
                 let left_val = left.evaluate(environment.clone())?;
                 let right_val = right.evaluate(environment.clone())?;
-                self.evaluate_binary(operator, left_val, right_val)
+                binary_op(operator, left_val, right_val)
             },
-            Expr::Call { id: _, callee, paren: _, arguments, generics: _ } => {
+            Expr::Call { id: _, callee, paren, arguments, generics: _ } => {
                 let callable = (*callee).evaluate(environment.clone())?;
                 let callable_clone = callable.clone();
                 match callable {
                     Callable(CallableImpl::FluxarFunction(fluxarfun)) => {
-                        run_fluxar_function(fluxarfun, arguments, environment)
+                        run_fluxar_function(fluxarfun, arguments, environment).map_err(|e| e.or_at(paren))
                     }
                     Callable(CallableImpl::NativeFunction(nativefun)) => {
+                        nativefun.check_arity(arguments.len()).map_err(|e| e.or_at(paren))?;
+                        let mut evaluated_arguments = vec![];
+                        for argument in arguments {
+                            evaluated_arguments.push(argument.evaluate(environment.clone())?);
+                        }
+                        (nativefun.fun)(&evaluated_arguments).map_err(|e| e.or_at(paren))
+                    }
+                    callable @ Callable(_) => {
                         let mut evaluated_arguments = vec![];
                         for argument in arguments {
                             evaluated_arguments.push(argument.evaluate(environment.clone())?);
                         }
-                        Ok((nativefun.fun)(&evaluated_arguments))
+                        call_value(&callable, evaluated_arguments).map_err(|e| e.or_at(paren))
                     }
                     FluxarClass { name: _, generics: _, methods, superclass: _ } => {
                         let instance = FluxarInstance { 
@@ -287,199 +485,448 @@ impl Expr {
                             fields: Rc::new(RefCell::new(vec![])) 
                         };
                         // Call constructor if present
-                        if let Some(init_method) = methods.get("init") {
+                        if let Some(FluxarFunction(init_method)) = methods.get("init") {
                             if init_method.arity != arguments.len() {
-                                return Err("Invalid number of arguments in constructor".to_string());
+                                return Err(RuntimeError::new(paren, "Invalid number of arguments in constructor"));
                             }
-                            let mut init_method = init_method.clone();
-                            init_method.parent_env = init_method.parent_env.clone();
-                            init_method.parent_env.define("this".to_string(), instance.clone());
-
-                            if let Err(msg) = run_fluxar_function(
-                                init_method, arguments, environment) 
-                            { return Err(msg); }
+                            let init_method = bind_fluxar_function(init_method, &instance);
+                            run_fluxar_function(init_method, arguments, environment)
+                                .map_err(|e| e.or_at(paren))?;
                         }
                         Ok(instance)
                     }
-                    other => Err(format!("{} is not callable", other.to_type())),
+                    other => Err(RuntimeError::new(paren, &format!("{} is not callable", other.to_type()))),
                 }
             },
             Expr::Get { id: _, object, name } => {
                 let obj_value = object.evaluate(environment.clone())?;
-                // Now obj_value should be a FluxarInstance
-                if let FluxarInstance { class, fields } = obj_value.clone() {
-                    for (field_name, value) in (*fields.borrow()).iter() {
-                        // Are we getting a field on the object?
-                        if field_name == &name.lexeme { return Ok(value.clone()); }
-                    }
-                    // Are we getting a method in the object?
-                    // TODO: Make a function that finds a method in a class by looking first at the
-                    // class, then at the superclasses in a recursive manner
-                    if let FluxarClass { name: _, generics: _, methods: _, superclass: _ } = class.as_ref() {
-                        if let Some(method) = find_method(&name.lexeme, *class.clone()) {
-                            let mut callable_impl = method.clone();
-                            let mut new_env = callable_impl.parent_env.enclose();
-                            new_env.define("this".to_string(), obj_value.clone());
-                            callable_impl.parent_env = new_env;
-                            return Ok(Callable(FluxarFunction(callable_impl)));
-                        }
-                    } else { panic!("The class field on an instance was not a FluxarClass"); }
-                    Err(format!("No field named {} on this instance", name.lexeme))
-                } else { Err(format!("Cannot access property on type {}", obj_value.to_type())) }
+                get_property(name, obj_value)
             },
-            Expr::Set { id: _, object, name, value } => {
+            Expr::Set { id: _, object, name, operator, value } => {
                 let obj_value = object.evaluate(environment.clone())?;
-                if let FluxarInstance { class: _, fields } = obj_value {
-                    let value = value.evaluate(environment.clone())?;
-                    let mut idx = 0; let mut found = false;
-                    for i in 0..(*fields.borrow()).len() {
-                        let field_name = &(*fields.borrow())[i].0;
-                        if field_name == &name.lexeme { idx = i; found = true; break; }
+                let current = match operator {
+                    Some(_) => Some(get_property(name, obj_value.clone())?),
+                    None => None,
+                };
+                let mut value = value.evaluate(environment.clone())?;
+                if let (Some(operator), Some(current)) = (operator, current) { value = binary_op(operator, current, value)?; }
+                set_property(name, obj_value, value)
+            },
+            Expr::Grouping { id: _, expression } => expression.evaluate(environment),
+            Expr::Piped { id: _, pipe, value } => command::pipe_source(value.evaluate(environment)?).map_err(|e| e.or_at(pipe)),
+            Expr::Index { id: _, object, bracket, index } => {
+                let obj_value = object.evaluate(environment.clone())?;
+                let index_value = index.evaluate(environment)?;
+                index_op(bracket, obj_value, index_value)
+            },
+            Expr::IndexSet { id: _, object, bracket, index, operator, value } => {
+                let obj_value = object.evaluate(environment.clone())?;
+                let index_value = index.evaluate(environment.clone())?;
+                let current = match operator {
+                    Some(_) => Some(index_op(bracket, obj_value.clone(), index_value.clone())?),
+                    None => None,
+                };
+                let mut value = value.evaluate(environment)?;
+                if let (Some(operator), Some(current)) = (operator, current) { value = binary_op(operator, current, value)?; }
+                index_set_op(bracket, obj_value, index_value, value)
+            },
+            Expr::List { id: _, bracket: _, elements } => {
+                let mut items = vec![];
+                for element in elements {
+                    items.push(element.evaluate(environment.clone())?);
+                }
+                Ok(List(Rc::new(RefCell::new(items))))
+            },
+            Expr::Map { id: _, brace, entries } => {
+                let mut map_entries = vec![];
+                for (key, value) in entries {
+                    let key = key.evaluate(environment.clone())?;
+                    if !key.is_valid_key() {
+                        return Err(RuntimeError::new(brace, &format!(
+                            "{} cannot be used as a map key", key.to_type()
+                        )));
                     }
-                    if found { (*fields.borrow_mut())[idx].1 = value.clone(); }
-                    else { (*fields.borrow_mut()).push((name.lexeme.clone(), value)); }
-                    Ok(Nil)
-                } else {
-                    Err(format!(
-                        "Cannot set property on type {}",
-                        obj_value.to_type()
-                    ))
+                    let value = value.evaluate(environment.clone())?;
+                    map_insert(&mut map_entries, key, value);
                 }
+                Ok(LiteralValue::new_map(map_entries))
+            },
+            Expr::Literal { id: _, span: _, value } => Ok((*value).clone()),
+            Expr::Interpolated { id: _, span: _, parts } => {
+                let mut text = String::new();
+                for part in parts {
+                    text.push_str(&part.evaluate(environment.clone())?.to_text());
+                }
+                Ok(StringValue(text))
+            },
+            Expr::Conditional { id: _, keyword, condition, then_branch, else_branch } => {
+                // Only the branch that was chosen runs
+                let truth = condition.evaluate(environment.clone())?.is_true().map_err(|e| e.or_at(keyword))?;
+                if truth == True { then_branch.evaluate(environment) } else { else_branch.evaluate(environment) }
             },
-            Expr::Grouping { id: _, expression } => expression.evaluate(environment),
-            Expr::Literal { id: _, value } => Ok((*value).clone()),
             Expr::Logical { id: _, left, operator, right } => {
                 match operator.token_type {
                     TokenType::Or => {
                         let lhs_value = left.evaluate(environment.clone())?;
-                        let lhs_true = lhs_value.is_true();
+                        let lhs_true = lhs_value.is_true().map_err(|e| e.or_at(operator))?;
                         if lhs_true == True { Ok(lhs_value) } else { right.evaluate(environment.clone()) }
                     },
                     TokenType::And => {
-                        let lhs_true = left.evaluate(environment.clone())?.is_true();
+                        let lhs_true = left.evaluate(environment.clone())?.is_true().map_err(|e| e.or_at(operator))?;
                         if lhs_true == False { Ok(lhs_true) } else { right.evaluate(environment.clone()) }
                     },
-                    ttype => Err(format!("Invalid token in logical expression: {}", ttype)),
+                    ttype => Err(RuntimeError::new(operator, &format!("Invalid token in logical expression: {}", ttype))),
                 }
             },
-            Expr::This { id: _, keyword: _ } => {
-                let this = environment
-                    .get("this", self.get_id())
-                    .expect("Couldn't lookup 'this'");
-                Ok(this)
+            Expr::Slice { id: _, object, bracket, start, end } => {
+                let obj_value = object.evaluate(environment.clone())?;
+                let start = match start {
+                    Some(start) => Some(start.evaluate(environment.clone())?),
+                    None => None,
+                };
+                let end = match end {
+                    Some(end) => Some(end.evaluate(environment.clone())?),
+                    None => None,
+                };
+                slice_op(bracket, obj_value, start, end)
             },
-            Expr::Super { id: _, keyword: _, method } => {
-                let superclass = environment.get("super", self.get_id()).expect(&format!(
-                    "Couldn't lookup 'super':\n---------------\n{}---------------\n",
-                    environment.dump(0)
-                ));
-                let instance = environment.get_this_instance(self.get_id()).unwrap();
+            Expr::This { id: _, keyword } => environment
+                .get("this", self.get_id())
+                .ok_or_else(|| RuntimeError::new(keyword, "Couldn't lookup 'this'")),
+            Expr::Super { id: _, keyword, method } => {
+                let superclass = environment.get("super", self.get_id())
+                    .ok_or_else(|| RuntimeError::new(keyword, "Couldn't lookup 'super'"))?;
+                let instance = environment.get_this_instance(self.get_id())
+                    .ok_or_else(|| RuntimeError::new(keyword, "Couldn't lookup 'this' for 'super'"))?;
                 if let FluxarClass { name: _, generics: _, methods, superclass: _ } = superclass.clone() {
                     if let Some(method_value) = methods.get(&method.lexeme) {
-                        let mut method = method_value.clone();
-                        method.parent_env = method.parent_env.enclose();
-                        method.parent_env.define("this".to_string(), instance.clone());
-                        Ok(Callable(FluxarFunction(method)))
+                        Ok(bind_method(method_value, &instance))
                     } else {
-                        Err(format!(
+                        Err(RuntimeError::new(method, &format!(
                             "No method named {} on superclass {}",
                             method.lexeme, superclass.to_type()
-                        ))
+                        )))
                     }
-                } else { panic!("The superclass field on an instance was not a FluxarClass"); }
+                } else { Err(RuntimeError::new(keyword, &format!("Superclass must be a class, not {}", superclass.to_type()))) }
             },
             Expr::Unary { id: _, operator, right } => {
                 let right_val = right.evaluate(environment)?;
-                self.evaluate_unary(operator, right_val)
+                unary_op(operator, right_val)
+            },
+            Expr::Update { id: _, operator, prefix, target } => {
+                let (old, new) = match target.as_ref() {
+                    Expr::Variable { id: _, name } => {
+                        let old = target.evaluate(environment.clone())?;
+                        let new = binary_op(operator, old.clone(), Int(1))?;
+                        if !environment.assign(&name.lexeme, new.clone(), target.get_id()) {
+                            return Err(RuntimeError::new(name, &format!("Variable '{}' has not been declared.", name.lexeme)));
+                        }
+                        (old, new)
+                    },
+                    Expr::Get { id: _, object, name } => {
+                        let obj_value = object.evaluate(environment.clone())?;
+                        let old = get_property(name, obj_value.clone())?;
+                        let new = binary_op(operator, old.clone(), Int(1))?;
+                        set_property(name, obj_value, new.clone())?;
+                        (old, new)
+                    },
+                    Expr::Index { id: _, object, bracket, index } => {
+                        let obj_value = object.evaluate(environment.clone())?;
+                        let index_value = index.evaluate(environment)?;
+                        let old = index_op(bracket, obj_value.clone(), index_value.clone())?;
+                        let new = binary_op(operator, old.clone(), Int(1))?;
+                        index_set_op(bracket, obj_value, index_value, new.clone())?;
+                        (old, new)
+                    },
+                    _ => return Err(RuntimeError::new(operator, "Invalid assignment target")),
+                };
+                Ok(if *prefix { new } else { old })
             },
-            Expr::Variable { id: _, var_type: _, name } => match environment.get(&name.lexeme, self.get_id()) {
+            Expr::Variable { id: _, name } => match environment.get(&name.lexeme, self.get_id()) {
                 Some(value) => Ok(value.clone()),
-                None => Err(format!(
+                None => Err(RuntimeError::new(name, &format!(
                     "Variable '{}' has not been declared at distance {:?}", 
                     name.lexeme, environment.get_distance(self.get_id())
-                ))
+                )))
             },
         }
     }
-    fn evaluate_unary(&self, operator: &Token, right: LiteralValue) -> Result<LiteralValue, String> {
-        match (&right, operator.token_type) {
-            (Number(x), TokenType::Minus) => Ok(Number(-x)),
-            (_, TokenType::Minus) => {
-                Err(format!("Minus not implemented for {}", right.to_type()))
-            }
-            (any, TokenType::Bang) => Ok(any.is_false()),
-            (_, ttype) => Err(format!("{} is not a valid unary operator", ttype)),
+    #[allow(dead_code)]
+    pub fn print(&self) {
+        println!("{}", self.to_string());
+    }
+}
+/// Inserts into an insertion-ordered map, overwriting the value in place if the key already exists.
+pub fn map_insert(entries: &mut Vec<(LiteralValue, LiteralValue)>, key: LiteralValue, value: LiteralValue) {
+    match entries.iter_mut().find(|(existing, _)| *existing == key) {
+        Some(entry) => entry.1 = value,
+        None => entries.push((key, value)),
+    }
+}
+pub fn unary_op(operator: &Token, right: LiteralValue) -> Result<LiteralValue, RuntimeError> {
+    match (&right, operator.token_type) {
+        (Int(_) | Float(_) | Decimal(_), TokenType::Minus) => number::negate(operator, &right),
+        (Int(x), TokenType::Tilde) => Ok(Int(!x)),
+        (Float(_), TokenType::Tilde) => Err(number::not_integer(operator, &right)),
+        (_, TokenType::Tilde) => {
+            Err(RuntimeError::new(operator, &format!("Operator ~ is not defined for {}", right.to_type())))
+        },
+        (_, TokenType::Minus) => {
+            Err(RuntimeError::new(operator, &format!("Minus not implemented for {}", right.to_type())))
         }
+        (any, TokenType::Bang) => any.is_false().map_err(|e| e.or_at(operator)),
+        (_, ttype) => Err(RuntimeError::new(operator, &format!("{} is not a valid unary operator", ttype))),
     }
-    fn evaluate_binary(&self, operator: &Token, left: LiteralValue, right: LiteralValue) -> Result<LiteralValue, String> {
-        match (left, operator.token_type, right) {
-            // Arithmetic for numbers
-            (LiteralValue::Number(x), TokenType::Plus, LiteralValue::Number(y)) => Ok(LiteralValue::Number(x + y)),
-            (LiteralValue::Number(x), TokenType::Minus, LiteralValue::Number(y)) => Ok(LiteralValue::Number(x - y)),
-            (LiteralValue::Number(x), TokenType::Star, LiteralValue::Number(y)) => Ok(LiteralValue::Number(x * y)),
-            (LiteralValue::Number(x), TokenType::Slash, LiteralValue::Number(y)) => Ok(LiteralValue::Number(x / y)),
-
-            // Comparisons for numbers
-            (LiteralValue::Number(x), TokenType::Greater, LiteralValue::Number(y)) => Ok(LiteralValue::from_bool(x > y)),
-            (LiteralValue::Number(x), TokenType::GreaterEqual, LiteralValue::Number(y)) => Ok(LiteralValue::from_bool(x >= y)),
-            (LiteralValue::Number(x), TokenType::Less, LiteralValue::Number(y)) => Ok(LiteralValue::from_bool(x < y)),
-            (LiteralValue::Number(x), TokenType::LessEqual, LiteralValue::Number(y)) => Ok(LiteralValue::from_bool(x <= y)),
-
-            (StringValue(s1), TokenType::Plus, StringValue(s2)) => Ok(StringValue(format!("{}{}", s1, s2))),
+}
+pub fn binary_op(operator: &Token, left: LiteralValue, right: LiteralValue) -> Result<LiteralValue, RuntimeError> {
+    match (left, operator.token_type, right) {
+        (l @ (Int(_) | Float(_) | Decimal(_)), op, r @ (Int(_) | Float(_) | Decimal(_))) if !matches!(op, TokenType::EqualEqual | TokenType::BangEqual) => {
+            number::binary(operator, l, r)
+        },
+        (StringValue(s1), TokenType::Plus, StringValue(s2)) => Ok(StringValue(format!("{}{}", s1, s2))),
 
-            (l, TokenType::EqualEqual, r) => Ok(LiteralValue::from_bool(l == r)),
-            (l, TokenType::BangEqual, r) => Ok(LiteralValue::from_bool(l != r)),
+        (l, TokenType::EqualEqual, r) => Ok(LiteralValue::from_bool(l == r)),
+        (l, TokenType::BangEqual, r) => Ok(LiteralValue::from_bool(l != r)),
 
-            (StringValue(s1), TokenType::Greater, StringValue(s2)) => Ok(LiteralValue::from_bool(s1 > s2)),
-            (StringValue(s1), TokenType::GreaterEqual, StringValue(s2)) => Ok(LiteralValue::from_bool(s1 >= s2)),
-            (StringValue(s1), TokenType::Less, StringValue(s2)) => Ok(LiteralValue::from_bool(s1 < s2)),
-            (StringValue(s1), TokenType::LessEqual, StringValue(s2)) => Ok(LiteralValue::from_bool(s1 <= s2)),
+        (StringValue(s1), TokenType::Greater, StringValue(s2)) => Ok(LiteralValue::from_bool(s1 > s2)),
+        (StringValue(s1), TokenType::GreaterEqual, StringValue(s2)) => Ok(LiteralValue::from_bool(s1 >= s2)),
+        (StringValue(s1), TokenType::Less, StringValue(s2)) => Ok(LiteralValue::from_bool(s1 < s2)),
+        (StringValue(s1), TokenType::LessEqual, StringValue(s2)) => Ok(LiteralValue::from_bool(s1 <= s2)),
 
-            // Handle invalid cases
-            (LiteralValue::StringValue(_), _, LiteralValue::Number(_)) |
-            (LiteralValue::Number(_), _, LiteralValue::StringValue(_)) => {
-                Err(format!("{} is not defined for mixed types!", operator.lexeme))
+        // Handle invalid cases
+        (StringValue(_), _, Int(_) | Float(_) | Decimal(_)) |
+        (Int(_) | Float(_) | Decimal(_), _, StringValue(_)) => {
+            Err(RuntimeError::new(operator, &format!("{} is not defined for mixed types!", operator.lexeme)))
+        }
+        (l, ttype, r) => Err(RuntimeError::new(operator, &format!(
+            "Operator {} is not implemented for {:?} and {:?}", ttype, l, r
+        ))),
+    }
+}
+/// Reads `object[index]`, shared by the tree-walker and the VM so both report the same errors.
+pub fn index_op(bracket: &Token, object: LiteralValue, index: LiteralValue) -> Result<LiteralValue, RuntimeError> {
+    match object {
+        List(items) => {
+            let items = items.borrow();
+            let i = resolve_index(bracket, &index, items.len())?;
+            Ok(items[i].clone())
+        },
+        Map(entries) => {
+            for (key, value) in entries.borrow().iter() {
+                if *key == index { return Ok(value.clone()); }
+            }
+            Err(RuntimeError::new(bracket, &format!(
+                "Key {} not found in map", index.to_string()
+            )))
+        },
+        Table(table) => match index {
+            StringValue(column) => Ok(List(Rc::new(RefCell::new(
+                table.column(&column)
+                    .map_err(|msg| RuntimeError::new(bracket, &msg))?
+                    .values.clone()
+            )))),
+            _ => Ok(table.row(resolve_index(bracket, &index, table.row_count())?)),
+        },
+        StringValue(s) => {
+            let chars: Vec<char> = s.chars().collect();
+            let i = resolve_index(bracket, &index, chars.len())?;
+            Ok(StringValue(chars[i].to_string()))
+        },
+        other => Err(RuntimeError::new(bracket, &format!(
+            "Cannot index into type {}", other.to_type()
+        ))),
+    }
+}
+/// Performs `object[index] = value` and returns the assigned value.
+pub fn index_set_op(
+    bracket: &Token, object: LiteralValue, index: LiteralValue, value: LiteralValue
+) -> Result<LiteralValue, RuntimeError> {
+    match object {
+        List(items) => {
+            let len = items.borrow().len();
+            let i = resolve_index(bracket, &index, len)?;
+            items.borrow_mut()[i] = value.clone();
+            Ok(value)
+        },
+        Map(entries) => {
+            if !index.is_valid_key() {
+                return Err(RuntimeError::new(bracket, &format!(
+                    "{} cannot be used as a map key", index.to_type()
+                )));
             }
-            (l, ttype, r) => Err(format!("Operator {} is not implemented for {:?} and {:?}", ttype, l, r)),
+            map_insert(&mut entries.borrow_mut(), index, value.clone());
+            Ok(value)
+        },
+        other => Err(RuntimeError::new(bracket, &format!(
+            "Cannot assign by index on type {}", other.to_type()
+        ))),
+    }
+}
+pub fn slice_op(
+    bracket: &Token, object: LiteralValue, start: Option<LiteralValue>, end: Option<LiteralValue>
+) -> Result<LiteralValue, RuntimeError> {
+    match object {
+        List(items) => {
+            let items = items.borrow();
+            let (from, to) = resolve_slice(bracket, &start, &end, items.len())?;
+            Ok(List(Rc::new(RefCell::new(items[from..to].to_vec()))))
+        },
+        Table(table) => {
+            let (from, to) = resolve_slice(bracket, &start, &end, table.row_count())?;
+            Ok(Table(Rc::new(table.take_rows(&(from..to).collect::<Vec<usize>>()))))
+        },
+        StringValue(s) => {
+            let chars: Vec<char> = s.chars().collect();
+            let (from, to) = resolve_slice(bracket, &start, &end, chars.len())?;
+            Ok(StringValue(chars[from..to].iter().collect()))
+        },
+        other => Err(RuntimeError::new(bracket, &format!(
+            "Cannot slice type {}", other.to_type()
+        ))),
+    }
+}
+/// Looks a name up on an instance: fields first, then methods through the superclass chain.
+/// On a module it is one of the module's globals.
+pub fn get_property(name: &Token, object: LiteralValue) -> Result<LiteralValue, RuntimeError> {
+    if let Module { name: module, globals } = &object {
+        return globals.borrow().get(&name.lexeme).cloned().ok_or_else(|| RuntimeError::new(name, &format!(
+            "Module '{}' has no member named {}", module, name.lexeme
+        )));
+    }
+    if let FluxarInstance { class, fields } = &object {
+        for (field_name, value) in (*fields.borrow()).iter() {
+            if field_name == &name.lexeme { return Ok(value.clone()); }
+        }
+        let method = find_method(&name.lexeme, *class.clone()).map_err(|msg| RuntimeError::new(name, &msg))?;
+        if let Some(method) = method {
+            return Ok(bind_method(&method, &object));
+        }
+        Err(RuntimeError::new(name, &format!("No field named {} on this instance", name.lexeme)))
+    } else { Err(RuntimeError::new(name, &format!("Cannot access property on type {}", object.to_type()))) }
+}
+/// Sets a field on an instance, adding it if it does not exist yet. Evaluates to nil.
+pub fn set_property(name: &Token, object: LiteralValue, value: LiteralValue) -> Result<LiteralValue, RuntimeError> {
+    if let FluxarInstance { class: _, fields } = object {
+        let mut fields = fields.borrow_mut();
+        match fields.iter_mut().find(|(field_name, _)| field_name == &name.lexeme) {
+            Some(field) => field.1 = value,
+            None => fields.push((name.lexeme.clone(), value)),
         }
+        Ok(Nil)
+    } else {
+        Err(RuntimeError::new(name, &format!(
+            "Cannot set property on type {}",
+            object.to_type()
+        )))
     }
-    #[allow(dead_code)]
-    pub fn print(&self) {
-        println!("{}", self.to_string());
+}
+/// Produces a method value with `this` bound to `instance`.
+pub fn bind_method(method: &CallableImpl, instance: &LiteralValue) -> LiteralValue {
+    match method {
+        FluxarFunction(fun) => Callable(FluxarFunction(bind_fluxar_function(fun, instance))),
+        CallableImpl::Closure(closure) => Callable(CallableImpl::Closure(closure.bind(instance.clone()))),
+        NativeFunction(_) | CallableImpl::Command(_) => Callable(method.clone()),
     }
 }
-pub fn find_method(name: &str, class: LiteralValue) -> Option<FluxarFunctionImpl> {
+/// Wraps the function's environment in a scope whose only slot is `this`.
+fn bind_fluxar_function(fun: &FluxarFunctionImpl, instance: &LiteralValue) -> FluxarFunctionImpl {
+    let mut callable_impl = fun.clone();
+    let mut new_env = callable_impl.parent_env.enclose();
+    new_env.define("this".to_string(), instance.clone());
+    callable_impl.parent_env = new_env;
+    callable_impl
+}
+fn as_index(bracket: &Token, value: &LiteralValue) -> Result<i64, RuntimeError> {
+    match value {
+        Int(x) => Ok(*x),
+        other => Err(RuntimeError::new(bracket, &format!(
+            "Index must be an integer, not {}", other.to_string()
+        ))),
+    }
+}
+/// Turns a possibly negative index into a position inside a sequence of length `len`.
+fn resolve_index(bracket: &Token, value: &LiteralValue, len: usize) -> Result<usize, RuntimeError> {
+    let index = as_index(bracket, value)?;
+    let position = if index < 0 { index + len as i64 } else { index };
+    if position < 0 || position >= len as i64 {
+        return Err(RuntimeError::new(bracket, &format!(
+            "Index {} out of range for length {}", index, len
+        )));
+    }
+    Ok(position as usize)
+}
+/// Slice bounds behave like Python's: negative bounds count from the end and out of range bounds are clamped.
+fn resolve_slice(
+    bracket: &Token, start: &Option<LiteralValue>, end: &Option<LiteralValue>, len: usize
+) -> Result<(usize, usize), RuntimeError> {
+    let clamp = |bound: &Option<LiteralValue>, default: usize| -> Result<usize, RuntimeError> {
+        match bound {
+            None | Some(Nil) => Ok(default),
+            Some(value) => {
+                let index = as_index(bracket, value)?;
+                let position = if index < 0 { index + len as i64 } else { index };
+                Ok(position.clamp(0, len as i64) as usize)
+            }
+        }
+    };
+    let from = clamp(start, 0)?;
+    let to = clamp(end, len)?;
+    Ok((from, to.max(from)))
+}
+pub fn find_method(name: &str, class: LiteralValue) -> Result<Option<CallableImpl>, String> {
     if let FluxarClass { name: _, generics: _, methods, superclass } = class {
-        if let Some(fun) = methods.get(name) { return Some(fun.clone()); }
+        if let Some(fun) = methods.get(name) { return Ok(Some(fun.clone())); }
         if let Some(superclass) = superclass { return find_method(name, *superclass.clone()); }
-        None
-    } else { panic!("Cannot find method on non-class"); }
+        Ok(None)
+    } else { Err(format!("Cannot find method on non-class {}", class.to_type())) }
 }
 pub fn run_fluxar_function(
     fluxarfun: FluxarFunctionImpl, 
     arguments: &Vec<Expr>,
     eval_env: Environment
-) -> Result<LiteralValue, String> {
+) -> Result<LiteralValue, RuntimeError> {
     if arguments.len() != fluxarfun.arity {
         return Err(format!(
             "Callable {} expected {} arguments but got {}", fluxarfun.name,
             fluxarfun.arity, arguments.len()
-        ));
+        ).into());
     }
     let mut arg_vals = vec![];
     for arg in arguments {
         let val = arg.evaluate(eval_env.clone())?;
         arg_vals.push(val);
     }
+    call_fluxar_function(fluxarfun, arg_vals)
+}
+fn call_fluxar_function(fluxarfun: FluxarFunctionImpl, arg_vals: Vec<LiteralValue>) -> Result<LiteralValue, RuntimeError> {
     let mut fun_env = fluxarfun.parent_env.enclose();
     for (i, val) in arg_vals.iter().enumerate() { 
         fun_env.define(fluxarfun.params[i].lexeme.clone(), (*val).clone()); 
     }
     let mut int = Interpreter::with_env(fun_env);
-    for i in 0..(fluxarfun.body.len()) {
-        let result = int.interpret(vec![&fluxarfun.body[i]]);
-        if let Err(e) = result { return Err(e); }
-        if let Some(value) = int.specials.get("return") { return Ok(value.clone()); }
+    match int.interpret(fluxarfun.body.iter().map(|b| b.as_ref()).collect())? {
+        ControlFlow::Return(value) => Ok(*value),
+        _ => Ok(LiteralValue::Nil),
+    }
+}
+/// Calls a function value with already evaluated arguments, used by natives that take callbacks.
+pub fn call_value(callee: &LiteralValue, arguments: Vec<LiteralValue>) -> Result<LiteralValue, RuntimeError> {
+    match callee {
+        Callable(FluxarFunction(fluxarfun)) => {
+            if arguments.len() != fluxarfun.arity {
+                return Err(format!(
+                    "Callable {} expected {} arguments but got {}", fluxarfun.name,
+                    fluxarfun.arity, arguments.len()
+                ).into());
+            }
+            call_fluxar_function(fluxarfun.clone(), arguments)
+        },
+        Callable(NativeFunction(nativefun)) => {
+            nativefun.check_arity(arguments.len())?;
+            (nativefun.fun)(&arguments)
+        },
+        Callable(CallableImpl::Closure(closure)) => vm::call_closure(closure, arguments),
+        Callable(CallableImpl::Command(command)) => command.call(&arguments),
+        other => Err(format!("{} is not callable", other.to_type()).into()),
     }
-    Ok(LiteralValue::Nil)
 }
\ No newline at end of file
@@ -1,47 +1,76 @@
 use crate::environment::Environment;
 use crate::expr::{
     LiteralValue, CallableImpl, 
-    FluxarFunctionImpl, NativeFunctionImpl
+    FluxarFunctionImpl
 };
 use crate::statements::Statement;
 use crate::scanner::Token;
+use crate::error::RuntimeError;
+use crate::expr;
+use crate::command;
+use crate::module::{Import, ModuleLoader};
+use crate::permissions::Permissions;
 
+use std::cell::RefCell;
 use std::collections::HashMap;
-use std::process::Command;
 use std::rc::Rc;
 
+/// How a statement finished. Anything other than `Normal` stops the enclosing statements
+/// and is handed upwards until a loop or function call consumes it.
+#[derive(Debug, Clone)]
+pub enum ControlFlow {
+    Normal,
+    Return(Box<LiteralValue>),
+    /// Carries the loop label, if the statement named one.
+    Break(Option<String>),
+    Continue(Option<String>),
+}
 pub struct Interpreter {
-    pub specials: HashMap<String, LiteralValue>,
     pub environment: Environment,
+    /// Only the interpreters running a file's top level import, since imports are not allowed elsewhere.
+    loader: Option<Rc<RefCell<ModuleLoader>>>,
 }
 impl Interpreter {
-    pub fn new() -> Self {
+    pub fn new(loader: Rc<RefCell<ModuleLoader>>, permissions: Rc<RefCell<Permissions>>) -> Self {
         Self {
-            specials: HashMap::new(),
-            environment: Environment::new(HashMap::new()),
+            environment: Environment::new(HashMap::new(), permissions),
+            loader: Some(loader),
         }
     }
-    pub fn resolve(&mut self, locals: HashMap<usize, usize>) { self.environment.resolve(locals); }
-    pub fn with_env(env: Environment) -> Self { Self { specials: HashMap::new(), environment: env } }
+    pub fn resolve(&mut self, locals: HashMap<usize, (usize, usize)>) { self.environment.resolve(locals); }
+    pub fn with_env(env: Environment) -> Self { Self { environment: env, loader: None } }
 
     #[allow(dead_code)]
     pub fn for_anon(parent: Environment) -> Self {
         let env = parent.enclose();
-        Self { specials: HashMap::new(), environment: env }
+        Self { environment: env, loader: None }
+    }
+    /// Runs a file's top level, returning the value of the trailing expression statement if it ends in one.
+    pub fn run(&mut self, stmts: &[Statement]) -> Result<LiteralValue, RuntimeError> {
+        match stmts.split_last() {
+            Some((Statement::Expression { expression }, rest)) => {
+                self.interpret(rest.iter().collect())?;
+                expression.evaluate(self.environment.clone())
+            },
+            _ => self.interpret(stmts.iter().collect()).map(|_| LiteralValue::Nil),
+        }
     }
-    pub fn interpret(&mut self, stmts: Vec<&Statement>) -> Result<(), String> {
+    pub fn interpret(&mut self, stmts: Vec<&Statement>) -> Result<ControlFlow, RuntimeError> {
         for stmt in stmts {
-            match stmt {
+            let flow = match stmt {
                 Statement::Expression { expression } => {
                     expression.evaluate(self.environment.clone())?;
+                    ControlFlow::Normal
                 },
                 Statement::Print { expression } => {
                     let value = expression.evaluate(self.environment.clone())?;
                     println!("{}", value.to_string());
+                    ControlFlow::Normal
                 },
                 Statement::Var { name, var_type: _, initializer } => {
                     let value = initializer.evaluate(self.environment.clone())?;
                     self.environment.define(name.lexeme.clone(), value);
+                    ControlFlow::Normal
                 },
                 Statement::Block { statements } => {
                     let new_environment = self.environment.enclose();
@@ -52,12 +81,13 @@ impl Interpreter {
                     let block_result = self.interpret(
                         (*statements).iter().map(|b| b.as_ref()).collect());
                     self.environment = old_environment;
-                    block_result?; 
+                    block_result?
                 },
-                Statement::Function { name, params: _, generics: _, return_type: _, body: _ } => {
-                    let callable = self.make_function(stmt);
+                Statement::Function { name, params: _, param_types: _, generics: _, return_type: _, body: _ } => {
+                    let callable = self.make_function(stmt)?;
                     let fun = LiteralValue::Callable(CallableImpl::FluxarFunction(callable));
                     self.environment.define(name.lexeme.clone(), fun);
+                    ControlFlow::Normal
                 }
                 Statement::Class { name, generics, methods, superclass } => {
                     let mut methods_map = HashMap::new();
@@ -67,92 +97,144 @@ impl Interpreter {
                         let superclass = superclass.evaluate(self.environment.clone())?;
                         if let LiteralValue::FluxarClass { .. } = superclass {
                             superclass_value = Some(Box::new(superclass));
-                        } else { return Err(format!("Superclass must be a class, not {}", superclass.to_type())); }
+                        } else {
+                            return Err(RuntimeError::new(name, &format!(
+                                "Superclass must be a class, not {}", superclass.to_type()
+                            )));
+                        }
                     } else { superclass_value = None }
 
-                    self.environment.define(name.lexeme.clone(), LiteralValue::Nil);
+                    // Methods see the class through the enclosing scope, so it is defined once
+                    // it is complete, in the slot the resolver gave its name
+                    let class_environment = self.environment.clone();
                     self.environment = self.environment.enclose();
-                    for generic in generics {
-                        self.environment.define(generic.lexeme.clone(), LiteralValue::Nil);
-                    }
                     if let Some(sc) = superclass_value.clone() {
                         self.environment.define("super".to_string(), *sc);
                     }
                     for method in methods {
-                        if let Statement::Function { name, params: _, generics: _, return_type: _, body: _ } = method.as_ref() {
-                            let function = self.make_function(method);
-                            methods_map.insert(name.lexeme.clone(), function);
-                        } else { panic!("Something that was not a function was in the methods of a class"); }
+                        match self.make_function(method) {
+                            Ok(function) => { methods_map.insert(function.name.clone(), CallableImpl::FluxarFunction(function)); },
+                            Err(e) => {
+                                self.environment = class_environment;
+                                return Err(e.or_at(name));
+                            },
+                        }
                     }
                     let class = LiteralValue::FluxarClass { 
                         name: name.lexeme.clone(), 
-                        generics: generics.iter().map(|g| (*g).clone()).collect(),
+                        generics: generics.iter().map(|g| g.name.clone()).collect(),
                         methods: methods_map, superclass: superclass_value
                     };
-                    if !self.environment.assign_global(&name.lexeme, class) {
-                        return Err(format!("Class definition failed for {}", name.lexeme));
-                    }; self.environment = *self.environment.enclosing.clone().unwrap();
+                    self.environment = class_environment;
+                    self.environment.define(name.lexeme.clone(), class);
+                    ControlFlow::Normal
                 },
                 Statement::IfStmt { predicate, then, els } => {
                     let truth_value = predicate.evaluate(self.environment.clone())?;
-                    if truth_value.is_true() == LiteralValue::True {
-                        self.interpret(vec![then.as_ref()])?;
+                    if truth_value.is_true()? == LiteralValue::True {
+                        self.interpret(vec![then.as_ref()])?
                     } else if let Some(els_stmt) = els {
-                        self.interpret(vec![els_stmt.as_ref()])?;
-                    }
+                        self.interpret(vec![els_stmt.as_ref()])?
+                    } else { ControlFlow::Normal }
                 },
-                Statement::WhileStmt { condition, body } => {
+                Statement::WhileStmt { condition, body, increment, label } => {
+                    let label = label.as_ref().map(|l| &l.lexeme);
+                    let targets_this_loop = |target: &Option<String>| target.is_none() || target.as_ref() == label;
+                    let mut flow = ControlFlow::Normal;
                     let mut flag = condition.evaluate(self.environment.clone())?;
-                    while flag.is_true() == LiteralValue::True {
-                        let statements = vec![body.as_ref()];
-                        self.interpret(statements)?;
+                    while flag.is_true()? == LiteralValue::True {
+                        match self.interpret(vec![body.as_ref()])? {
+                            ControlFlow::Normal => (),
+                            ControlFlow::Break(target) if targets_this_loop(&target) => break,
+                            ControlFlow::Continue(target) if targets_this_loop(&target) => (),
+                            // A return or a jump to an outer loop leaves this loop too
+                            other => { flow = other; break; },
+                        }
+                        if let Some(increment) = increment {
+                            increment.evaluate(self.environment.clone())?;
+                        }
                         flag = condition.evaluate(self.environment.clone())?;
                     }
+                    flow
                 },
+                Statement::Break { keyword: _, label } => ControlFlow::Break(label.as_ref().map(|l| l.lexeme.clone())),
+                Statement::Continue { keyword: _, label } => ControlFlow::Continue(label.as_ref().map(|l| l.lexeme.clone())),
                 Statement::ReturnStmt { keyword: _, value } => {
                     let eval_val;
                     if let Some(value) = value {
                         eval_val = value.evaluate(self.environment.clone())?;
                     } else { eval_val = LiteralValue::Nil; }
-                    self.specials.insert("return".to_string(), eval_val);
+                    ControlFlow::Return(Box::new(eval_val))
                 },
-                Statement::CmdFunction { name, cmd } => {
-                    // Return a callable that runs a shell commmand, captures the stdout and returns it in a String
-                    let cmd = cmd.clone();
-                    let local_fn = move |_args: &Vec<LiteralValue>| {
-                        let cmd = cmd.clone();
-                        let parts = cmd.split(" ").collect::<Vec<&str>>();
-                        let mut command = Command::new(parts[0].replace("\"", ""));
-                        for part in parts[1..].iter() { command.arg(part.replace("\"", "")); }
-                        let output = command.output().expect("Failed to run command");
-                        return LiteralValue::StringValue(
-                            std::str::from_utf8(output.stdout.as_slice())
-                                .unwrap().to_string()
-                        );
-                    };
-                    let fun_val = LiteralValue::Callable(
-                        CallableImpl::NativeFunction(NativeFunctionImpl {
-                            name: name.lexeme.clone(), arity: 0,
-                            fun: Rc::new(local_fn)
-                        })
-                    ); self.environment.define(name.lexeme.clone(), fun_val);
+                Statement::Throw { keyword, value } => {
+                    let value = value.evaluate(self.environment.clone())?;
+                    return Err(RuntimeError::thrown(keyword, value));
+                },
+                Statement::TryStmt { try_block, catch_name, catch_block, finally_block } => {
+                    let mut result = self.interpret(vec![try_block.as_ref()]);
+                    if let (Err(error), Some(name), Some(catch_block)) = (&result, catch_name, catch_block) {
+                        let old_environment = self.environment.clone();
+                        self.environment = self.environment.enclose();
+                        self.environment.define(name.lexeme.clone(), error.to_value());
+                        result = self.interpret(vec![catch_block.as_ref()]);
+                        self.environment = old_environment;
+                    }
+                    // An error or jump out of the finally block replaces whatever was pending
+                    if let Some(finally_block) = finally_block {
+                        let finally_flow = self.interpret(vec![finally_block.as_ref()])?;
+                        if !matches!(finally_flow, ControlFlow::Normal) { return Ok(finally_flow); }
+                    }
+                    result?
+                },
+                Statement::CmdFunction { name, params, param_types: _, command } => {
+                    self.environment.define(name.lexeme.clone(), command::cmd_function(&name.lexeme, params.len(), command, self.environment.permissions()));
+                    ControlFlow::Normal
+                },
+                Statement::Import { keyword: _, path, alias, names } => {
+                    let module = self.import(path)?;
+                    match alias {
+                        Some(alias) => self.environment.define(alias.lexeme.clone(), module),
+                        None => for name in names {
+                            let value = expr::get_property(name, module.clone())?;
+                            self.environment.define(name.lexeme.clone(), value);
+                        },
+                    }
+                    ControlFlow::Normal
                 },
             };
+            if !matches!(flow, ControlFlow::Normal) { return Ok(flow); }
         }
-        Ok(())
+        Ok(ControlFlow::Normal)
+    }
+    /// Runs the module at `path` in an environment of its own, unless it already ran.
+    fn import(&mut self, path: &Token) -> Result<LiteralValue, RuntimeError> {
+        let Some(loader) = self.loader.clone() else {
+            return Err(RuntimeError::new(path, "Imports are only allowed at the top level of a file"));
+        };
+        let begun = loader.borrow_mut().begin(path)?;
+        let module = match begun {
+            Import::Cached(module) => return Ok(module),
+            Import::Load(module) => module,
+        };
+        let mut interpreter = Interpreter { environment: Environment::new(module.locals, self.environment.permissions()), loader: Some(loader.clone()) };
+        let result = interpreter.interpret(module.statements.iter().collect()).map(|_| LiteralValue::Module {
+            name: module.name, globals: interpreter.environment.globals(),
+        });
+        let module = loader.borrow_mut().finish(result);
+        module
     }
-    fn make_function(&self, fn_stmt: &Statement) -> FluxarFunctionImpl {
-        if let Statement::Function { name, params, generics, return_type, body } = fn_stmt {
+    fn make_function(&self, fn_stmt: &Statement) -> Result<FluxarFunctionImpl, RuntimeError> {
+        if let Statement::Function { name, params, param_types: _, generics, return_type, body } = fn_stmt {
             let (arity, name_clone) = (params.len(), name.lexeme.clone());
             let params: Vec<Token> = params.iter().map(|t| (*t).clone()).collect();
-            let generics: Vec<Token> = generics.iter().map(|g| (*g).clone()).collect();
+            let generics: Vec<Token> = generics.iter().map(|g| g.name.clone()).collect();
             let body: Vec<Box<Statement>> = body.iter().map(|b| (*b).clone()).collect();
 
             let parent_env = self.environment.clone();
             let callable_impl = FluxarFunctionImpl {
                 name: name_clone, arity, parent_env, params,
                 generics, return_type: return_type.clone(), body
-            }; callable_impl
-        } else { panic!("Tried to make a function from a non-function statement"); }
+            }; Ok(callable_impl)
+        } else { Err("Tried to make a function from a non-function statement".into()) }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,328 @@
+use std::rc::Rc;
+use std::cell::RefCell;
+
+use crate::expr::{LiteralValue, map_insert};
+use crate::error::RuntimeError;
+use crate::number;
+use crate::decimal::Decimal;
+
+/// Deeper nesting than this is rejected, which also stops cyclic lists and maps from recursing forever.
+const MAX_DEPTH: usize = 512;
+
+struct JsonParser<'a> {
+    source: &'a [u8],
+    text: &'a str,
+    current: usize,
+    depth: usize,
+}
+impl<'a> JsonParser<'a> {
+    fn new(text: &'a str) -> Self {
+        Self { source: text.as_bytes(), text, current: 0, depth: 0 }
+    }
+    fn error(&self, offset: usize, msg: &str) -> String {
+        format!("JSON error at byte {}: {}", offset, msg)
+    }
+    fn peek(&self) -> Option<u8> {
+        self.source.get(self.current).copied()
+    }
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.peek() { self.current += 1; }
+    }
+    fn expect(&mut self, byte: u8) -> Result<(), String> {
+        if self.peek() == Some(byte) { self.current += 1; Ok(()) }
+        else { Err(self.error(self.current, &format!("expected '{}'", byte as char))) }
+    }
+    fn parse_document(&mut self) -> Result<LiteralValue, String> {
+        let value = self.parse_value()?;
+        self.skip_whitespace();
+        if self.current < self.source.len() {
+            return Err(self.error(self.current, "unexpected trailing characters"));
+        }
+        Ok(value)
+    }
+    fn parse_value(&mut self) -> Result<LiteralValue, String> {
+        self.skip_whitespace();
+        match self.peek() {
+            None => Err(self.error(self.current, "unexpected end of input")),
+            Some(b'{') => self.nested(Self::parse_object),
+            Some(b'[') => self.nested(Self::parse_array),
+            Some(b'"') => Ok(LiteralValue::StringValue(self.parse_string()?)),
+            Some(b't') => self.parse_keyword("true", LiteralValue::True),
+            Some(b'f') => self.parse_keyword("false", LiteralValue::False),
+            Some(b'n') => self.parse_keyword("null", LiteralValue::Nil),
+            Some(b'-' | b'0'..=b'9') => self.parse_number(),
+            Some(_) => Err(self.error(self.current, "unexpected character")),
+        }
+    }
+    fn nested(&mut self, parse: fn(&mut Self) -> Result<LiteralValue, String>) -> Result<LiteralValue, String> {
+        if self.depth >= MAX_DEPTH { return Err(self.error(self.current, "nesting is too deep")); }
+        self.depth += 1;
+        let value = parse(self);
+        self.depth -= 1;
+        value
+    }
+    fn parse_keyword(&mut self, keyword: &str, value: LiteralValue) -> Result<LiteralValue, String> {
+        if self.text[self.current..].starts_with(keyword) {
+            self.current += keyword.len();
+            Ok(value)
+        } else { Err(self.error(self.current, "invalid literal")) }
+    }
+    fn parse_object(&mut self) -> Result<LiteralValue, String> {
+        self.expect(b'{')?;
+        let mut entries = vec![];
+        self.skip_whitespace();
+        if self.peek() == Some(b'}') { self.current += 1; return Ok(LiteralValue::new_map(entries)); }
+        loop {
+            self.skip_whitespace();
+            if self.peek() != Some(b'"') { return Err(self.error(self.current, "expected a string key")); }
+            let key = self.parse_string()?;
+            self.skip_whitespace();
+            self.expect(b':')?;
+            let value = self.parse_value()?;
+            map_insert(&mut entries, LiteralValue::StringValue(key), value);
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.current += 1,
+                Some(b'}') => { self.current += 1; return Ok(LiteralValue::new_map(entries)); },
+                _ => return Err(self.error(self.current, "expected ',' or '}' in object")),
+            }
+        }
+    }
+    fn parse_array(&mut self) -> Result<LiteralValue, String> {
+        self.expect(b'[')?;
+        let mut items = vec![];
+        self.skip_whitespace();
+        if self.peek() == Some(b']') {
+            self.current += 1;
+            return Ok(LiteralValue::List(Rc::new(RefCell::new(items))));
+        }
+        loop {
+            items.push(self.parse_value()?);
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.current += 1,
+                Some(b']') => {
+                    self.current += 1;
+                    return Ok(LiteralValue::List(Rc::new(RefCell::new(items))));
+                },
+                _ => return Err(self.error(self.current, "expected ',' or ']' in array")),
+            }
+        }
+    }
+    fn parse_number(&mut self) -> Result<LiteralValue, String> {
+        let start = self.current;
+        if self.peek() == Some(b'-') { self.current += 1; }
+        match self.peek() {
+            Some(b'0') => self.current += 1,
+            Some(b'1'..=b'9') => while let Some(b'0'..=b'9') = self.peek() { self.current += 1; },
+            _ => return Err(self.error(self.current, "expected a digit")),
+        }
+        if self.peek() == Some(b'.') {
+            self.current += 1;
+            if !matches!(self.peek(), Some(b'0'..=b'9')) { return Err(self.error(self.current, "expected a digit after '.'")); }
+            while let Some(b'0'..=b'9') = self.peek() { self.current += 1; }
+        }
+        if let Some(b'e' | b'E') = self.peek() {
+            self.current += 1;
+            if let Some(b'+' | b'-') = self.peek() { self.current += 1; }
+            if !matches!(self.peek(), Some(b'0'..=b'9')) { return Err(self.error(self.current, "expected a digit in exponent")); }
+            while let Some(b'0'..=b'9') = self.peek() { self.current += 1; }
+        }
+        // Numbers without a fraction or exponent are ints as long as they fit in 64 bits, others
+        // floats as long as the nearest f64 prints back as written. Anything else would lose
+        // digits, so it becomes a decimal
+        let text = &self.text[start..self.current];
+        let exact = Decimal::parse_scientific(text);
+        if !text.contains(['.', 'e', 'E']) {
+            if let Ok(x) = text.parse::<i64>() { return Ok(LiteralValue::Int(x)); }
+        } else if let Ok(x) = text.parse::<f64>() {
+            if exact.is_some() && Decimal::from_f64(x) == exact { return Ok(LiteralValue::Float(x)); }
+        }
+        exact.map(LiteralValue::Decimal).ok_or_else(|| self.error(start, "number out of range"))
+    }
+    fn parse_hex4(&mut self) -> Result<u32, String> {
+        let digits = self.text.get(self.current..self.current + 4)
+            .ok_or(self.error(self.current, "incomplete unicode escape"))?;
+        // from_str_radix would also take a sign like "+041"
+        if !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(self.error(self.current, "invalid unicode escape"));
+        }
+        let code = u32::from_str_radix(digits, 16).map_err(|_| self.error(self.current, "invalid unicode escape"))?;
+        self.current += 4;
+        Ok(code)
+    }
+    fn parse_string(&mut self) -> Result<String, String> {
+        let start = self.current;
+        self.expect(b'"')?;
+        let mut out = String::new();
+        loop {
+            let ch = self.text[self.current..].chars().next()
+                .ok_or(self.error(start, "unterminated string"))?;
+            let offset = self.current;
+            self.current += ch.len_utf8();
+            match ch {
+                '"' => return Ok(out),
+                '\\' => {
+                    let escape = self.peek().ok_or(self.error(offset, "unterminated escape"))?;
+                    self.current += 1;
+                    match escape {
+                        b'"' => out.push('"'), b'\\' => out.push('\\'), b'/' => out.push('/'),
+                        b'b' => out.push('\u{8}'), b'f' => out.push('\u{c}'),
+                        b'n' => out.push('\n'), b'r' => out.push('\r'), b't' => out.push('\t'),
+                        b'u' => {
+                            let mut code = self.parse_hex4()?;
+                            if (0xD800..0xDC00).contains(&code) {
+                                if !self.text[self.current..].starts_with("\\u") {
+                                    return Err(self.error(offset, "unpaired surrogate in unicode escape"));
+                                }
+                                self.current += 2;
+                                let low = self.parse_hex4()?;
+                                if !(0xDC00..0xE000).contains(&low) {
+                                    return Err(self.error(offset, "invalid low surrogate in unicode escape"));
+                                }
+                                code = 0x10000 + ((code - 0xD800) << 10) + (low - 0xDC00);
+                            }
+                            out.push(char::from_u32(code).ok_or(self.error(offset, "invalid unicode escape"))?);
+                        },
+                        _ => return Err(self.error(offset, "invalid escape sequence")),
+                    }
+                },
+                c if (c as u32) < 0x20 => return Err(self.error(offset, "control character in string")),
+                c => out.push(c),
+            }
+        }
+    }
+}
+fn write_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""), '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"), '\r' => out.push_str("\\r"), '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+fn write_newline(out: &mut String, indent: usize, depth: usize) {
+    if indent > 0 {
+        out.push('\n');
+        out.push_str(&" ".repeat(indent * depth));
+    }
+}
+fn write_entries<T>(
+    out: &mut String, items: &[T], brackets: (char, char), indent: usize, depth: usize,
+    mut write_item: impl FnMut(&mut String, &T) -> Result<(), String>
+) -> Result<(), String> {
+    out.push(brackets.0);
+    for (i, item) in items.iter().enumerate() {
+        if i > 0 { out.push(','); }
+        write_newline(out, indent, depth + 1);
+        write_item(out, item)?;
+    }
+    if !items.is_empty() { write_newline(out, indent, depth); }
+    out.push(brackets.1);
+    Ok(())
+}
+fn write_value(out: &mut String, value: &LiteralValue, indent: usize, depth: usize) -> Result<(), String> {
+    if depth >= MAX_DEPTH { return Err("Cannot convert to JSON: value is nested too deeply or cyclic".to_string()); }
+    let separator = if indent > 0 { ": " } else { ":" };
+    match value {
+        LiteralValue::Nil => out.push_str("null"),
+        LiteralValue::True => out.push_str("true"),
+        LiteralValue::False => out.push_str("false"),
+        LiteralValue::Int(x) => out.push_str(&x.to_string()),
+        LiteralValue::Decimal(x) => out.push_str(&x.to_string()),
+        LiteralValue::Float(x) => {
+            if !x.is_finite() { return Err(format!("Cannot convert {} to JSON", x)); }
+            out.push_str(&number::format_float(*x));
+        },
+        LiteralValue::StringValue(s) => write_string(out, s),
+        LiteralValue::List(items) => write_entries(out, &items.borrow(), ('[', ']'), indent, depth, |out, item| {
+            write_value(out, item, indent, depth + 1)
+        })?,
+        LiteralValue::Map(entries) => write_entries(out, &entries.borrow(), ('{', '}'), indent, depth, |out, (key, value)| {
+            match key {
+                LiteralValue::StringValue(key) => write_string(out, key),
+                other => write_string(out, &other.to_string()),
+            }
+            out.push_str(separator);
+            write_value(out, value, indent, depth + 1)
+        })?,
+        LiteralValue::FluxarInstance { class: _, fields } => write_entries(out, &fields.borrow(), ('{', '}'), indent, depth, |out, (key, value)| {
+            write_string(out, key);
+            out.push_str(separator);
+            write_value(out, value, indent, depth + 1)
+        })?,
+        LiteralValue::Table(table) => {
+            let rows: Vec<LiteralValue> = (0..table.row_count()).map(|i| table.row(i)).collect();
+            write_entries(out, &rows, ('[', ']'), indent, depth, |out, row| write_value(out, row, indent, depth + 1))?
+        },
+        other => return Err(format!("Cannot convert {} to JSON", other.to_type())),
+    }
+    Ok(())
+}
+pub fn parse(text: &str) -> Result<LiteralValue, String> {
+    JsonParser::new(text).parse_document()
+}
+pub fn stringify(value: &LiteralValue, indent: usize) -> Result<String, String> {
+    let mut out = String::new();
+    write_value(&mut out, value, indent, 0)?;
+    Ok(out)
+}
+/// `json_parse(text)` maps objects to maps, arrays to lists and null to nil.
+pub fn json_parse_impl(args: &[LiteralValue]) -> Result<LiteralValue, RuntimeError> {
+    match args.first() {
+        Some(LiteralValue::StringValue(text)) => Ok(parse(text)?),
+        Some(other) => Err(format!("json_parse() expects a String, not {}", other.to_type()).into()),
+        None => Err("json_parse() expects 1 argument".into()),
+    }
+}
+/// `json_stringify(value, indent)` produces compact output unless an indent width is given.
+pub fn json_stringify_impl(args: &[LiteralValue]) -> Result<LiteralValue, RuntimeError> {
+    let value = args.first().ok_or("json_stringify() expects 1 argument".to_string())?;
+    let indent = match args.get(1) {
+        None | Some(LiteralValue::Nil) => 0,
+        Some(LiteralValue::Int(n)) if *n >= 0 => *n as usize,
+        Some(other) => return Err(format!("json_stringify() indent must be a whole number, not {}", other.to_string()).into()),
+    };
+    Ok(LiteralValue::StringValue(stringify(value, indent)?))
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn round_trips_nested_values() {
+        let text = r#"{"name":"Flüxar 🚀","tags":["a\n\"b\""],"n":-12.5e-3,"big":9007199254740993,"ok":true,"none":null}"#;
+        let value = parse(text).unwrap();
+        assert_eq!(
+            stringify(&value, 0).unwrap(),
+            "{\"name\":\"Flüxar 🚀\",\"tags\":[\"a\\n\\\"b\\\"\"],\"n\":-0.0125,\"big\":9007199254740993,\"ok\":true,\"none\":null}"
+        );
+        assert_eq!(stringify(&parse("[1, {\"a\": []}]").unwrap(), 2).unwrap(), "[\n  1,\n  {\n    \"a\": []\n  }\n]");
+        assert_eq!(parse("[1, 1.0, 1e2]").unwrap(), LiteralValue::List(Rc::new(RefCell::new(vec![
+            LiteralValue::Int(1), LiteralValue::Float(1.0), LiteralValue::Float(100.0),
+        ]))));
+        assert_eq!(stringify(&parse("[2, 2.0]").unwrap(), 0).unwrap(), "[2,2.0]");
+        assert_eq!(parse("\"\\ud83d\\ude80\\u00e9\"").unwrap(), LiteralValue::StringValue("🚀é".to_string()));
+    }
+    #[test]
+    fn keeps_digits_that_do_not_fit_in_ints_or_floats() {
+        let text = "[12345678901234567890,0.1000000000000000000001,0.1,1.5e3,-2E-2]";
+        let value = parse(text).unwrap();
+        assert!(matches!(&value, LiteralValue::List(items) if matches!(
+            items.borrow().as_slice(),
+            [LiteralValue::Decimal(_), LiteralValue::Decimal(_), LiteralValue::Float(_), LiteralValue::Float(_), LiteralValue::Float(_)]
+        )));
+        assert_eq!(stringify(&value, 0).unwrap(), "[12345678901234567890,0.1000000000000000000001,0.1,1500.0,-0.02]");
+        assert_eq!(parse("1e99999").err().unwrap(), "JSON error at byte 0: number out of range");
+    }
+    #[test]
+    fn reports_byte_offset_of_errors() {
+        assert_eq!(parse("[1, 2,]").err().unwrap(), "JSON error at byte 6: unexpected character");
+        assert_eq!(parse("{\"é\": tru}").err().unwrap(), "JSON error at byte 7: invalid literal");
+        assert_eq!(parse(r#""\u+041""#).err().unwrap(), "JSON error at byte 3: invalid unicode escape");
+        assert_eq!(parse("\"abc").err().unwrap(), "JSON error at byte 0: unterminated string");
+    }
+}
@@ -0,0 +1,29 @@
+//! Fluxar as a library, for embedding the language in Rust applications through [`Engine`].
+mod scanner;
+mod expr;
+mod parser;
+mod interpreter;
+mod statements;
+mod environment;
+mod resolver;
+mod type_;
+mod table;
+mod csv;
+mod json;
+mod error;
+mod chunk;
+mod compiler;
+mod vm;
+mod checker;
+mod diagnostic;
+mod module;
+mod command;
+mod engine;
+mod permissions;
+mod number;
+mod decimal;
+
+pub use engine::Engine;
+pub use error::RuntimeError;
+pub use expr::LiteralValue;
+pub use permissions::{Access, Permissions};
@@ -0,0 +1,116 @@
+use crate::checker::TypeChecker;
+use crate::diagnostic::{Diagnostic, SourceMap};
+use crate::error::RuntimeError;
+use crate::expr::LiteralValue;
+use crate::parser::Parser;
+use crate::permissions::Permissions;
+use crate::resolver::Resolver;
+use crate::scanner::{self, Scanner, Token};
+use crate::statements::Statement;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::{env, fs};
+
+/// Where the resolver placed each local, by expression id.
+pub type Locals = HashMap<usize, (usize, usize)>;
+/// Scans, parses, resolves and type checks a file, returning its statements and resolved locals.
+pub fn analyze(text: &str, file: u32) -> Result<(Vec<Statement>, Locals), Vec<Diagnostic>> {
+    let tokens = Scanner::new(text, file).scan_tokens()?;
+    let statements = Parser::new(tokens).parse()?;
+    // Name and type errors are independent, so both are reported at once
+    let resolved = Resolver::new().resolve(&statements.iter().collect());
+    let checked = TypeChecker::new().check(&statements.iter().collect());
+    match (resolved, checked) {
+        (Ok(locals), Ok(())) => Ok((statements, locals)),
+        (resolved, checked) => {
+            let mut diagnostics = resolved.err().unwrap_or_default();
+            diagnostics.extend(checked.err().unwrap_or_default());
+            Diagnostic::sort(&mut diagnostics);
+            Err(diagnostics)
+        },
+    }
+}
+/// A module that passed analysis and now has to be run by the backend that imported it.
+pub struct Module {
+    pub name: String,
+    pub statements: Vec<Statement>,
+    pub locals: Locals,
+}
+pub enum Import {
+    /// The module already ran; this is its namespace.
+    Cached(LiteralValue),
+    Load(Module),
+}
+/// Finds, reads and caches the modules of a program. Shared by every interpreter or VM
+/// running one of its files, so each module runs once however often it is imported.
+pub struct ModuleLoader {
+    pub sources: SourceMap,
+    /// Directories from `FLUXAR_PATH`, tried after the importing file's own directory.
+    search_path: Vec<PathBuf>,
+    /// The files being run with the path they were imported by, the main program first,
+    /// to resolve relative imports and catch circular ones.
+    loading: Vec<(PathBuf, String)>,
+    cache: HashMap<PathBuf, LiteralValue>,
+    /// Problems found in modules that failed to load, reported after the failed import.
+    diagnostics: Vec<Diagnostic>,
+    /// Importing reads a file, so it needs read access like `read_csv` does.
+    permissions: Rc<RefCell<Permissions>>,
+}
+impl ModuleLoader {
+    /// Until `set_main` names the file the program came from, imports resolve from the working directory.
+    pub fn new(permissions: Rc<RefCell<Permissions>>) -> Self {
+        let search_path = env::var_os("FLUXAR_PATH").map(|paths| env::split_paths(&paths).collect()).unwrap_or_default();
+        let main = (env::current_dir().unwrap_or_default().join("<main>"), "<main>".to_string());
+        Self {
+            sources: SourceMap::default(), search_path, loading: vec![main],
+            cache: HashMap::new(), diagnostics: vec![], permissions,
+        }
+    }
+    pub fn set_main(&mut self, path: &str) {
+        let file = fs::canonicalize(path).unwrap_or_else(|_| env::current_dir().unwrap_or_default().join(path));
+        self.loading[0] = (file, path.to_string());
+    }
+    fn find(&self, path: &str) -> Option<PathBuf> {
+        let importer = self.loading.last().and_then(|(file, _)| file.parent()).map(|dir| dir.to_path_buf());
+        importer.into_iter().chain(self.search_path.iter().cloned())
+            .map(|dir| dir.join(path))
+            .find(|candidate| candidate.is_file())
+            .and_then(|found| fs::canonicalize(found).ok())
+    }
+    /// Starts importing the module at `path`. A `Load` must be followed by `finish` once it ran.
+    pub fn begin(&mut self, path: &Token) -> Result<Import, RuntimeError> {
+        let Some(scanner::LiteralValue::StringValue(written)) = &path.literal else {
+            return Err(RuntimeError::new(path, "Module path must be a string"));
+        };
+        let Some(file) = self.find(written) else {
+            return Err(RuntimeError::new(path, &format!("Module '{}' not found", written)));
+        };
+        self.permissions.borrow().check_read(&file.to_string_lossy()).map_err(|e| e.or_at(path))?;
+        if let Some(module) = self.cache.get(&file) { return Ok(Import::Cached(module.clone())); }
+        if let Some(start) = self.loading.iter().position(|(loading, _)| *loading == file) {
+            let cycle: Vec<&str> = self.loading[start..].iter().map(|(_, name)| name.as_str()).chain([written.as_str()]).collect();
+            return Err(RuntimeError::new(path, &format!("Circular import: {}", cycle.join(" -> "))));
+        }
+        let text = fs::read_to_string(&file)
+            .map_err(|e| RuntimeError::new(path, &format!("Could not read module '{}': {}", written, e)))?;
+        let index = self.sources.add(Some(written.clone()), &text);
+        let (statements, locals) = analyze(&text, index).map_err(|diagnostics| {
+            self.diagnostics.extend(diagnostics);
+            RuntimeError::new(path, &format!("Module '{}' has errors", written))
+        })?;
+        let name = file.file_stem().map_or(written.clone(), |stem| stem.to_string_lossy().to_string());
+        self.loading.push((file, written.clone()));
+        Ok(Import::Load(Module { name, statements, locals }))
+    }
+    /// Ends the innermost load, caching the module's namespace if it ran without errors.
+    pub fn finish(&mut self, result: Result<LiteralValue, RuntimeError>) -> Result<LiteralValue, RuntimeError> {
+        let (file, _) = self.loading.pop().expect("finish() without a module being loaded");
+        if let Ok(module) = &result { self.cache.insert(file, module.clone()); }
+        result
+    }
+    /// Takes the problems of modules that failed to load.
+    pub fn take_diagnostics(&mut self) -> Vec<Diagnostic> { std::mem::take(&mut self.diagnostics) }
+}
@@ -0,0 +1,155 @@
+use crate::error::RuntimeError;
+use crate::decimal::Decimal;
+use crate::expr::LiteralValue::{self, Float, Int};
+use crate::scanner::{Token, TokenType};
+
+use std::cmp::Ordering;
+
+/// Applies a binary operator other than `==` and `!=` to two numbers. Ints stay ints, with
+/// overflow an error, and an int meeting a float is promoted. `/` always divides as floats,
+/// but an int divided by an int zero is an error as for `~/` and `%`, not infinity.
+/// `~/` rounds down and `%` takes the sign of the divisor, so `(x ~/ y) * y + x % y == x`.
+/// Decimals take ints along exactly but refuse floats, which would bring rounding back in.
+pub fn binary(operator: &Token, left: LiteralValue, right: LiteralValue) -> Result<LiteralValue, RuntimeError> {
+    use TokenType::*;
+    match (operator.token_type, left, right) {
+        (_, LiteralValue::Decimal(_), Float(_)) | (_, Float(_), LiteralValue::Decimal(_)) => {
+            Err(RuntimeError::new(operator, &format!(
+                "Cannot mix decimal and float in {}, convert one with decimal() or float()", operator.lexeme
+            )))
+        },
+        (Greater | GreaterEqual | Less | LessEqual, x, y) => {
+            let holds = match ordering(&x, &y) {
+                // NaN is neither smaller nor larger than anything
+                None => false,
+                Some(ordering) => match operator.token_type {
+                    Greater => ordering == Ordering::Greater,
+                    GreaterEqual => ordering != Ordering::Less,
+                    Less => ordering == Ordering::Less,
+                    _ => ordering != Ordering::Greater,
+                },
+            };
+            Ok(LiteralValue::from_bool(holds))
+        },
+        (Ampersand | Bar | Caret | LessLess | GreaterGreater, Int(x), Int(y)) => bitwise(operator, x, y),
+        (Ampersand | Bar | Caret | LessLess | GreaterGreater, x, y) => {
+            Err(not_integer(operator, if matches!(x, Int(_)) { &y } else { &x }))
+        },
+        (_, Int(x), Int(y)) => int_arithmetic(operator, x, y),
+        (_, x @ LiteralValue::Decimal(_), y) | (_, x, y @ LiteralValue::Decimal(_)) => {
+            decimal_arithmetic(operator, &to_decimal(&x), &to_decimal(&y))
+        },
+        (_, x, y) => float_arithmetic(operator, to_f64(&x), to_f64(&y)),
+    }
+}
+/// How two numbers compare, `None` when either is NaN or not a number.
+pub fn ordering(x: &LiteralValue, y: &LiteralValue) -> Option<Ordering> {
+    match (x, y) {
+        (Int(x), Int(y)) => Some(x.cmp(y)),
+        (Int(_) | Float(_), Int(_) | Float(_)) => to_f64(x).partial_cmp(&to_f64(y)),
+        (LiteralValue::Decimal(_), Int(_) | LiteralValue::Decimal(_)) | (Int(_), LiteralValue::Decimal(_)) => {
+            Some(to_decimal(x).cmp(&to_decimal(y)))
+        },
+        _ => None,
+    }
+}
+pub fn negate(operator: &Token, value: &LiteralValue) -> Result<LiteralValue, RuntimeError> {
+    match value {
+        Int(x) => x.checked_neg().map(Int)
+            .ok_or_else(|| RuntimeError::new(operator, &format!("Integer overflow in -{}", x))),
+        Float(x) => Ok(Float(-x)),
+        LiteralValue::Decimal(x) => Ok(LiteralValue::Decimal(x.neg())),
+        other => Err(RuntimeError::new(operator, &format!("Minus not implemented for {}", other.to_type()))),
+    }
+}
+/// Floats are printed with a fractional part even when they are whole, so `1.0` and `1`
+/// can be told apart.
+pub fn format_float(x: f64) -> String {
+    if x.is_finite() && x.fract() == 0.0 { format!("{:.1}", x) } else { x.to_string() }
+}
+pub fn not_integer(operator: &Token, value: &LiteralValue) -> RuntimeError {
+    RuntimeError::new(operator, &format!("Operator {} expects integers, got {}", operator.lexeme, value.to_string()))
+}
+fn to_f64(value: &LiteralValue) -> f64 {
+    match value {
+        Int(x) => *x as f64,
+        Float(x) => *x,
+        _ => f64::NAN,
+    }
+}
+fn to_decimal(value: &LiteralValue) -> Decimal {
+    match value {
+        LiteralValue::Decimal(x) => x.clone(),
+        Int(x) => Decimal::from_i64(*x),
+        _ => Decimal::from_i64(0),
+    }
+}
+fn decimal_arithmetic(operator: &Token, x: &Decimal, y: &Decimal) -> Result<LiteralValue, RuntimeError> {
+    use TokenType::*;
+    Ok(LiteralValue::Decimal(match operator.token_type {
+        Plus => x.add(y),
+        Minus => x.sub(y),
+        Star => x.mul(y),
+        Slash => x.div(y).ok_or_else(|| division_by_zero(operator))?,
+        _ => return Err(RuntimeError::new(operator, &format!("Operator {} is not defined for decimals", operator.lexeme))),
+    }))
+}
+fn int_arithmetic(operator: &Token, x: i64, y: i64) -> Result<LiteralValue, RuntimeError> {
+    use TokenType::*;
+    let result = match operator.token_type {
+        Plus => x.checked_add(y),
+        Minus => x.checked_sub(y),
+        Star => x.checked_mul(y),
+        Slash | TildeSlash | Percent if y == 0 => return Err(division_by_zero(operator)),
+        Slash => return float_arithmetic(operator, x as f64, y as f64),
+        TildeSlash => x.checked_div(y).map(|q| if x % y != 0 && (x < 0) != (y < 0) { q - 1 } else { q }),
+        Percent => Some(match x.wrapping_rem(y) {
+            r if r != 0 && (r < 0) != (y < 0) => r + y,
+            r => r,
+        }),
+        StarStar if y < 0 => return Err(RuntimeError::new(operator, &format!(
+            "Cannot raise the integer {} to the negative power {}, use a float base", x, y
+        ))),
+        StarStar => u32::try_from(y).ok().and_then(|y| x.checked_pow(y)),
+        _ => return Err(unsupported(operator)),
+    };
+    result.map(Int).ok_or_else(|| overflow(operator, x, y))
+}
+fn float_arithmetic(operator: &Token, x: f64, y: f64) -> Result<LiteralValue, RuntimeError> {
+    use TokenType::*;
+    Ok(Float(match operator.token_type {
+        Plus => x + y,
+        Minus => x - y,
+        Star => x * y,
+        Slash => x / y,
+        StarStar => x.powf(y),
+        TildeSlash | Percent if y == 0.0 => return Err(division_by_zero(operator)),
+        TildeSlash => (x / y).floor(),
+        Percent => x - y * (x / y).floor(),
+        _ => return Err(unsupported(operator)),
+    }))
+}
+fn bitwise(operator: &Token, x: i64, y: i64) -> Result<LiteralValue, RuntimeError> {
+    use TokenType::*;
+    if matches!(operator.token_type, LessLess | GreaterGreater) && !(0..64).contains(&y) {
+        return Err(RuntimeError::new(operator, &format!("Cannot shift by {}, shifts must be between 0 and 63", y)));
+    }
+    Ok(Int(match operator.token_type {
+        Ampersand => x & y,
+        Bar => x | y,
+        Caret => x ^ y,
+        // Shifting back has to give `x` again, or bits were pushed out of the top
+        LessLess if (x << y) >> y != x => return Err(overflow(operator, x, y)),
+        LessLess => x << y,
+        _ => x >> y,
+    }))
+}
+fn overflow(operator: &Token, x: i64, y: i64) -> RuntimeError {
+    RuntimeError::new(operator, &format!("Integer overflow in {} {} {}", x, operator.lexeme, y))
+}
+fn division_by_zero(operator: &Token) -> RuntimeError {
+    RuntimeError::new(operator, &format!("Division by zero in {}", operator.lexeme))
+}
+fn unsupported(operator: &Token) -> RuntimeError {
+    RuntimeError::new(operator, &format!("Operator {} is not defined for numbers", operator.lexeme))
+}
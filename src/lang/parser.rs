@@ -1,13 +1,16 @@
-use crate::scanner::{Token, TokenType::*, TokenType};
+use crate::scanner::{self, Token, TokenType::*, TokenType};
+use crate::command::CommandTemplate;
 use crate::expr::{Expr::*, Expr, LiteralValue};
 use crate::statements::Statement;
+use crate::type_::{TypeAnnotation, TypeParam};
+use crate::diagnostic::{Diagnostic, Span};
 
 pub struct Parser {
     tokens: Vec<Token>,
     current: usize,
     next_id: usize,
 }
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 enum FunctionKind { Function, Method }
 impl Parser {
     pub fn new(tokens: Vec<Token>) -> Self {
@@ -29,54 +32,83 @@ impl Parser {
         let peek = self.tokens[self.current].clone();
         peek.token_type == Eof
     }
-    pub fn parse(&mut self) -> Result<Vec<Statement>, String> {
+    pub fn parse(&mut self) -> Result<Vec<Statement>, Vec<Diagnostic>> {
         let mut stmts = vec![];
         let mut errors = vec![];
         while !self.is_at_end() {
             let stmt = self.declaration();
             match stmt {
                 Ok(s) => stmts.push(s),
-                Err(msg) => {
-                    errors.push(msg);
+                Err(diagnostic) => {
+                    errors.push(diagnostic);
                     self.synchronize();
                 },
             }
         }
-        if errors.len() == 0 { Ok(stmts) } else {
-            Err(errors.join("\n"))
-        }
+        if errors.len() == 0 { Ok(stmts) } else { Err(errors) }
+    }
+    /// A syntax error at `token`, labelled with what was found there.
+    fn error(&self, code: &'static str, token: &Token, message: &str) -> Diagnostic {
+        let found = if token.token_type == Eof { "found end of file".to_string() }
+        else { format!("found '{}'", token.lexeme) };
+        Diagnostic::error(code, message).at(token.span, &found)
     }
-    fn declaration(&mut self) -> Result<Statement, String> {
+    fn declaration(&mut self) -> Result<Statement, Diagnostic> {
         if self.match_token(Var) { self.var_declaration() }
         else if self.match_token(Fun) { self.function(FunctionKind::Function) }
         else if self.match_token(Class) { self.class_declaration() }
+        else if self.match_token(Import) { self.import_declaration() }
+        // `from` is only a keyword in front of a module path, so it stays usable as a name
+        else if self.check_contextual("from") && self.check_next(StringLit) { self.import_from_declaration() }
         else { self.statement() }
     }
-    fn var_declaration(&mut self) -> Result<Statement, String> {
+    fn import_declaration(&mut self) -> Result<Statement, Diagnostic> {
+        let keyword = self.previous();
+        let path = self.consume(StringLit, "Expected module path after 'import'.")?;
+        if !self.check_contextual("as") {
+            let token = self.tokens[self.current].clone();
+            return Err(self.error("E0100", &token, "Expected 'as' after module path."));
+        }
+        self.advance();
+        let alias = self.consume(Identifier, "Expected module name after 'as'.")?;
+        self.consume(Semicolon, "Expected ';' after import.")?;
+        Ok(Statement::Import { keyword, path, alias: Some(alias), names: vec![] })
+    }
+    fn import_from_declaration(&mut self) -> Result<Statement, Diagnostic> {
+        let keyword = self.advance();
+        let path = self.advance();
+        self.consume(Import, "Expected 'import' after module path.")?;
+        let mut names = vec![self.consume(Identifier, "Expected name to import.")?];
+        while self.match_token(Comma) {
+            names.push(self.consume(Identifier, "Expected name to import.")?);
+        }
+        self.consume(Semicolon, "Expected ';' after import.")?;
+        Ok(Statement::Import { keyword, path, alias: None, names })
+    }
+    fn var_declaration(&mut self) -> Result<Statement, Diagnostic> {
         let initializer;
         let token = self.consume(Identifier, "Expected variable name.")?;
         let var_type = if self.match_token(TokenType::Colon) {
-            Some(self.consume(TokenType::Identifier, "Expected type annotation")?)
+            Some(self.type_annotation()?)
         } else { None };
 
         if self.match_token(Equal) { initializer = self.expression()?; }
-        else { initializer = Literal { id: self.get_id(), value: LiteralValue::Nil }; }
+        else { initializer = Literal { id: self.get_id(), span: token.span, value: LiteralValue::Nil }; }
         self.consume(Semicolon, "Expected ';' after variable declaration!")?;
         Ok(Statement::Var { name: token, var_type, initializer })
     }
-    fn class_declaration(&mut self) -> Result<Statement, String> {
+    fn class_declaration(&mut self) -> Result<Statement, Diagnostic> {
         let name = self.consume(Identifier, "Expected name after 'class' keyword.")?;
-        let mut generics = Vec::new();
-        if self.match_token(TokenType::Less) {
-            loop {
-                generics.push(self.consume(Identifier, "Expected type parameter.")?);
-                if !self.match_token(Comma) { break; }
-            }
-            self.consume(Greater, "Expected '>' after type parameters.")?;
-        }
+        // `class Box<T> {` declares type parameters, while `class B < A {` names a superclass
+        let declares_generics = self.check(Less) && self.check_next(Identifier)
+            && matches!(self.tokens.get(self.current + 2).map(|t| &t.token_type), Some(Greater | Comma | Colon));
+        let generics = if declares_generics {
+            self.advance();
+            self.type_params()?
+        } else { vec![] };
         let superclass = if self.match_token(TokenType::Less) {
             self.consume(Identifier, "Expected superclass name after '<'.")?;
-            Some(Expr::Variable { id: self.get_id(), var_type: None, name: self.previous() })
+            Some(Expr::Variable { id: self.get_id(), name: self.previous() })
         } else { None };
         self.consume(LeftBrace, "Expected '{' before class body.")?;
 
@@ -88,63 +120,178 @@ impl Parser {
         self.consume(RightBrace, "Expected '}' after class body.")?;
         Ok(Statement::Class {name, generics, methods, superclass})
     }
-    fn function(&mut self, kind: FunctionKind) -> Result<Statement, String> {
+    fn function(&mut self, kind: FunctionKind) -> Result<Statement, Diagnostic> {
         let name = self.consume(Identifier, &format!("Expected {kind:?} name"))?;
-        if self.match_token(Gets) {
-            let cmd_body = self.consume(StringLit, "Expected command body")?;
-            self.consume(Semicolon, "Expected ';' after command body")?;
-            return Ok(Statement::CmdFunction { name, cmd: cmd_body.lexeme });
-        }
+        // Commands without parameters may leave out the parentheses
+        if self.check(Gets) { return self.cmd_function(name, vec![], vec![]); }
+        let generics = if self.match_token(Less) { self.type_params()? } else { vec![] };
         self.consume(LeftParen, &format!("Expected '(' after {kind:?} name"))?;
 
-        let mut generics = Vec::new();
-        if self.match_token(Less) {
-            loop {
-                generics.push(self.consume(Identifier, "Expected type parameter.")?);
-                if !self.match_token(Comma) { break; }
-            }
-            self.consume(Greater, "Expected '>' after type parameters.")?;
-        }
-        let mut parameters = vec![];
+        let (mut parameters, mut param_types) = (vec![], vec![]);
         if !self.check(RightParen) {
             loop {
                 if parameters.len() >= 255 {
                     let peek = self.tokens[self.current].clone();
-                    let location = peek.line_number;
-                    return Err(format!("Line {location}: Can't have more than 255 arguments"));
+                    return Err(self.error("E0102", &peek, "Can't have more than 255 arguments"));
                 }
                 let param = self.consume(Identifier, "Expected parameter name")?;
                 parameters.push(param);
+                param_types.push(self.parameter_type()?);
                 if !self.match_token(Comma) { break; }
             }
         }
         self.consume(RightParen, "Expected ')' after parameters")?;
-        let return_type = if self.match_token(Arrow) {
-            Some(self.consume(Identifier, "Expected return type after '->'")?)
-        } else { None };
+        if self.check(Gets) && kind == FunctionKind::Function && generics.is_empty() {
+            return self.cmd_function(name, parameters, param_types);
+        }
+        let return_type = self.return_type()?;
         
         self.consume(LeftBrace, &format!("Expected '{{' before {kind:?} body"))?;
         let body = match self.block_statement()? {
             Statement::Block { statements } => statements,
             _ => panic!("Block statement parsed something that was not a block"),
         };
-        Ok(Statement::Function { name, params: parameters, generics, return_type, body })
+        Ok(Statement::Function { name, params: parameters, param_types, generics, return_type, body })
+    }
+    fn cmd_function(
+        &mut self, name: Token, params: Vec<Token>, param_types: Vec<Option<TypeAnnotation>>
+    ) -> Result<Statement, Diagnostic> {
+        self.consume(Gets, "Expected '<-' before command")?;
+        let body = self.consume(StringLit, "Expected command body")?;
+        let source = match &body.literal {
+            Some(scanner::LiteralValue::StringValue(source)) => source.clone(),
+            _ => body.lexeme.clone(),
+        };
+        let command = CommandTemplate::parse(&source, &params)
+            .map_err(|msg| Diagnostic::error("E0104", &msg).at(body.span, "in this command"))?;
+        self.consume(Semicolon, "Expected ';' after command body")?;
+        Ok(Statement::CmdFunction { name, params, param_types, command })
+    }
+    /// Type parameters after the opening `<`, each with an optional bound: `<T, U: Comparable>`.
+    fn type_params(&mut self) -> Result<Vec<TypeParam>, Diagnostic> {
+        let mut params = vec![];
+        loop {
+            let name = self.consume(Identifier, "Expected type parameter.")?;
+            let bound = if self.match_token(Colon) { Some(self.type_annotation()?) } else { None };
+            params.push(TypeParam { name, bound });
+            if !self.match_token(Comma) { break; }
+        }
+        self.close_type_args("Expected '>' after type parameters.")?;
+        Ok(params)
+    }
+    /// Type arguments at a call site, as in `Box<int>(35)`. Since `a < b > (c)` is also a valid
+    /// comparison, this backtracks and returns `None` unless the arguments are followed by `(`.
+    fn call_type_args(&mut self) -> Option<Vec<TypeAnnotation>> {
+        let start = self.current;
+        let mut parse = || -> Result<Vec<TypeAnnotation>, Diagnostic> {
+            self.consume(Less, "Expected '<'")?;
+            let mut args = vec![];
+            loop {
+                args.push(self.type_annotation()?);
+                if !self.match_token(Comma) { break; }
+            }
+            self.close_type_args("Expected '>' after type arguments.")?;
+            let peek = self.tokens[self.current].clone();
+            if !self.check(LeftParen) { return Err(self.error("E0100", &peek, "Expected '(' after type arguments.")); }
+            Ok(args)
+        };
+        match parse() {
+            Ok(args) => Some(args),
+            Err(_) => {
+                self.rejoin_shifts(start, self.current);
+                self.current = start;
+                None
+            },
+        }
+    }
+    /// Consumes the `>` ending type arguments. The scanner reads the end of `List<List<number>>`
+    /// as a `>>`, which is split here so each list gets a `>` of its own.
+    fn close_type_args(&mut self, msg: &str) -> Result<Token, Diagnostic> {
+        if self.check(GreaterGreater) {
+            let token = self.tokens[self.current].clone();
+            let first = Token {
+                token_type: Greater, lexeme: ">".to_string(), literal: None,
+                span: Span { end: token.span.start + 1, ..token.span },
+            };
+            let second = Token { span: Span { start: first.span.end, column: token.span.column + 1, ..token.span }, ..first.clone() };
+            self.tokens.splice(self.current..=self.current, [first, second]);
+        }
+        self.consume(Greater, msg)
+    }
+    /// Undoes the splits of `close_type_args` between `start` and `end` after backtracking over
+    /// what were not type arguments. Touching `>`s can only come from a split, since the scanner
+    /// reads `>>` as one token.
+    fn rejoin_shifts(&mut self, start: usize, end: usize) {
+        let mut i = start;
+        while i <= end && i + 1 < self.tokens.len() {
+            let (first, second) = (&self.tokens[i], &self.tokens[i + 1]);
+            if first.token_type == Greater && second.token_type == Greater && first.span.end == second.span.start {
+                let span = first.span.to(second.span);
+                let joined = Token { token_type: GreaterGreater, lexeme: ">>".to_string(), literal: None, span };
+                self.tokens.splice(i..=i + 1, [joined]);
+            }
+            i += 1;
+        }
+    }
+    fn parameter_type(&mut self) -> Result<Option<TypeAnnotation>, Diagnostic> {
+        if self.match_token(Colon) { Ok(Some(self.type_annotation()?)) } else { Ok(None) }
     }
-    fn statement(&mut self) -> Result<Statement, String> {
-        if self.match_token(Print) { self.print_statement() }
+    fn return_type(&mut self) -> Result<Option<TypeAnnotation>, Diagnostic> {
+        if self.match_token(Arrow) { Ok(Some(self.type_annotation()?)) } else { Ok(None) }
+    }
+    fn type_annotation(&mut self) -> Result<TypeAnnotation, Diagnostic> {
+        let mut members = vec![self.type_primary()?];
+        while self.match_token(Bar) { members.push(self.type_primary()?); }
+        if members.len() == 1 { Ok(members.remove(0)) } else { Ok(TypeAnnotation::Union(members)) }
+    }
+    fn type_primary(&mut self) -> Result<TypeAnnotation, Diagnostic> {
+        if self.match_token(Fun) {
+            if !self.match_token(LeftParen) {
+                return Ok(TypeAnnotation::Function { params: None, ret: None });
+            }
+            let mut params = vec![];
+            if !self.check(RightParen) {
+                loop {
+                    params.push(self.type_annotation()?);
+                    if !self.match_token(Comma) { break; }
+                }
+            }
+            self.consume(RightParen, "Expected ')' after parameter types")?;
+            let ret = self.return_type()?.map(Box::new);
+            return Ok(TypeAnnotation::Function { params: Some(params), ret });
+        }
+        let name = if self.match_token(Nil) { self.previous() }
+        else { self.consume(Identifier, "Expected type annotation")? };
+        let mut args = vec![];
+        if self.match_token(Less) {
+            loop {
+                args.push(self.type_annotation()?);
+                if !self.match_token(Comma) { break; }
+            }
+            self.close_type_args("Expected '>' after type arguments")?;
+        }
+        Ok(TypeAnnotation::Named { name, args })
+    }
+    fn statement(&mut self) -> Result<Statement, Diagnostic> {
+        if self.check(Identifier) && self.check_next(Colon) { self.labelled_statement() }
+        else if self.match_token(Print) { self.print_statement() }
         else if self.match_token(LeftBrace) { self.block_statement() } 
         else if self.match_token(If) { self.if_statement() }
-        else if self.match_token(While) { self.while_statement() }
-        else if self.match_token(For) { self.for_statement() }
+        else if self.match_token(While) { self.while_statement(None) }
+        else if self.match_token(For) { self.for_statement(None) }
         else if self.match_token(Return) { self.return_statement() }
+        else if self.match_token(Break) { self.break_statement() }
+        else if self.match_token(Continue) { self.continue_statement() }
+        else if self.match_token(Throw) { self.throw_statement() }
+        else if self.match_token(Try) { self.try_statement() }
         else { self.expression_statement() }
     }
-    fn print_statement(&mut self) -> Result<Statement, String> {
+    fn print_statement(&mut self) -> Result<Statement, Diagnostic> {
         let value = self.expression()?;
         self.consume(Semicolon, "Expected ';' after value.")?;
         Ok(Statement::Print { expression: value })
     }
-    fn block_statement(&mut self) -> Result<Statement, String> {
+    fn block_statement(&mut self) -> Result<Statement, Diagnostic> {
         let mut statements = vec![];
         while !self.check(RightBrace) && !self.is_at_end() {
             let decl = self.declaration()?;
@@ -153,7 +300,7 @@ impl Parser {
         self.consume(RightBrace, "Expected '}' after a block.")?;
         Ok(Statement::Block { statements })
     }
-    fn if_statement(&mut self) -> Result<Statement, String> {
+    fn if_statement(&mut self) -> Result<Statement, Diagnostic> {
         self.consume(LeftParen, "Expected '(' after 'if'")?;
         let predicate = self.expression()?;
         self.consume(RightParen, "Expected ')' after if-predicate")?;
@@ -165,14 +312,26 @@ impl Parser {
         } else { None };
         Ok(Statement::IfStmt { predicate, then, els })
     }
-    fn while_statement(&mut self) -> Result<Statement, String> {
+    /// `name: while (...)` or `name: for (...)`, so `break name;` can leave nested loops.
+    fn labelled_statement(&mut self) -> Result<Statement, Diagnostic> {
+        let label = self.advance();
+        self.advance();
+        if self.match_token(While) { self.while_statement(Some(label)) }
+        else if self.match_token(For) { self.for_statement(Some(label)) }
+        else {
+            let peek = self.tokens[self.current].clone();
+            Err(self.error("E0103", &peek, &format!("Expected a loop after label '{}'", label.lexeme)))
+        }
+    }
+    fn while_statement(&mut self, label: Option<Token>) -> Result<Statement, Diagnostic> {
         self.consume(LeftParen, "Expected '(' after 'while'")?;
         let condition = self.expression()?;
         self.consume(RightParen, "Expected ')' after condition.")?;
         let body = Box::new(self.statement()?);
-        Ok(Statement::WhileStmt { condition, body })
+        Ok(Statement::WhileStmt { condition, body, increment: None, label })
     }
-    fn for_statement(&mut self) -> Result<Statement, String> {
+    fn for_statement(&mut self, label: Option<Token>) -> Result<Statement, Diagnostic> {
+        let keyword = self.previous();
         self.consume(LeftParen, "Expected '(' after 'for'.")?;
         let initializer;
         if self.match_token(Semicolon) { initializer = None;
@@ -197,26 +356,19 @@ impl Parser {
         } else { increment = None; }
         self.consume(RightParen, "Expected ')' after for clauses.")?;
 
+        // The increment stays on the loop itself so that `continue` still runs it
         let mut body = self.statement()?;
-        if let Some(incr) = increment {
-            body = Statement::Block {
-                statements: vec![
-                    Box::new(body),
-                    Box::new(Statement::Expression { expression: incr }),
-                ],
-            };
-        }
         let cond;
         match condition {
-            None => {cond = Expr::Literal { id: self.get_id(), value: LiteralValue::True }}
+            None => {cond = Expr::Literal { id: self.get_id(), span: keyword.span, value: LiteralValue::True }}
             Some(c) => cond = c,
         }
-        body = Statement::WhileStmt { condition: cond, body: Box::new(body) };
+        body = Statement::WhileStmt { condition: cond, body: Box::new(body), increment, label };
         if let Some(init) = initializer {
             body = Statement::Block { statements: vec![Box::new(init), Box::new(body)] };
         } Ok(body)
     }
-    fn return_statement(&mut self) -> Result<Statement, String> {
+    fn return_statement(&mut self) -> Result<Statement, Diagnostic> {
         let value;
         let keyword = self.previous();
         if !self.check(Semicolon) { value = Some(self.expression()?); }
@@ -224,75 +376,149 @@ impl Parser {
         self.consume(Semicolon, "Expected ';' after return value")?;
         Ok(Statement::ReturnStmt { keyword, value })
     }
-    fn expression_statement(&mut self) -> Result<Statement, String> {
+    fn break_statement(&mut self) -> Result<Statement, Diagnostic> {
+        let keyword = self.previous();
+        let label = if self.check(Identifier) { Some(self.advance()) } else { None };
+        self.consume(Semicolon, "Expected ';' after 'break'")?;
+        Ok(Statement::Break { keyword, label })
+    }
+    fn continue_statement(&mut self) -> Result<Statement, Diagnostic> {
+        let keyword = self.previous();
+        let label = if self.check(Identifier) { Some(self.advance()) } else { None };
+        self.consume(Semicolon, "Expected ';' after 'continue'")?;
+        Ok(Statement::Continue { keyword, label })
+    }
+    fn throw_statement(&mut self) -> Result<Statement, Diagnostic> {
+        let keyword = self.previous();
+        let value = self.expression()?;
+        self.consume(Semicolon, "Expected ';' after thrown value")?;
+        Ok(Statement::Throw { keyword, value })
+    }
+    fn try_statement(&mut self) -> Result<Statement, Diagnostic> {
+        let keyword = self.previous();
+        self.consume(LeftBrace, "Expected '{' after 'try'")?;
+        let try_block = Box::new(self.block_statement()?);
+        let (mut catch_name, mut catch_block) = (None, None);
+        if self.match_token(Catch) {
+            self.consume(LeftParen, "Expected '(' after 'catch'")?;
+            catch_name = Some(self.consume(Identifier, "Expected error variable name")?);
+            self.consume(RightParen, "Expected ')' after error variable")?;
+            self.consume(LeftBrace, "Expected '{' after catch clause")?;
+            catch_block = Some(Box::new(self.block_statement()?));
+        }
+        let finally_block = if self.match_token(Finally) {
+            self.consume(LeftBrace, "Expected '{' after 'finally'")?;
+            Some(Box::new(self.block_statement()?))
+        } else { None };
+        if catch_block.is_none() && finally_block.is_none() {
+            let peek = self.tokens[self.current].clone();
+            return Err(self.error("E0100", &peek, "Expected 'catch' or 'finally' after try block")
+                .with_label(keyword.span, "try block starts here"));
+        }
+        Ok(Statement::TryStmt { try_block, catch_name, catch_block, finally_block })
+    }
+    fn expression_statement(&mut self) -> Result<Statement, Diagnostic> {
         let expr = self.expression()?;
         self.consume(Semicolon, "Expected ';' after expression.")?;
         Ok(Statement::Expression { expression: expr })
     }
-    fn expression(&mut self) -> Result<Expr, String> {
+    fn expression(&mut self) -> Result<Expr, Diagnostic> {
         self.assignment()
     }
-    fn function_expression(&mut self) -> Result<Expr, String> {
+    fn function_expression(&mut self) -> Result<Expr, Diagnostic> {
+        let generics = if self.match_token(Less) { self.type_params()? } else { vec![] };
         let paren = self.consume(LeftParen, "Expected '(' after anonymous function")?;
-        let mut generics = Vec::new();
-        if self.match_token(Less) {
-            loop {
-                generics.push(self.consume(Identifier, "Expected type parameter.")?);
-                if !self.match_token(Comma) {
-                    break;
-                }
-            }
-            self.consume(Greater, "Expected '>' after type parameters.")?;
-        }
-        let mut parameters = vec![];
+        let (mut parameters, mut param_types) = (vec![], vec![]);
         if !self.check(RightParen) {
             loop {
                 if parameters.len() >= 255 {
                     let peek = self.tokens[self.current].clone();
-                    let location = peek.line_number;
-                    return Err(format!("Line {location}: Can't have more than 255 arguments"));
+                    return Err(self.error("E0102", &peek, "Can't have more than 255 arguments"));
                 }
                 let param = self.consume(Identifier, "Expected parameter name")?;
                 parameters.push(param);
+                param_types.push(self.parameter_type()?);
                 if !self.match_token(Comma) { break; }
             }
         }
-        let return_type = if self.match_token(Greater) {
-            Some(self.consume(Identifier, "Expected return type after '->'")?)
-        } else { None };
-
         self.consume(RightParen, "Expected ')' after anonymous function parameters")?;
+        let return_type = self.return_type()?;
         self.consume(LeftBrace, "Expected '{' after anonymous function declaration")?;
         let body = match self.block_statement()? {
             Statement::Block { statements } => statements,
             _ => panic!("Block statement parsed something that was not a block")
         };
-        Ok(Expr::AnonFunction { id: self.get_id(), paren, generics, arguments: parameters, return_type, body })
-    }
-    fn assignment(&mut self) -> Result<Expr, String> {
-        let expr = self.pipe()?;
-        if self.match_token(Equal) {
+        Ok(Expr::AnonFunction { id: self.get_id(), paren, generics, arguments: parameters, param_types, return_type, body })
+    }
+    /// `x op= y` becomes `x = x op y` for variables. Properties and indexes keep the operator,
+    /// so their object and index are evaluated only once.
+    fn assignment(&mut self) -> Result<Expr, Diagnostic> {
+        let expr = self.conditional()?;
+        if self.match_tokens(&[Equal, PlusEqual, MinusEqual, StarEqual, SlashEqual, PercentEqual]) {
+            let assign = self.previous();
+            let operator = match assign.token_type {
+                PlusEqual => Some(Plus),
+                MinusEqual => Some(Minus),
+                StarEqual => Some(Star),
+                SlashEqual => Some(Slash),
+                PercentEqual => Some(Percent),
+                _ => None,
+            }.map(|token_type| Token { token_type, ..assign });
             let value = self.expression()?;
             match expr {
-                Variable { id: _, var_type: _, name } => { Ok(Assign { id: self.get_id(), name, value: Box::from(value) }) },
-                Get { id: _, object, name } => { Ok(Set { id: self.get_id(), object, name, value: Box::new(value) }) }
-                _ => Err("Invalid assignment target!".to_string())
+                Variable { id: _, name } => {
+                    let value = match operator {
+                        Some(operator) => Binary {
+                            id: self.get_id(), operator, right: Box::new(value),
+                            left: Box::new(Variable { id: self.get_id(), name: name.clone() }),
+                        },
+                        None => value,
+                    };
+                    Ok(Assign { id: self.get_id(), name, value: Box::from(value) })
+                },
+                Get { id: _, object, name } => { Ok(Set { id: self.get_id(), object, name, operator, value: Box::new(value) }) }
+                Index { id: _, object, bracket, index } => {
+                    Ok(IndexSet { id: self.get_id(), object, bracket, index, operator, value: Box::new(value) })
+                }
+                target => Err(Diagnostic::error("E0101", "Invalid assignment target")
+                    .at(target.span(), "cannot assign to this"))
             }
         } else { Ok(expr) }
     }
-    fn pipe(&mut self) -> Result<Expr, String> {
+    /// `cond ? a : b`, right associative so `a ? b : c ? d : e` picks the first true condition.
+    fn conditional(&mut self) -> Result<Expr, Diagnostic> {
+        let condition = self.pipe()?;
+        if !self.match_token(Question) { return Ok(condition); }
+        let keyword = self.previous();
+        let then_branch = self.expression()?;
+        self.consume(Colon, "Expected ':' after the first branch of '?'")?;
+        let else_branch = self.conditional()?;
+        Ok(Conditional {
+            id: self.get_id(), keyword, condition: Box::new(condition),
+            then_branch: Box::new(then_branch), else_branch: Box::new(else_branch),
+        })
+    }
+    fn pipe(&mut self) -> Result<Expr, Diagnostic> {
         let mut expr = self.or()?;
         while self.match_token(Pipe) {
             let pipe = self.previous();
             let function = self.or()?;
-            expr = Call {
-                id: self.get_id(), callee: Box::new(function),
-                paren: pipe, arguments: vec![expr], generics: vec![],
+            let piped = Piped { id: self.get_id(), pipe: pipe.clone(), value: Box::new(expr) };
+            // `x |> f(a)` becomes `f(x, a)`, anything else is called with the piped value alone
+            expr = match function {
+                Call { id, callee, paren, mut arguments, generics } => {
+                    arguments.insert(0, piped);
+                    Call { id, callee, paren, arguments, generics }
+                },
+                function => Call {
+                    id: self.get_id(), callee: Box::new(function),
+                    paren: pipe, arguments: vec![piped], generics: vec![],
+                },
             };
         }
         Ok(expr)
     }
-    fn or(&mut self) -> Result<Expr, String> {
+    fn or(&mut self) -> Result<Expr, Diagnostic> {
         let mut expr = self.and()?;
         while self.match_token(Or) {
             let operator = self.previous();
@@ -304,7 +530,7 @@ impl Parser {
         }
         Ok(expr)
     }
-    fn and(&mut self) -> Result<Expr, String> {
+    fn and(&mut self) -> Result<Expr, Diagnostic> {
         let mut expr = self.equality()?;
         while self.match_token(And) {
             let operator = self.previous();
@@ -316,68 +542,79 @@ impl Parser {
         }
         Ok(expr)
     }
-    fn equality(&mut self) -> Result<Expr, String> {
-        let mut expr = self.comparison()?;
-        while self.match_tokens(&[BangEqual, EqualEqual]) {
-            let operator = self.previous();
-            let rhs = self.comparison()?;
-            expr = Binary {
-                id: self.get_id(), left: Box::from(expr), 
-                operator: operator, right: Box::from(rhs), 
-            };
-        }
-        Ok(expr)
-    }
-    fn comparison(&mut self) -> Result<Expr, String> {
-        let mut expr = self.term()?;
-        while self.match_tokens(&[Greater, GreaterEqual, Less, LessEqual]) {
-            let operator = self.previous();
-            let rhs = self.term()?;
-            expr = Binary {
-                id: self.get_id(),
-                left: Box::from(expr),
-                operator: operator,
-                right: Box::from(rhs),
-            }
-        }
-        Ok(expr)
-    }
-    fn term(&mut self) -> Result<Expr, String> {
-        let mut expr = self.factor()?;
-        while self.match_tokens(&[Minus, Plus]) {
-            let operator = self.previous();
-            let rhs = self.factor()?;
-            expr = Binary {
-                id: self.get_id(),
-                left: Box::from(expr), 
-                operator: operator,
-                right: Box::from(rhs),
-            };
-        }
-        Ok(expr)
-    }
-    fn factor(&mut self) -> Result<Expr, String> {
-        let mut expr = self.unary()?;
-        while self.match_tokens(&[Slash, Star]) {
+    /// Binary operators bind, loosest first: `== !=`, then `< <= > >=`, `|`, `^`, `&`,
+    /// `<< >>`, `+ -`, `* / ~/ %`, the prefix operators `! - ~ ++ --`, `**`, and finally postfix `++ --`.
+    /// All are left-associative except `**`, so `2 ** 3 ** 2` is `2 ** 9` and `-2 ** 2` is -4.
+    fn equality(&mut self) -> Result<Expr, Diagnostic> {
+        self.left_associative(&[BangEqual, EqualEqual], Self::comparison)
+    }
+    fn comparison(&mut self) -> Result<Expr, Diagnostic> {
+        self.left_associative(&[Greater, GreaterEqual, Less, LessEqual], Self::bit_or)
+    }
+    fn bit_or(&mut self) -> Result<Expr, Diagnostic> { self.left_associative(&[Bar], Self::bit_xor) }
+    fn bit_xor(&mut self) -> Result<Expr, Diagnostic> { self.left_associative(&[Caret], Self::bit_and) }
+    fn bit_and(&mut self) -> Result<Expr, Diagnostic> { self.left_associative(&[Ampersand], Self::shift) }
+    fn shift(&mut self) -> Result<Expr, Diagnostic> { self.left_associative(&[LessLess, GreaterGreater], Self::term) }
+    fn term(&mut self) -> Result<Expr, Diagnostic> { self.left_associative(&[Minus, Plus], Self::factor) }
+    fn factor(&mut self) -> Result<Expr, Diagnostic> {
+        self.left_associative(&[Slash, Star, TildeSlash, Percent], Self::unary)
+    }
+    /// One precedence level: operands parsed by `operand`, joined by any of `operators`.
+    fn left_associative(
+        &mut self, operators: &[TokenType], operand: fn(&mut Self) -> Result<Expr, Diagnostic>
+    ) -> Result<Expr, Diagnostic> {
+        let mut expr = operand(self)?;
+        while self.match_tokens(operators) {
             let operator = self.previous();
-            let rhs = self.unary()?;
+            let rhs = operand(self)?;
             expr = Binary {
                 id: self.get_id(),
                 left: Box::from(expr),
-                operator: operator, 
+                operator,
                 right: Box::from(rhs),
             };
         }
         Ok(expr)
     }
-    fn unary(&mut self) -> Result<Expr, String> {
-        if self.match_tokens(&[Bang, Minus]) {
+    fn unary(&mut self) -> Result<Expr, Diagnostic> {
+        if self.match_tokens(&[Bang, Minus, Tilde]) {
             let operator = self.previous();
             let rhs = self.unary()?;
             Ok(Unary { id: self.get_id(), operator: operator, right: Box::from(rhs) })
-        } else { self.call() }
+        } else if self.match_tokens(&[PlusPlus, MinusMinus]) {
+            let operator = self.previous();
+            let target = self.unary()?;
+            self.update(operator, target, true)
+        } else { self.power() }
+    }
+    fn power(&mut self) -> Result<Expr, Diagnostic> {
+        let expr = self.postfix()?;
+        if !self.match_token(StarStar) { return Ok(expr); }
+        let operator = self.previous();
+        // The exponent may have a sign of its own, and a further `**` makes it right-associative
+        let rhs = self.unary()?;
+        Ok(Binary { id: self.get_id(), left: Box::from(expr), operator, right: Box::from(rhs) })
+    }
+    fn postfix(&mut self) -> Result<Expr, Diagnostic> {
+        let expr = self.call()?;
+        if !self.match_tokens(&[PlusPlus, MinusMinus]) { return Ok(expr); }
+        let operator = self.previous();
+        self.update(operator, expr, false)
+    }
+    /// `++` or `--` on a variable, property or index. Like `+=`, the operator becomes the
+    /// arithmetic applied to the old value, and the object and index are evaluated only once.
+    fn update(&mut self, operator: Token, target: Expr, prefix: bool) -> Result<Expr, Diagnostic> {
+        match target {
+            Variable { .. } | Get { .. } | Index { .. } => {
+                let token_type = if operator.token_type == PlusPlus { Plus } else { Minus };
+                let operator = Token { token_type, ..operator };
+                Ok(Update { id: self.get_id(), operator, prefix, target: Box::new(target) })
+            },
+            target => Err(Diagnostic::error("E0101", "Invalid assignment target")
+                .at(target.span(), &format!("cannot apply {} to this", operator.lexeme)))
+        }
     }
-    fn call(&mut self) -> Result<Expr, String> {
+    fn call(&mut self) -> Result<Expr, Diagnostic> {
         let mut expr = self.primary()?;
         loop {
             if self.match_token(LeftParen) { expr = self.finish_call(expr)?; }
@@ -385,22 +622,16 @@ impl Parser {
                 let name = self.consume(Identifier, "Expected token after dot-accessor")?;
                 expr = Get { id: self.get_id(), object: Box::new(expr), name };
             }
+            else if self.match_token(LeftBracket) { expr = self.finish_subscript(expr)?; }
             else { break; }
         }
         Ok(expr)
     }
-    fn finish_call(&mut self, callee: Expr) -> Result<Expr, String> {
+    fn finish_call(&mut self, callee: Expr) -> Result<Expr, Diagnostic> {
+        self.finish_generic_call(callee, vec![])
+    }
+    fn finish_generic_call(&mut self, callee: Expr, generics: Vec<TypeAnnotation>) -> Result<Expr, Diagnostic> {
         let mut arguments = vec![];
-        let mut generics = vec![];
-
-        if self.match_token(Less) {
-            loop {
-                let generic = self.consume(Identifier, "Expected type parameter.")?;
-                generics.push(generic);
-                if !self.match_token(Comma) { break; }
-            }
-            self.consume(Greater, "Expected '>' after type parameters.")?;
-        }
         if !self.check(RightParen) {
             loop {
                 let arg = self.expression()?;
@@ -408,8 +639,7 @@ impl Parser {
 
                 if arguments.len() >= 255 {
                     let peek = self.tokens[self.current].clone();
-                    let location = peek.line_number;
-                    return Err(format!("Line {location}: Can't have more than 255 arguments"));
+                    return Err(self.error("E0102", &peek, "Can't have more than 255 arguments"));
                 }
                 if !self.match_token(Comma) { break; }
             }
@@ -417,7 +647,42 @@ impl Parser {
         let paren = self.consume(RightParen, "Expected ')' after arguments.")?;
         Ok(Call { id: self.get_id(), callee: Box::new(callee), paren, arguments, generics })
     }
-    fn primary(&mut self) -> Result<Expr, String> {
+    fn finish_subscript(&mut self, object: Expr) -> Result<Expr, Diagnostic> {
+        let bracket = self.previous();
+        let start = if self.check(Colon) { None } else { Some(Box::new(self.expression()?)) };
+        if self.match_token(Colon) {
+            let end = if self.check(RightBracket) { None } else { Some(Box::new(self.expression()?)) };
+            self.consume(RightBracket, "Expected ']' after slice.")?;
+            return Ok(Slice { id: self.get_id(), object: Box::new(object), bracket, start, end });
+        }
+        self.consume(RightBracket, "Expected ']' after index.")?;
+        let index = start.expect("Index without a colon always has a start expression");
+        Ok(Index { id: self.get_id(), object: Box::new(object), bracket, index })
+    }
+    fn list_literal(&mut self) -> Result<Expr, Diagnostic> {
+        let bracket = self.previous();
+        let mut elements = vec![];
+        while !self.check(RightBracket) && !self.is_at_end() {
+            elements.push(self.expression()?);
+            if !self.match_token(Comma) { break; }
+        }
+        self.consume(RightBracket, "Expected ']' after list elements.")?;
+        Ok(Expr::List { id: self.get_id(), bracket, elements })
+    }
+    fn map_literal(&mut self) -> Result<Expr, Diagnostic> {
+        let brace = self.previous();
+        let mut entries = vec![];
+        while !self.check(RightBrace) && !self.is_at_end() {
+            let key = self.expression()?;
+            self.consume(Colon, "Expected ':' after map key.")?;
+            let value = self.expression()?;
+            entries.push((key, value));
+            if !self.match_token(Comma) { break; }
+        }
+        self.consume(RightBrace, "Expected '}' after map entries.")?;
+        Ok(Expr::Map { id: self.get_id(), brace, entries })
+    }
+    fn primary(&mut self) -> Result<Expr, Diagnostic> {
         let result;
         let token = self.tokens[self.current].clone();
         match token.token_type {
@@ -429,73 +694,49 @@ impl Parser {
             },
             False | True | Nil | Number | StringLit => {
                 self.advance();
-                result = Literal { id: self.get_id(), value: LiteralValue::from_token(token) }
+                let value = LiteralValue::from_token(token.clone())
+                    .map_err(|e| Diagnostic::error("E0100", &e.to_string()).at(token.span, ""))?;
+                result = Literal { id: self.get_id(), span: token.span, value }
+            },
+            Interpolation => {
+                self.advance();
+                result = self.interpolation(token)?;
             },
             Identifier => {
                 self.advance();
-                if self.match_token(Less) {
-                    let mut generics = Vec::new();
-                    loop {
-                        generics.push(self.consume(Identifier, "Expected type parameter.")?);
-                        if !self.match_token(Comma) { break; }
-                    }
-                    self.consume(Greater, "Expected '>' after type parameters.")?;
-                    if self.match_token(LeftParen) {
-                        // It's a function call with generics
-                        let paren = self.previous(); // Capture the '(' token
-                        let mut arguments = vec![];
-                        if !self.check(RightParen) {
-                            loop {
-                                let arg = self.expression()?;
-                                arguments.push(arg);
-                                if !self.match_token(Comma) { break; }
-                            }
-                        }
-                        self.consume(RightParen, "Expected ')' after arguments.")?;
-                        result = Expr::Call {
-                            id: self.get_id(),
-                            callee: Box::new(Expr::Variable {
-                                id: self.get_id(),
-                                var_type: None, name: token 
-                            }),
-                            paren, arguments,
-                            generics
-                        };
-                    } else {
-                        // It's a generic class instantiation
-                        self.consume(LeftParen, "Expected '(' after type parameters")?;
-                        let paren = self.previous(); // Capture the '(' token
-                        let mut arguments = vec![];
-                        if !self.check(RightParen) {
-                            loop {
-                                let arg = self.expression()?;
-                                arguments.push(arg);
-                                if !self.match_token(Comma) { break; }
-                            }
-                        }
-                        self.consume(RightParen, "Expected ')' after arguments.")?;
-                        result = Expr::Call {
-                            id: self.get_id(),
-                            callee: Box::new(Expr::Variable {
-                                id: self.get_id(),
-                                var_type: None, name: token
-                            }),
-                            paren,
-                            arguments,
-                            generics
-                        };
-                    }
-                } else {
-                    result = Expr::Variable {
-                        id: self.get_id(), var_type: None,
-                        name: self.previous(),
-                    };
-                }
+                let variable = Expr::Variable { id: self.get_id(), name: token };
+                if let Some(generics) = self.call_type_args() {
+                    self.advance();
+                    result = self.finish_generic_call(variable, generics)?;
+                } else { result = variable; }
+            },
+            LeftBracket => {
+                self.advance();
+                result = self.list_literal()?;
+            },
+            // A '{' in statement position is always a block, so here it can only start a map
+            LeftBrace => {
+                self.advance();
+                result = self.map_literal()?;
             },
             Fun => {
                 self.advance();
                 result = self.function_expression()?;
             },
+            // `if` only reaches here where a statement cannot start, so it must have a value
+            If => {
+                self.advance();
+                self.consume(LeftParen, "Expected '(' after 'if'")?;
+                let condition = self.expression()?;
+                self.consume(RightParen, "Expected ')' after if condition")?;
+                let then_branch = self.expression()?;
+                self.consume(Else, "Expected 'else', an if expression needs a value for both branches")?;
+                let else_branch = self.expression()?;
+                result = Conditional {
+                    id: self.get_id(), keyword: token, condition: Box::new(condition),
+                    then_branch: Box::new(then_branch), else_branch: Box::new(else_branch),
+                };
+            },
             TokenType::This => {
                 self.advance();
                 result = Expr::This { id: self.get_id(), keyword: token };
@@ -506,29 +747,62 @@ impl Parser {
                 let method = self.consume(TokenType::Identifier, "Expected superclass method name")?;
                 result = Expr::Super { id: self.get_id(), keyword: token, method };
             }
-            _ => return Err("Expected expression".to_string()),
+            _ => return Err(self.error("E0100", &token, "Expected expression")),
         }
         Ok(result)
     }
-    fn consume(&mut self, token_type: TokenType, msg: &str) -> Result<Token, String> {
+    /// The rest of a string after its first `${`. The scanner already split it into the text
+    /// before each `${`, the tokens of each expression, and the text after the last `}`.
+    fn interpolation(&mut self, first: Token) -> Result<Expr, Diagnostic> {
+        let mut parts = vec![];
+        let mut segment = first.clone();
+        loop {
+            if let Some(scanner::LiteralValue::StringValue(text)) = &segment.literal {
+                if !text.is_empty() {
+                    let value = LiteralValue::StringValue(text.clone());
+                    parts.push(Literal { id: self.get_id(), span: segment.span, value });
+                }
+            }
+            if segment.token_type == StringLit { break; }
+            parts.push(self.expression()?);
+            segment = self.tokens[self.current].clone();
+            if !matches!(segment.token_type, Interpolation | StringLit) {
+                return Err(self.error("E0100", &segment, "Expected '}' after interpolated expression"));
+            }
+            self.advance();
+        }
+        Ok(Expr::Interpolated { id: self.get_id(), span: first.span.to(segment.span), parts })
+    }
+    fn consume(&mut self, token_type: TokenType, msg: &str) -> Result<Token, Diagnostic> {
         let token = self.tokens[self.current].clone();
         if token.token_type == token_type {
             self.advance();
             let token = self.previous();
             Ok(token)
-        } else { Err(format!("Line {}: {}", token.line_number, msg)) }
+        } else { Err(self.error("E0100", &token, msg)) }
     }
     fn check(&mut self, typ: TokenType) -> bool {
         let token = self.tokens[self.current].clone();
         token.token_type == typ
     }
+    /// Whether the next token is the identifier `word`, for keywords that only mean something in context.
+    fn check_contextual(&self, word: &str) -> bool {
+        let token = &self.tokens[self.current];
+        token.token_type == Identifier && token.lexeme == word
+    }
+    fn check_next(&mut self, typ: TokenType) -> bool {
+        match self.tokens.get(self.current + 1) {
+            Some(token) => token.token_type == typ,
+            None => false,
+        }
+    }
     fn synchronize(&mut self) {
         self.advance();
         while !self.is_at_end() {
             if self.previous().token_type == Semicolon { return; }
             let peek = self.tokens[self.current].clone();
             match peek.token_type {
-                Class | Fun | Var | For | If | While | Print | Return => return,
+                Class | Fun | Var | For | If | While | Print | Return | Throw | Try | Import => return,
                 _ => (),
             }
             self.advance();
@@ -0,0 +1,124 @@
+use crate::error::RuntimeError;
+
+use std::path::{Component, Path, PathBuf};
+use std::{env, fs};
+
+/// Which programs or paths a capability covers.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Access {
+    All,
+    /// Programs by the name they are run with, or paths along with everything below them.
+    Only(Vec<String>),
+}
+/// What a program may do outside the interpreter: run commands, read files and write files.
+/// Everything is allowed by default, so scripts from elsewhere should run with less.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Permissions {
+    pub run: Access,
+    pub read: Access,
+    pub write: Access,
+}
+impl Permissions {
+    pub fn allow_all() -> Self { Self { run: Access::All, read: Access::All, write: Access::All } }
+    pub fn deny_all() -> Self {
+        Self { run: Access::Only(vec![]), read: Access::Only(vec![]), write: Access::Only(vec![]) }
+    }
+    /// Takes the permission flags out of command line arguments. `--deny-all` starts from nothing
+    /// allowed, and `--allow-run=a,b`, `--allow-read=...` and `--allow-write=...` allow only what
+    /// they list, or everything when given without a list.
+    pub fn from_args(args: &mut Vec<String>) -> Result<Self, String> {
+        let mut permissions = if args.iter().any(|arg| arg == "--deny-all") { Self::deny_all() } else { Self::allow_all() };
+        // Repeating a flag adds to its list
+        let mut given: [Option<Access>; 3] = [None, None, None];
+        for arg in args.iter().filter(|arg| arg.starts_with("--allow-")) {
+            let (flag, list) = arg.split_once('=').map_or((arg.as_str(), None), |(flag, list)| (flag, Some(list)));
+            let index = ["--allow-run", "--allow-read", "--allow-write"].iter().position(|name| *name == flag)
+                .ok_or_else(|| format!("Unknown permission flag '{}'", flag))?;
+            given[index] = match (given[index].take(), list) {
+                (_, None) | (Some(Access::All), _) => Some(Access::All),
+                (previous, Some(list)) => {
+                    let mut items = match previous { Some(Access::Only(items)) => items, _ => vec![] };
+                    items.extend(list.split(',').filter(|item| !item.is_empty()).map(str::to_string));
+                    Some(Access::Only(items))
+                },
+            };
+        }
+        let [run, read, write] = given;
+        if let Some(run) = run { permissions.run = run; }
+        if let Some(read) = read { permissions.read = read; }
+        if let Some(write) = write { permissions.write = write; }
+        args.retain(|arg| arg != "--deny-all" && !arg.starts_with("--allow-"));
+        Ok(permissions)
+    }
+    pub fn check_run(&self, program: &str) -> Result<(), RuntimeError> {
+        match &self.run {
+            Access::Only(programs) if !programs.iter().any(|allowed| allowed == program) => Err(denied("run", program)),
+            _ => Ok(()),
+        }
+    }
+    pub fn check_read(&self, path: &str) -> Result<(), RuntimeError> { check_path(&self.read, "read", path) }
+    pub fn check_write(&self, path: &str) -> Result<(), RuntimeError> { check_path(&self.write, "write", path) }
+}
+impl Default for Permissions {
+    fn default() -> Self { Self::allow_all() }
+}
+fn denied(capability: &str, target: &str) -> RuntimeError {
+    format!("Permission denied: {} access to '{}' (allow it with --allow-{})", capability, target, capability).into()
+}
+fn check_path(access: &Access, capability: &str, path: &str) -> Result<(), RuntimeError> {
+    let Access::Only(allowed) = access else { return Ok(()) };
+    let target = resolve(Path::new(path));
+    if allowed.iter().any(|allowed| target.starts_with(resolve(Path::new(allowed)))) { Ok(()) }
+    else { Err(denied(capability, path)) }
+}
+/// The absolute path a path names, with symlinks followed so a link cannot lead out of an
+/// allowed directory. A file about to be written may not exist yet, but its directory should.
+fn resolve(path: &Path) -> PathBuf {
+    let path = env::current_dir().unwrap_or_default().join(path);
+    if let Ok(path) = fs::canonicalize(&path) { return path; }
+    match (path.parent().map(fs::canonicalize), path.file_name()) {
+        (Some(Ok(dir)), Some(name)) => dir.join(name),
+        _ => normalize(&path),
+    }
+}
+/// Removes `.` and `..` without touching the filesystem, for paths that do not exist,
+/// so `allowed/missing/../../secret` is not mistaken for a path inside `allowed`.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normal = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => { normal.pop(); },
+            other => normal.push(other),
+        }
+    }
+    normal
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn args(args: &[&str]) -> Vec<String> { args.iter().map(|arg| arg.to_string()).collect() }
+    #[test]
+    fn parses_flags() {
+        let mut given = args(&["cli", "--deny-all", "--allow-run=ls,grep", "--allow-read", "script.fsc"]);
+        let permissions = Permissions::from_args(&mut given).unwrap();
+        assert_eq!(given, args(&["cli", "script.fsc"]));
+        assert_eq!(permissions.run, Access::Only(vec!["ls".to_string(), "grep".to_string()]));
+        assert_eq!(permissions.read, Access::All);
+        assert_eq!(permissions.write, Access::Only(vec![]));
+        let permissions = Permissions::from_args(&mut args(&["cli", "--allow-write=out"])).unwrap();
+        assert_eq!((permissions.run, permissions.write), (Access::All, Access::Only(vec!["out".to_string()])));
+        assert!(Permissions::from_args(&mut args(&["--allow-net"])).is_err());
+    }
+    #[test]
+    fn checks_paths_below_allowed_directories() {
+        let permissions = Permissions { read: Access::Only(vec!["src".to_string()]), ..Permissions::deny_all() };
+        assert!(permissions.check_read("src/lang/lib.rs").is_ok());
+        assert!(permissions.check_read("./src/missing.csv").is_ok());
+        assert!(permissions.check_read("src/missing/../../Cargo.toml").is_err());
+        assert!(permissions.check_read("Cargo.toml").is_err());
+        let error = permissions.check_write("src/out.csv").unwrap_err();
+        assert_eq!(error.message, "Permission denied: write access to 'src/out.csv' (allow it with --allow-write)");
+        assert!(permissions.check_run("ls").is_err());
+    }
+}
@@ -1,7 +1,7 @@
-use crate::expr::{Expr, LiteralValue};
+use crate::expr::Expr;
 use crate::scanner::Token;
-use crate::type_::Type;
 use crate::statements::Statement;
+use crate::diagnostic::{Diagnostic, Span};
 use std::collections::HashMap;
 
 // use std::cell::RefCell;
@@ -9,12 +9,19 @@ use std::collections::HashMap;
 
 #[derive(Copy, Clone, PartialEq)]
 enum FunctionType { None, Function, Method }
+/// A name declared in a local scope, the slot it gets in that scope's environment and where
+/// it was declared.
+#[derive(Copy, Clone)]
+struct Binding { defined: bool, slot: usize, span: Span }
 
 #[allow(dead_code)]
 pub struct Resolver {
-    scopes: Vec<HashMap<String, bool>>,
+    scopes: Vec<HashMap<String, Binding>>,
     current_function: FunctionType,
-    locals: HashMap<usize, usize>,
+    /// Labels of the loops enclosing the statement being resolved, innermost last.
+    loops: Vec<Option<String>>,
+    /// Expression id to the `(distance, slot)` of the local it refers to.
+    locals: HashMap<usize, (usize, usize)>,
 }
 impl Resolver {
     #[allow(dead_code)]
@@ -22,62 +29,112 @@ impl Resolver {
         Self {
             scopes: vec![],
             current_function: FunctionType::None,
+            loops: vec![],
             locals: HashMap::new(),
         }
     }
     #[allow(dead_code)]
-    pub fn resolve(mut self, statements: &Vec<&Statement>) -> Result<HashMap<usize, usize>, String> {
-        self.resolve_many(statements)?; Ok(self.locals)
+    /// Resolves every top-level statement, reporting the first error in each of them.
+    pub fn resolve(mut self, statements: &Vec<&Statement>) -> Result<HashMap<usize, (usize, usize)>, Vec<Diagnostic>> {
+        let mut errors = vec![];
+        for statement in statements {
+            if let Err(diagnostic) = self.resolve_internal(statement) {
+                errors.push(diagnostic);
+                // The statement was left halfway, so start the next one from the top level
+                self.scopes.clear();
+                self.loops.clear();
+                self.current_function = FunctionType::None;
+            }
+        }
+        if errors.is_empty() { Ok(self.locals) } else { Err(errors) }
     }
-    fn resolve_internal(&mut self, statement: &Statement) -> Result<(), String> {
+    fn resolve_internal(&mut self, statement: &Statement) -> Result<(), Diagnostic> {
         match statement {
             Statement::Block { statements: _ } => self.resolve_block(statement)?,
             Statement::Var { name: _, var_type: _, initializer: _ } => self.resolve_var(statement)?,
-            Statement::Class { name, generics, methods, superclass } => { 
+            Statement::Class { name, generics: _, methods, superclass } => { 
                 if let Some(super_expr) = superclass {
-                    if let Expr::Variable { id: _, var_type: _, name: super_name } = super_expr {
+                    if let Expr::Variable { id: _, name: super_name } = super_expr {
                         if super_name.lexeme == name.lexeme {
-                            return Err("A class cannot inherit from itself".to_string());
+                            return Err(Diagnostic::error("E0201", "A class cannot inherit from itself")
+                                .at(super_name.span, "").with_label(name.span, "class declared here"));
                         }
                     }
-                    self.resolve_expr(super_expr)?; self.begin_scope();
-                    self.scopes.last_mut().unwrap().insert("super".to_string(), true);
-                }
-                self.declare(name)?; self.define(name); self.begin_scope();
-                self.scopes.last_mut().unwrap().insert("this".to_string(), true);
-                for generic in generics {
-                    self.declare(generic)?;
-                    self.define(generic);
+                    self.resolve_expr(super_expr)?;
                 }
+                self.declare(name)?; self.define(name);
+                // The class scope holds `super`, the scope inside it `this`. Type parameters
+                // only exist for the checker.
+                self.begin_scope();
+                if superclass.is_some() { self.define_name("super", Span::default()); }
+                self.begin_scope();
+                self.define_name("this", Span::default());
                 for method in methods {
                     let declaration = FunctionType::Method;
                     self.resolve_function(method, declaration)?;
                 }
                 self.end_scope();
-                if superclass.is_some() {
-                    self.end_scope();
-                }
+                self.end_scope();
              },
-            Statement::Function { name: _, params: _, generics: _, return_type: _, body: _ } => self.resolve_function(statement, FunctionType::Function)?,
+            Statement::Function { name: _, params: _, param_types: _, generics: _, return_type: _, body: _ } => self.resolve_function(statement, FunctionType::Function)?,
             Statement::Expression { expression } => self.resolve_expr(expression)?,
             Statement::IfStmt { predicate: _, then: _, els: _ } => self.resolve_if_stmt(statement)?,
             Statement::Print { expression } => self.resolve_expr(expression)?,
-            Statement::ReturnStmt { keyword: _, value } => {
-                if self.current_function == FunctionType::None { return Err("Return statement is not allowed outside of a function".to_string()); }
+            Statement::ReturnStmt { keyword, value } => {
+                if self.current_function == FunctionType::None {
+                    return Err(Diagnostic::error("E0202", "Return statement is not allowed outside of a function")
+                        .at(keyword.span, ""));
+                }
                 if let Some(value) = value { self.resolve_expr(value)?; }
             },
-            Statement::WhileStmt { condition, body } => {
+            Statement::Throw { keyword: _, value } => self.resolve_expr(value)?,
+            Statement::TryStmt { try_block, catch_name, catch_block, finally_block } => {
+                self.resolve_internal(try_block.as_ref())?;
+                if let (Some(name), Some(catch_block)) = (catch_name, catch_block) {
+                    // The error variable lives in its own scope around the catch block
+                    self.begin_scope();
+                    self.declare(name)?; self.define(name);
+                    self.resolve_internal(catch_block.as_ref())?;
+                    self.end_scope();
+                }
+                if let Some(finally_block) = finally_block {
+                    self.resolve_internal(finally_block.as_ref())?;
+                }
+            },
+            Statement::WhileStmt { condition, body, increment, label } => {
                 self.resolve_expr(condition)?;
-                self.resolve_internal(body.as_ref())?;
+                self.loops.push(label.as_ref().map(|l| l.lexeme.clone()));
+                let result = self.resolve_internal(body.as_ref());
+                self.loops.pop(); result?;
+                if let Some(increment) = increment { self.resolve_expr(increment)?; }
+            },
+            Statement::Break { keyword, label } | Statement::Continue { keyword, label } => {
+                if self.loops.is_empty() {
+                    return Err(Diagnostic::error("E0203", &format!("'{}' is not allowed outside of a loop", keyword.lexeme))
+                        .at(keyword.span, ""));
+                }
+                if let Some(label) = label {
+                    if !self.loops.contains(&Some(label.lexeme.clone())) {
+                        return Err(Diagnostic::error("E0204", &format!("No enclosing loop labelled '{}'", label.lexeme))
+                            .at(label.span, "unknown label"));
+                    }
+                }
+            },
+            Statement::CmdFunction { .. } => self.resolve_var(statement)?,
+            Statement::Import { keyword, path: _, alias: _, names: _ } => {
+                // Imported names are globals, so they can only be bound at the top of a file
+                if !self.scopes.is_empty() {
+                    return Err(Diagnostic::error("E0209", "Imports are only allowed at the top level of a file")
+                        .at(keyword.span, ""));
+                }
             },
-            Statement::CmdFunction { name: _, cmd: _ } => self.resolve_var(statement)?,
         }
         Ok(())
     }
-    fn resolve_many(&mut self, statements: &Vec<&Statement>) -> Result<(), String> {
+    fn resolve_many(&mut self, statements: &Vec<&Statement>) -> Result<(), Diagnostic> {
         for statement in statements { self.resolve_internal(statement)?; } Ok(())
     }
-    fn resolve_block(&mut self, statement: &Statement) -> Result<(), String> {
+    fn resolve_block(&mut self, statement: &Statement) -> Result<(), Diagnostic> {
         match statement {
             Statement::Block { statements } => {
                 self.begin_scope();
@@ -88,59 +145,42 @@ impl Resolver {
         }
         Ok(())
     }
-    fn resolve_var(&mut self, statement: &Statement) -> Result<(), String> {
-        if let Statement::Var { name, var_type, initializer } = statement {
+    fn resolve_var(&mut self, statement: &Statement) -> Result<(), Diagnostic> {
+        if let Statement::Var { name, var_type: _, initializer } = statement {
             self.declare(name)?;
-            if let Some(var_type) = var_type {
-                let expected_type = self.resolve_type(var_type)?;
-                let actual_type = self.infer_type(initializer)?;
-    
-                if expected_type != actual_type {
-                    return Err(format!(
-                        "Type error: expected {:?}, but found {:?} for variable {}",
-                        expected_type, actual_type, name.lexeme
-                    ));
-                }
-            }    
             self.resolve_expr(initializer)?;
             self.define(name);
-        } else if let Statement::CmdFunction { name, cmd: _ } = statement {
+        } else if let Statement::CmdFunction { name, .. } = statement {
             self.declare(name)?; self.define(name);
         } else { panic!("Wrong type in resolve var"); }
         Ok(())
     }
-    fn resolve_function(&mut self, statement: &Statement, fn_type: FunctionType) -> Result<(), String> {
-        if let Statement::Function { name, generics, return_type, params, body } = statement {
+    fn resolve_function(&mut self, statement: &Statement, fn_type: FunctionType) -> Result<(), Diagnostic> {
+        if let Statement::Function { name, params, body, .. } = statement {
             self.declare(name)?; self.define(name);
-            self.resolve_function_helper(params, generics, return_type, &body.iter().map(|b| 
+            self.resolve_function_helper(params, &body.iter().map(|b| 
                 b.as_ref()).collect(), fn_type)
         } else { panic!("Wrong type in resolve function"); }
     }
     fn resolve_function_helper(
-        &mut self, params: &Vec<Token>, generics: &Vec<Token>, return_type: &Option<Token>,
-        body: &Vec<&Statement>, resolving_function: FunctionType
-    ) -> Result<(), String> {
+        &mut self, params: &Vec<Token>, body: &Vec<&Statement>, resolving_function: FunctionType
+    ) -> Result<(), Diagnostic> {
         let enclosing_function = self.current_function;
         self.current_function = resolving_function;
+        // Loops outside the function cannot be left from inside it
+        let enclosing_loops = std::mem::take(&mut self.loops);
+        // Only the parameters get slots, type names are not values
         self.begin_scope();
-
-        for generic in generics {
-            self.declare(generic)?;
-            self.define(generic);
-        }
         for param in params {
             self.declare(param)?;
             self.define(param);
         }
-        if let Some(return_type) = return_type {
-            self.declare(return_type)?;
-            self.define(return_type);
-        }
         self.resolve_many(body)?;
         self.end_scope(); self.current_function = enclosing_function;
+        self.loops = enclosing_loops;
         Ok(())
     }
-    fn resolve_if_stmt(&mut self, statement: &Statement) -> Result<(), String> {
+    fn resolve_if_stmt(&mut self, statement: &Statement) -> Result<(), Diagnostic> {
         if let Statement::IfStmt { predicate, then, els } = statement {
             self.resolve_expr(predicate)?;
             self.resolve_internal(then.as_ref())?;
@@ -152,141 +192,141 @@ impl Resolver {
     }
     fn begin_scope(&mut self) { self.scopes.push(HashMap::new()); }
     fn end_scope(&mut self) { self.scopes.pop().expect("Stack underflow"); }
-    fn declare(&mut self, name: &Token) -> Result<(), String> {
+    fn declare(&mut self, name: &Token) -> Result<(), Diagnostic> {
         let size = self.scopes.len();
         if self.scopes.is_empty() { return Ok(()); }
-        if self.scopes[size - 1].contains_key(&name.lexeme.clone()) {
-            return Err("A variable with this name is already in scope".to_string())
-        }; self.scopes[size - 1].insert(name.lexeme.clone(), false); Ok(())
+        let scope = &mut self.scopes[size - 1];
+        if let Some(previous) = scope.get(&name.lexeme) {
+            return Err(Diagnostic::error("E0205", "A variable with this name is already in scope")
+                .at(name.span, "declared again here").with_label(previous.span, "first declared here"))
+        };
+        let slot = scope.len();
+        scope.insert(name.lexeme.clone(), Binding { defined: false, slot, span: name.span }); Ok(())
     }
-    fn define(&mut self, name: &Token) {
-        if self.scopes.is_empty() { return; }
-        let size = self.scopes.len();
-        self.scopes[size - 1].insert(name.lexeme.clone(), true);
+    fn define(&mut self, name: &Token) { self.define_name(&name.lexeme, name.span); }
+    fn define_name(&mut self, name: &str, span: Span) {
+        let Some(scope) = self.scopes.last_mut() else { return };
+        let slot = scope.len();
+        scope.entry(name.to_string()).or_insert(Binding { defined: false, slot, span }).defined = true;
     }
-    fn resolve_expr(&mut self, expr: &Expr) -> Result<(), String> {
+    fn resolve_expr(&mut self, expr: &Expr) -> Result<(), Diagnostic> {
         match expr {
             Expr::Assign { id: _, name: _, value: _ } => self.resolve_expr_assign(expr, expr.get_id()),
-            Expr::AnonFunction { id: _, paren: _, generics, arguments, return_type, body } 
-                => self.resolve_function_helper(generics, arguments, return_type, 
+            Expr::AnonFunction { id: _, paren: _, generics: _, arguments, param_types: _, return_type: _, body } 
+                => self.resolve_function_helper(arguments, 
                     &body.iter().map(|b| b.as_ref()).collect(),
                     FunctionType::Function),
             Expr::Binary { id: _, left, operator: _, right } => {
                 self.resolve_expr(left)?;
                 self.resolve_expr(right)
             },
-            Expr::Call { id: _, callee, paren: _, arguments, generics } => {
+            Expr::Call { id: _, callee, paren: _, arguments, generics: _ } => {
                 self.resolve_expr(callee.as_ref())?;
                 for arg in arguments {
                     self.resolve_expr(arg)?;
                 }
-                for generic in generics {
-                    let var_type = Some(generic.clone());
-                    self.resolve_expr(&Expr::Variable {
-                        id: expr.get_id(), var_type,
-                        name: generic.clone(),
-                    })?;
-                }
                 Ok(())
             },
             Expr::Get { id: _, object, name: _ } => self.resolve_expr(object),
             Expr::Grouping { id: _, expression } => self.resolve_expr(expression),
-            Expr::Literal { id: _, value: _ } => Ok(()),
+            Expr::Piped { id: _, pipe: _, value } => self.resolve_expr(value),
+            Expr::Index { id: _, object, bracket: _, index } => {
+                self.resolve_expr(object)?;
+                self.resolve_expr(index)
+            },
+            Expr::IndexSet { id: _, object, bracket: _, index, operator: _, value } => {
+                self.resolve_expr(value)?;
+                self.resolve_expr(object)?;
+                self.resolve_expr(index)
+            },
+            Expr::List { id: _, bracket: _, elements } => {
+                for element in elements { self.resolve_expr(element)?; }
+                Ok(())
+            },
+            Expr::Map { id: _, brace: _, entries } => {
+                for (key, value) in entries {
+                    self.resolve_expr(key)?;
+                    self.resolve_expr(value)?;
+                }
+                Ok(())
+            },
+            Expr::Literal { id: _, span: _, value: _ } => Ok(()),
+            Expr::Interpolated { id: _, span: _, parts } => {
+                for part in parts { self.resolve_expr(part)?; }
+                Ok(())
+            },
             Expr::Logical { id: _, left, operator: _, right } => {
                 self.resolve_expr(left)?;
                 self.resolve_expr(right)
             },
-            Expr::Set { id: _, object, name: _, value } => {
+            Expr::Conditional { id: _, keyword: _, condition, then_branch, else_branch } => {
+                self.resolve_expr(condition)?;
+                self.resolve_expr(then_branch)?;
+                self.resolve_expr(else_branch)
+            },
+            Expr::Set { id: _, object, name: _, operator: _, value } => {
                 self.resolve_expr(value)?;
                 self.resolve_expr(object)
             }
+            Expr::Slice { id: _, object, bracket: _, start, end } => {
+                self.resolve_expr(object)?;
+                if let Some(start) = start { self.resolve_expr(start)?; }
+                if let Some(end) = end { self.resolve_expr(end)?; }
+                Ok(())
+            },
             Expr::This { id: _, keyword } => {
                 if self.current_function != FunctionType::Method {
-                    return Err("Cannot use 'this' keyword outside of a class".to_string());
+                    return Err(Diagnostic::error("E0206", "Cannot use 'this' keyword outside of a class").at(keyword.span, ""));
                 }; self.resolve_local(keyword, expr.get_id())
             },
             Expr::Super { id: _, keyword, method: _ } => {
-                if self.current_function != FunctionType::Method { return Err("Cannot use 'super' keyword outside of a class".to_string()); }
+                if self.current_function != FunctionType::Method {
+                    return Err(Diagnostic::error("E0206", "Cannot use 'super' keyword outside of a class").at(keyword.span, ""));
+                }
                 if self.scopes.len() < 3 || !self.scopes[self.scopes.len() - 3].contains_key("super") {
-                    return Err("Class has no superclass".to_string());
+                    return Err(Diagnostic::error("E0207", "Class has no superclass").at(keyword.span, ""));
                 }; self.resolve_local(keyword, expr.get_id())
             }
-            Expr::Variable { id: _, var_type: _, name: _ } => self.resolve_expr_var(expr, expr.get_id()),
+            Expr::Variable { id: _, name: _ } => self.resolve_expr_var(expr, expr.get_id()),
             Expr::Unary { id: _, operator: _, right } => self.resolve_expr(right),
+            Expr::Update { id: _, operator: _, prefix: _, target } => self.resolve_expr(target),
         }
     }
-    fn resolve_expr_var(&mut self, expr: &Expr, resolve_id: usize) -> Result<(), String> {
+    fn resolve_expr_var(&mut self, expr: &Expr, resolve_id: usize) -> Result<(), Diagnostic> {
         match expr {
-            Expr::Variable { id: _, var_type: _, name } => {
+            Expr::Variable { id: _, name } => {
                 if !self.scopes.is_empty() {
-                    if let Some(false) = self.scopes[self.scopes.len() - 1].get(&name.lexeme) {
-                        return Err("Can't read local variable in it's own initializer".to_string());
+                    if let Some(Binding { defined: false, .. }) = self.scopes[self.scopes.len() - 1].get(&name.lexeme) {
+                        return Err(Diagnostic::error("E0208", "Can't read local variable in its own initializer")
+                            .at(name.span, ""));
                     }
                 }
                 self.resolve_local(name, resolve_id)
             },
             Expr::Call { id: _, callee, paren: _, arguments: _, generics: _ } => match callee.as_ref() {
-                Expr::Variable { id: _, var_type: _, name } => self.resolve_local(&name, resolve_id),
+                Expr::Variable { id: _, name } => self.resolve_local(&name, resolve_id),
                 _ => panic!("Wrong type in resolve_expr_var"),
             },
             _ => panic!("Wrong type in resolve_expr_var"),
         }
     }
-    fn resolve_local(&mut self, name: &Token, resolve_id: usize) -> Result<(), String> {
+    fn resolve_local(&mut self, name: &Token, resolve_id: usize) -> Result<(), Diagnostic> {
         let size = self.scopes.len();
         if size == 0 { return Ok(()); }
 
         for i in (0..=(size - 1)).rev() {
-            let scope = &self.scopes[i];
-            if scope.contains_key(&name.lexeme) {
-                self.locals.insert(resolve_id, size - 1 - i);
+            if let Some(binding) = self.scopes[i].get(&name.lexeme) {
+                self.locals.insert(resolve_id, (size - 1 - i, binding.slot));
                 return Ok(());
             }
         }
         Ok(())
     }
-    fn resolve_expr_assign(&mut self, expr: &Expr, resolve_id: usize) -> Result<(), String> {
+    fn resolve_expr_assign(&mut self, expr: &Expr, resolve_id: usize) -> Result<(), Diagnostic> {
         if let Expr::Assign { id: _, name, value } = expr {
             self.resolve_expr(value.as_ref())?;
             self.resolve_local(name, resolve_id)?;
         } else { panic!("Wrong type in resolve assign"); }
         Ok(())
     }
-    fn resolve_type(&self, token: &Token) -> Result<Type, String> {
-        Type::from_str(&token.lexeme)
-    }
-    fn infer_type(&self, expr: &Expr) -> Result<Type, String> {
-        match expr {
-            Expr::Literal { id: _, value } => match &value {
-                LiteralValue::Number(_) => Ok(Type::Int),
-                LiteralValue::StringValue(_) => Ok(Type::String),
-                _ => Err(format!("Unknown literal type")),
-            },
-            Expr::Call { callee, arguments, generics, .. } => {
-                let callee_type = self.infer_type(callee)?;
-                if let Type::Generic(ref type_name) = callee_type {
-                    if type_name == "Box" {
-                        if generics.len() == 1 {
-                            let generic_type = self.resolve_type(&generics[0])?;
-                            return Ok(Type::Class(Box::new(generic_type)));
-                        } else {
-                            return Err(format!("Box type requires exactly one generic parameter"));
-                        }
-                    }
-                }
-                for arg in arguments {
-                    self.infer_type(arg)?;
-                }
-                Ok(callee_type)
-            },
-            Expr::Variable { name, var_type, .. } => {
-                if let Some(var_type) = var_type {
-                    self.resolve_type(var_type)
-                } else {
-                    Err(format!("Cannot infer type for variable {}", name.lexeme))
-                }
-            },
-            _ => Err(format!("Cannot infer type for expression")),
-        }
-    }
-}
\ No newline at end of file
+}
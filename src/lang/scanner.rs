@@ -1,3 +1,6 @@
+use crate::diagnostic::{Diagnostic, Span};
+use crate::decimal::Decimal;
+
 use core::str;
 use std::{collections::HashMap, string::String};
 fn get_keywords_hashmap() -> HashMap<&'static str, TokenType> { 
@@ -10,54 +13,72 @@ fn get_keywords_hashmap() -> HashMap<&'static str, TokenType> {
         ("return", Return), ("super", Super),
         ("this", This), ("true", True),
         ("var", Var), ("while", While),
+        ("throw", Throw), ("try", Try),
+        ("catch", Catch), ("finally", Finally),
+        ("break", Break), ("continue", Continue),
+        ("import", Import),
     ])
 }
 pub struct Scanner {
-    source: String,
+    source: Vec<char>,
     pub tokens: Vec<Token>,
     start: usize,
     current: usize,
     line: usize,
+    /// Where the current line starts, to give tokens a column.
+    line_start: usize,
+    /// The index of the source in the program's `SourceMap`.
+    file: u32,
     keywords: HashMap<&'static str, TokenType>,
+    /// For each `${` not closed yet, innermost last: how many braces inside it are open,
+    /// and where it started. Its closing `}` resumes the string.
+    interpolations: Vec<(usize, Span)>,
 }
 impl Scanner {
-    pub fn new(source: &str) -> Self {
+    /// `file` is the source's index in the program's `SourceMap`.
+    pub fn new(source: &str, file: u32) -> Self {
         Self {
-            source: source.to_string(),
-            tokens: vec![], line: 1,
+            source: source.chars().collect(),
+            tokens: vec![], line: 1, line_start: 0, file,
             start: 0, current: 0,
-            keywords: get_keywords_hashmap()
+            keywords: get_keywords_hashmap(),
+            interpolations: vec![],
         }
     }
-    pub fn scan_tokens(self: &mut Self) -> Result<Vec<Token>, String> {
+    pub fn scan_tokens(self: &mut Self) -> Result<Vec<Token>, Vec<Diagnostic>> {
         let mut errors = vec![];
         while !self.is_at_end() {
             self.start = self.current;
             match self.scan_token() {
                 Ok(_) => (),
-                Err(msg) => errors.push(msg),
+                Err(diagnostic) => errors.push(diagnostic),
             }
         }
+        for (_, span) in self.interpolations.drain(..).rev() {
+            errors.push(Diagnostic::error("E0005", "Unterminated interpolation").at(span, "expected '}' to close this"));
+        }
+        self.start = self.current;
         self.tokens.push(Token { 
             token_type: Eof, 
             lexeme: "".to_string(),
             literal: None,
-            line_number: self.line,
+            span: self.span(),
         });
-        if errors.len() > 0 {
-            let mut joined = "".to_string();
-            // let _ = errors.iter().map(|msg| {
-            //     joined.push_str(&msg);
-            //     joined.push_str("\n");
-            // });
-            for error in errors {
-                joined.push_str(&error);
-                joined.push_str("\n");
-            };
-            return Err(joined);
-        }
+        if errors.len() > 0 { return Err(errors); }
         Ok(self.tokens.clone())
     }
+    /// The span from the start of the current token to the current position.
+    fn span(&self) -> Span { self.span_from(self.start) }
+    /// The span from `start`, on the current line, to the current position.
+    fn span_from(&self, start: usize) -> Span {
+        Span {
+            start: start as u32, end: self.current as u32, line: self.line as u32,
+            column: (start - self.line_start + 1) as u32, file: self.file,
+        }
+    }
+    fn error(&self, code: &'static str, message: &str, label: &str) -> Diagnostic {
+        Diagnostic::error(code, message).at(self.span(), label)
+    }
     fn is_digit(self: &Self, ch: char) -> bool {
         let uch: u8 = ch as u8;
         uch >= '0' as u8 && uch <= '9' as u8
@@ -74,20 +95,59 @@ impl Scanner {
     fn is_at_end(self: &Self) -> bool {
         self.current >= self.source.len()
     }
-    fn scan_token(self: &mut Self) -> Result<(), String> {
+    fn scan_token(self: &mut Self) -> Result<(), Diagnostic> {
         let c = self.advance();
         match c {
             '(' => self.add_token(LeftParen),
             ')' => self.add_token(RightParen),
-            '{' => self.add_token(LeftBrace),
-            '}' => self.add_token(RightBrace),
+            '{' => {
+                if let Some((depth, _)) = self.interpolations.last_mut() { *depth += 1; }
+                self.add_token(LeftBrace);
+            },
+            '}' => match self.interpolations.last_mut() {
+                Some((0, _)) => {
+                    self.interpolations.pop();
+                    self.string()?;
+                },
+                Some((depth, _)) => {
+                    *depth -= 1;
+                    self.add_token(RightBrace);
+                },
+                None => self.add_token(RightBrace),
+            },
+            '[' => self.add_token(LeftBracket),
+            ']' => self.add_token(RightBracket),
             ',' => self.add_token(Comma),
             '.' => self.add_token(Dot),
-            '-' => self.add_token(Minus),
-            '+' => self.add_token(Plus),
+            '-' => {
+                let token = if self.char_match('>') { Arrow }
+                    else if self.char_match('=') { MinusEqual }
+                    else if self.char_match('-') { MinusMinus }
+                    else { Minus };
+                self.add_token(token);
+            },
+            '+' => {
+                let token = if self.char_match('=') { PlusEqual } else if self.char_match('+') { PlusPlus } else { Plus };
+                self.add_token(token);
+            },
             ':' => self.add_token(Colon),
+            '?' => self.add_token(Question),
             ';' => self.add_token(Semicolon),
-            '*' => self.add_token(Star),
+            '*' => {
+                let token = if self.char_match('*') { StarStar } else if self.char_match('=') { StarEqual } else { Star };
+                self.add_token(token);
+            },
+            '%' => {
+                let token = if self.char_match('=') { PercentEqual } else { Percent };
+                self.add_token(token);
+            },
+            '&' => self.add_token(Ampersand),
+            '^' => self.add_token(Caret),
+            // `~/` is floor division, unless the slash starts a comment
+            '~' => {
+                let token = if self.peek() == '/' && self.peek_next() != '/' { self.advance(); TildeSlash } else { Tilde };
+                self.add_token(token);
+            },
             '!' => {
                 let token = if self.char_match('=') { BangEqual } else { Bang };
                 self.add_token(token);
@@ -98,12 +158,13 @@ impl Scanner {
             },
             '<' => {
                 let token = if self.char_match('=') { LessEqual }
-                else if self.char_match('-') { Gets } else { Less };
+                else if self.char_match('-') { Gets }
+                else if self.char_match('<') { LessLess } else { Less };
                 self.add_token(token);
             },
             '>' => {
-                let token = if self.char_match('=') { GreaterEqual } 
-                else if self.char_match('-') { Arrow } else { Greater };
+                let token = if self.char_match('=') { GreaterEqual }
+                else if self.char_match('>') { GreaterGreater } else { Greater };
                 self.add_token(token);
             },
             '/' => {
@@ -112,17 +173,23 @@ impl Scanner {
                         if self.peek() == '\n' || self.is_at_end() { break; }
                         self.advance();
                     }
+                } else if self.char_match('=') {
+                    self.add_token(SlashEqual);
                 } else {
                     self.add_token(Slash);
                 }
             },
             '|' => {
-                if self.char_match('>') { self.add_token(Pipe); }
-                else { return Err(format!("Expected '>' at line {}", self.line)); }
+                let token = if self.char_match('>') { Pipe } else { Bar };
+                self.add_token(token);
             }
             ' ' | '\r' | '\t' => {},
-            '\n' => self.line += 1,
+            '\n' => {
+                self.line += 1;
+                self.line_start = self.current;
+            },
             '"' => self.string()?,
+            'r' if self.peek() == '"' || self.peek() == '#' => self.raw_string()?,
 
             c => {
                 if self.is_digit(c) {
@@ -130,7 +197,7 @@ impl Scanner {
                 } else if self.is_alpha(c) {
                     self.identifier();
                 } else {
-                    return Err(format!("Unrecognized char at line {}: {}", self.line, c));
+                    return Err(self.error("E0001", &format!("Unrecognized character '{}'", c), ""));
                 }
             },
         }
@@ -140,41 +207,154 @@ impl Scanner {
         self.add_token_lit(token_type, None);
     }
     fn add_token_lit(self: &mut Self, token_type: TokenType, literal: Option<LiteralValue>) {
-        let text = self.source[self.start..self.current].to_string();
+        let text = self.source[self.start..self.current].iter().collect();
         self.tokens.push(Token {
             token_type: token_type,
             lexeme: text,
             literal: literal,
-            line_number: self.line
+            span: self.span(),
         });
     }
-    fn string(self: &mut Self) -> Result<(), String> {
-        while self.peek() != '"' && !self.is_at_end() {
-            if self.peek() == '\n' { self.line += 1; }
-            self.advance();
+    /// The rest of a string literal, after its opening quote or the `}` ending an interpolation.
+    /// The text up to a `${` becomes an `Interpolation` token, the tokens of the expression
+    /// follow, and the string goes on once its `}` is reached.
+    fn string(self: &mut Self) -> Result<(), Diagnostic> {
+        // A string spanning lines is located by the line it starts on
+        let (line, line_start) = (self.line, self.line_start);
+        let (mut value, mut bad_escape) = (String::new(), None);
+        let token_type = loop {
+            if self.is_at_end() {
+                (self.line, self.line_start) = (line, line_start);
+                return Err(self.error("E0002", "Unterminated string", "string starts here"));
+            }
+            match self.advance() {
+                '"' => break StringLit,
+                '$' if self.peek() == '{' => {
+                    self.advance();
+                    break Interpolation;
+                },
+                '\\' => match self.escape() {
+                    Ok(c) => value.push(c),
+                    // Scanning on to the end of the string keeps its remainder from being read as code
+                    Err(diagnostic) => { bad_escape.get_or_insert(diagnostic); },
+                },
+                c => {
+                    if c == '\n' {
+                        self.line += 1;
+                        self.line_start = self.current;
+                    }
+                    value.push(c);
+                },
+            }
+        };
+        if token_type == Interpolation {
+            let start = self.current - 2;
+            self.interpolations.push((0, self.span_from(start)));
+        }
+        let (end_line, end_line_start) = (self.line, self.line_start);
+        (self.line, self.line_start) = (line, line_start);
+        self.add_token_lit(token_type, Some(StringValue(value)));
+        (self.line, self.line_start) = (end_line, end_line_start);
+        match bad_escape {
+            Some(diagnostic) => Err(diagnostic),
+            None => Ok(()),
+        }
+    }
+    /// The character an escape sequence stands for, after its backslash.
+    fn escape(&mut self) -> Result<char, Diagnostic> {
+        let start = self.current - 1;
+        if self.is_at_end() { return Err(Diagnostic::error("E0004", "Unknown escape sequence").at(self.span_from(start), "")); }
+        match self.advance() {
+            'n' => Ok('\n'),
+            't' => Ok('\t'),
+            'r' => Ok('\r'),
+            '0' => Ok('\0'),
+            '\\' => Ok('\\'),
+            '"' => Ok('"'),
+            '$' => Ok('$'),
+            'u' => {
+                let mut digits = String::new();
+                if self.char_match('{') {
+                    while self.peek().is_ascii_hexdigit() && digits.len() < 6 { digits.push(self.advance()); }
+                    if self.char_match('}') {
+                        if let Some(c) = u32::from_str_radix(&digits, 16).ok().and_then(char::from_u32) { return Ok(c); }
+                    }
+                }
+                Err(Diagnostic::error("E0004", "Invalid unicode escape")
+                    .at(self.span_from(start), "expected a code point like \\u{1F600}"))
+            },
+            c => Err(Diagnostic::error("E0004", &format!("Unknown escape sequence '\\{}'", c))
+                .at(self.span_from(start), "use '\\\\' for a backslash")),
+        }
+    }
+    /// `r"..."` or, to allow quotes inside, `r#"..."#` with any number of `#`s.
+    /// Nothing in a raw string is escaped or interpolated.
+    fn raw_string(&mut self) -> Result<(), Diagnostic> {
+        let (line, line_start) = (self.line, self.line_start);
+        let mut hashes = 0;
+        while self.char_match('#') { hashes += 1; }
+        if !self.char_match('"') {
+            return Err(self.error("E0002", "Expected '\"' to start a raw string", ""));
         }
-        if self.is_at_end() {
-            return Err("Unterminated string!".to_string());
+        let content = self.current;
+        loop {
+            if self.is_at_end() {
+                (self.line, self.line_start) = (line, line_start);
+                return Err(self.error("E0002", "Unterminated string", "string starts here"));
+            }
+            match self.advance() {
+                '"' if (0..hashes).all(|i| self.source.get(self.current + i) == Some(&'#')) => break,
+                '\n' => {
+                    self.line += 1;
+                    self.line_start = self.current;
+                },
+                _ => (),
+            }
         }
-        self.advance();
-        let value = &self.source[self.start + 1..self.current - 1];
-        self.add_token_lit(StringLit, 
-            Some(StringValue(value.to_string())));
+        let value = self.source[content..self.current - 1].iter().collect();
+        self.current += hashes;
+        let (end_line, end_line_start) = (self.line, self.line_start);
+        (self.line, self.line_start) = (line, line_start);
+        self.add_token_lit(StringLit, Some(StringValue(value)));
+        (self.line, self.line_start) = (end_line, end_line_start);
         Ok(())
     }
-    fn number(self: &mut Self) -> Result<(), String> {
-        while self.is_digit(self.peek()) { self.advance(); }
-        if self.peek() == '.' && self.is_digit(self.peek_next()) {
+    /// Integers may be written in hex with `0x` or binary with `0b`, and any number may use `_`
+    /// between digits. A fractional part makes a float, and a `d` suffix an exact decimal.
+    fn number(self: &mut Self) -> Result<(), Diagnostic> {
+        let radix = match (self.source[self.start], self.peek()) {
+            ('0', 'x' | 'X') => 16,
+            ('0', 'b' | 'B') => 2,
+            _ => 10,
+        };
+        if radix != 10 { self.advance(); }
+        while self.peek().is_digit(radix) || self.peek() == '_' { self.advance(); }
+        let mut float = false;
+        if radix == 10 && self.peek() == '.' && self.is_digit(self.peek_next()) {
+            float = true;
             self.advance();
-            while self.is_digit(self.peek()) {
-                self.advance();
-            }
+            while self.is_digit(self.peek()) || self.peek() == '_' { self.advance(); }
         }
-        let substring = &self.source[self.start..self.current];
-        let value = substring.parse::<f64>();
-        match value {
-            Ok(value) => self.add_token_lit(Number, Some(FValue(value))),
-            Err(_) => return Err(format!("Could not parse number: {}", substring)),
+        let prefix = if radix == 10 { 0 } else { 2 };
+        let digits: String = self.source[self.start + prefix..self.current].iter().filter(|c| **c != '_').collect();
+        let text: String = self.source[self.start..self.current].iter().collect();
+        if radix == 10 && self.peek() == 'd' && !self.is_alpha_numeric(self.peek_next()) {
+            self.advance();
+            let decimal = Decimal::parse(&digits).map(DValue);
+            self.add_token_lit(Number, decimal);
+            return Ok(());
+        }
+        if digits.is_empty() {
+            return Err(self.error("E0003", &format!("Expected digits after {}", text), ""));
+        }
+        let literal = if float {
+            digits.parse::<f64>().map(FValue).map_err(|_| "")
+        } else {
+            i64::from_str_radix(&digits, radix).map(IValue).map_err(|_| "integers must fit in 64 bits, write a float for larger numbers")
+        };
+        match literal {
+            Ok(literal) => self.add_token_lit(Number, Some(literal)),
+            Err(label) => return Err(self.error("E0003", &format!("Could not parse number: {}", text), label)),
         }
         Ok(())
     }
@@ -182,8 +362,8 @@ impl Scanner {
         while self.is_alpha_numeric(self.peek()) {
             self.advance();
         }
-        let substring = &self.source[self.start..self.current];
-        if let Some(&t_type) = self.keywords.get(substring) {
+        let substring: String = self.source[self.start..self.current].iter().collect();
+        if let Some(&t_type) = self.keywords.get(substring.as_str()) {
             self.add_token(t_type);
         } else {
             self.add_token(Identifier);
@@ -191,15 +371,15 @@ impl Scanner {
     }
     fn peek(self: &Self) -> char {
         if self.is_at_end() { return '\0'; }
-        self.source.chars().nth(self.current).unwrap()
+        self.source[self.current]
     }
     fn peek_next(self: &Self) -> char {
         if self.current + 1 >= self.source.len() { return '\0'; }
-        self.source.chars().nth(self.current + 1).unwrap()
+        self.source[self.current + 1]
     }
     fn char_match(self: &mut Self, ch: char) -> bool {
         if self.is_at_end() { return false; }
-        if self.source.chars().nth(self.current).unwrap() != ch { 
+        if self.source[self.current] != ch { 
             return false;
         } else {
             self.current += 1;
@@ -207,7 +387,7 @@ impl Scanner {
         }
     }
     fn advance(self: &mut Self) -> char {
-        let c = self.source.chars().nth(self.current).unwrap();
+        let c = self.source[self.current];
         self.current += 1;
         c
     }
@@ -216,21 +396,29 @@ impl Scanner {
 pub enum TokenType {
     // Single-char tokens
     LeftParen, RightParen, LeftBrace, RightBrace,
+    LeftBracket, RightBracket,
     Comma, Dot, Minus, Plus, Colon, Semicolon, Slash, Star,
+    Percent, Ampersand, Caret, Tilde, Question,
 
     // One or two chars
     Bang, BangEqual,
     Equal, EqualEqual,
     Greater, GreaterEqual,
     Less, LessEqual, 
-    Pipe, Gets, Arrow,
+    Pipe, Gets, Arrow, Bar,
+    StarStar, TildeSlash, LessLess, GreaterGreater,
+    PlusEqual, MinusEqual, StarEqual, SlashEqual, PercentEqual,
+    PlusPlus, MinusMinus,
 
     // Literals
     Identifier, StringLit, Number,
+    /// The text of a string up to a `${`, followed by the tokens of the interpolated expression.
+    Interpolation,
 
     // Keywords
     And, Class, Else, False, Fun, For, If, Nil, Or,
     Print, Return, Super, This, True, Var, While,
+    Throw, Try, Catch, Finally, Break, Continue, Import,
 
     Eof
 }
@@ -242,7 +430,9 @@ impl std::fmt::Display for TokenType {
 }
 #[derive(Debug, Clone)]
 pub enum LiteralValue {
+    IValue(i64),
     FValue(f64),
+    DValue(Decimal),
     StringValue(String)
 }
 use LiteralValue::*;
@@ -251,7 +441,7 @@ pub struct Token {
     pub token_type: TokenType,
     pub lexeme: String,
     pub literal: Option<LiteralValue>,
-    pub line_number: usize
+    pub span: Span,
 }
 impl Token {
     pub fn to_string(self: &Self) -> String {
@@ -264,10 +454,32 @@ mod tests {
     #[test]
     fn handle_one_char_tokens() {
         let source = "(( ))";
-        let mut scanner = Scanner::new(source);
+        let mut scanner = Scanner::new(source, 0);
         let _ = scanner.scan_tokens();
 
         assert_eq!(scanner.tokens.len(), 5);
         assert_eq!(scanner.tokens[0].token_type, LeftParen);
     }
-}
\ No newline at end of file
+    #[test]
+    fn split_interpolated_strings() {
+        let mut scanner = Scanner::new("\"a${ {} }b\\n${x}\"", 0);
+        let tokens = scanner.scan_tokens().unwrap();
+        let types: Vec<TokenType> = tokens.iter().map(|token| token.token_type).collect();
+        assert_eq!(types, vec![Interpolation, LeftBrace, RightBrace, Interpolation, Identifier, StringLit, Eof]);
+        assert!(matches!(&tokens[3].literal, Some(StringValue(text)) if text == "b\n"));
+    }
+    #[test]
+    fn keep_double_slash_a_comment() {
+        let mut scanner = Scanner::new("x ~/ 2; // note\n// line\n(y) // 2\n~// not division\n", 0);
+        let tokens = scanner.scan_tokens().unwrap();
+        let types: Vec<TokenType> = tokens.iter().map(|token| token.token_type).collect();
+        assert_eq!(types, vec![Identifier, TildeSlash, Number, Semicolon, LeftParen, Identifier, RightParen, Tilde, Eof]);
+    }
+    #[test]
+    fn tell_integers_from_floats() {
+        let mut scanner = Scanner::new("1 1.0 0xFF 0b1_01 1_000.5 1.x", 0);
+        let literals: Vec<String> = scanner.scan_tokens().unwrap().iter()
+            .filter_map(|token| token.literal.as_ref().map(|literal| format!("{:?}", literal))).collect();
+        assert_eq!(literals, vec!["IValue(1)", "FValue(1.0)", "IValue(255)", "IValue(5)", "FValue(1000.5)", "IValue(1)"]);
+    }
+}
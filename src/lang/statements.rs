@@ -1,21 +1,39 @@
 use crate::expr::Expr;
 use crate::scanner::Token;
+use crate::type_::{TypeAnnotation, TypeParam};
+use crate::command::CommandTemplate;
 
 #[derive(Debug, Clone)]
 pub enum Statement {
     Expression { expression: Expr },
     Print { expression: Expr },
-    Var { name: Token, var_type: Option<Token>, initializer: Expr },
+    Var { name: Token, var_type: Option<TypeAnnotation>, initializer: Expr },
     Block { statements: Vec<Box<Statement>> },
     IfStmt { 
         predicate: Expr, then: Box<Statement>, 
         els: Option<Box<Statement>> 
     },
-    WhileStmt { condition: Expr, body: Box<Statement> },
+    WhileStmt {
+        condition: Expr, body: Box<Statement>,
+        increment: Option<Expr>, label: Option<Token>
+    },
+    Break { keyword: Token, label: Option<Token> },
+    Continue { keyword: Token, label: Option<Token> },
     ReturnStmt { keyword: Token, value: Option<Expr> },
-    Class { name: Token, generics: Vec<Token>, methods: Vec<Box<Statement>>, superclass: Option<Expr> },
-    Function { name: Token, params: Vec<Token>, generics: Vec<Token>, return_type: Option<Token>, body: Vec<Box<Statement>> },
-    CmdFunction { name: Token, cmd: String },
+    Throw { keyword: Token, value: Expr },
+    TryStmt {
+        try_block: Box<Statement>, catch_name: Option<Token>,
+        catch_block: Option<Box<Statement>>, finally_block: Option<Box<Statement>>
+    },
+    Class { name: Token, generics: Vec<TypeParam>, methods: Vec<Box<Statement>>, superclass: Option<Expr> },
+    Function {
+        name: Token, params: Vec<Token>, param_types: Vec<Option<TypeAnnotation>>,
+        generics: Vec<TypeParam>, return_type: Option<TypeAnnotation>, body: Vec<Box<Statement>>
+    },
+    /// `fun name(params) <- "command {param}";` runs a process when called.
+    CmdFunction { name: Token, params: Vec<Token>, param_types: Vec<Option<TypeAnnotation>>, command: CommandTemplate },
+    /// `import "path" as alias;` binds the module to `alias`, `from "path" import a, b;` binds its members.
+    Import { keyword: Token, path: Token, alias: Option<Token>, names: Vec<Token> },
 }
 impl Statement {
     #[allow(dead_code)]
@@ -29,10 +47,10 @@ impl Statement {
                 "(block {})", statements.into_iter().map(|stmt| stmt.to_string()).collect::<String>()
             ),
             IfStmt { predicate: _, then: _, els: _ } => todo!(),
-            WhileStmt { condition: _, body: _ } => todo!(),
+            WhileStmt { condition: _, body: _, increment: _, label: _ } => todo!(),
             ReturnStmt { keyword: _, value: _ } => todo!(),
-            Function { name: _, params: _, generics: _, return_type: _, body: _ } => todo!(),
-            CmdFunction { name: _, cmd: _ } => todo!(),
+            Function { name: _, params: _, param_types: _, generics: _, return_type: _, body: _ } => todo!(),
+            CmdFunction { .. } => todo!(),
             _ => todo!(),
         }
     }
@@ -0,0 +1,449 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::cell::RefCell;
+
+use crate::expr::{LiteralValue, call_value};
+use crate::error::RuntimeError;
+use crate::number;
+use crate::decimal::Decimal;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ColumnType { Number, String, Boolean, Nil }
+impl ColumnType {
+    fn of(value: &LiteralValue) -> Result<ColumnType, String> {
+        match value {
+            LiteralValue::Int(_) | LiteralValue::Float(_) | LiteralValue::Decimal(_) => Ok(ColumnType::Number),
+            LiteralValue::StringValue(_) => Ok(ColumnType::String),
+            LiteralValue::True | LiteralValue::False => Ok(ColumnType::Boolean),
+            LiteralValue::Nil => Ok(ColumnType::Nil),
+            other => Err(format!("Table cells must be numbers, strings, booleans or nil, not {}", other.to_type())),
+        }
+    }
+    pub fn name(&self) -> &str {
+        match self {
+            ColumnType::Number => "Number", ColumnType::String => "String",
+            ColumnType::Boolean => "Boolean", ColumnType::Nil => "nil",
+        }
+    }
+}
+#[derive(Clone)]
+pub struct Column {
+    pub name: String,
+    pub kind: ColumnType,
+    pub values: Vec<LiteralValue>,
+}
+impl Column {
+    /// Builds a column and infers its type. Nil is allowed in any column, other values must all share one type.
+    pub fn new(name: &str, values: Vec<LiteralValue>) -> Result<Column, String> {
+        let mut kind = ColumnType::Nil;
+        for value in values.iter() {
+            match (kind, ColumnType::of(value)?) {
+                (_, ColumnType::Nil) => (),
+                (ColumnType::Nil, other) => kind = other,
+                (current, other) if current != other => return Err(format!(
+                    "Column '{}' mixes {} and {} values", name, current.name(), other.name()
+                )),
+                _ => (),
+            }
+        }
+        Ok(Column { name: name.to_string(), kind, values })
+    }
+    fn take(&self, indices: &[usize]) -> Column {
+        Column {
+            name: self.name.clone(), kind: self.kind,
+            values: indices.iter().map(|i| self.values[*i].clone()).collect(),
+        }
+    }
+}
+#[derive(Clone)]
+pub struct Table {
+    pub columns: Vec<Column>,
+}
+impl PartialEq for Table {
+    fn eq(&self, other: &Self) -> bool {
+        self.columns.len() == other.columns.len() && self.columns.iter().zip(other.columns.iter())
+            .all(|(a, b)| a.name == b.name && a.values == b.values)
+    }
+}
+impl Table {
+    pub fn new(columns: Vec<Column>) -> Result<Table, String> {
+        if let Some(first) = columns.first() {
+            for column in columns.iter() {
+                if column.values.len() != first.values.len() {
+                    return Err(format!(
+                        "Column '{}' has {} rows but column '{}' has {}",
+                        column.name, column.values.len(), first.name, first.values.len()
+                    ));
+                }
+                if columns.iter().filter(|c| c.name == column.name).count() > 1 {
+                    return Err(format!("Duplicate column name '{}'", column.name));
+                }
+            }
+        }
+        Ok(Table { columns })
+    }
+    pub fn from_rows(names: &[String], rows: Vec<Vec<LiteralValue>>) -> Result<Table, String> {
+        let mut columns = vec![];
+        for (i, name) in names.iter().enumerate() {
+            let values = rows.iter().map(|row| row.get(i).cloned().unwrap_or(LiteralValue::Nil)).collect();
+            columns.push(Column::new(name, values)?);
+        }
+        Table::new(columns)
+    }
+    pub fn row_count(&self) -> usize {
+        self.columns.first().map_or(0, |c| c.values.len())
+    }
+    pub fn column_names(&self) -> Vec<String> {
+        self.columns.iter().map(|c| c.name.clone()).collect()
+    }
+    pub fn column_index(&self, name: &str) -> Result<usize, String> {
+        self.columns.iter().position(|c| c.name == name)
+            .ok_or(format!("No column named '{}' in table", name))
+    }
+    pub fn column(&self, name: &str) -> Result<&Column, String> {
+        Ok(&self.columns[self.column_index(name)?])
+    }
+    /// Returns row `i` as a map from column name to value.
+    pub fn row(&self, i: usize) -> LiteralValue {
+        LiteralValue::new_map(self.columns.iter().map(|c| {
+            (LiteralValue::StringValue(c.name.clone()), c.values[i].clone())
+        }).collect())
+    }
+    pub fn take_rows(&self, indices: &[usize]) -> Table {
+        Table { columns: self.columns.iter().map(|c| c.take(indices)).collect() }
+    }
+}
+impl std::fmt::Display for Table {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let cells: Vec<Vec<String>> = self.columns.iter().map(|c| {
+            let mut cells = vec![c.name.clone()];
+            cells.extend(c.values.iter().map(display_cell));
+            cells
+        }).collect();
+        let widths: Vec<usize> = cells.iter()
+            .map(|c| c.iter().map(|s| s.chars().count()).max().unwrap_or(0)).collect();
+        let mut lines = vec![];
+        for row in 0..=self.row_count() {
+            let line = cells.iter().zip(widths.iter())
+                .map(|(c, w)| format!("{:<w$}", c[row], w = *w))
+                .collect::<Vec<String>>().join(" | ");
+            lines.push(line.trim_end().to_string());
+            if row == 0 {
+                lines.push(widths.iter().map(|w| "-".repeat(*w)).collect::<Vec<String>>().join("-+-"));
+            }
+        }
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+fn display_cell(value: &LiteralValue) -> String {
+    match value {
+        LiteralValue::StringValue(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+/// Total order used for sorting: nil < booleans < numbers < strings.
+pub fn compare_values(a: &LiteralValue, b: &LiteralValue) -> Ordering {
+    fn rank(value: &LiteralValue) -> u8 {
+        match value {
+            LiteralValue::Nil => 0,
+            LiteralValue::False | LiteralValue::True => 1,
+            LiteralValue::Int(_) | LiteralValue::Float(_) | LiteralValue::Decimal(_) => 2,
+            LiteralValue::StringValue(_) => 3,
+            _ => 4,
+        }
+    }
+    match (a, b) {
+        (
+            LiteralValue::Int(_) | LiteralValue::Float(_) | LiteralValue::Decimal(_),
+            LiteralValue::Int(_) | LiteralValue::Float(_) | LiteralValue::Decimal(_),
+        ) => number::ordering(a, b).unwrap_or(Ordering::Equal),
+        (LiteralValue::StringValue(x), LiteralValue::StringValue(y)) => x.cmp(y),
+        (LiteralValue::False, LiteralValue::True) => Ordering::Less,
+        (LiteralValue::True, LiteralValue::False) => Ordering::Greater,
+        _ => rank(a).cmp(&rank(b)),
+    }
+}
+fn row_key(table: &Table, columns: &[usize], row: usize) -> String {
+    columns.iter().map(|c| key_text(&table.columns[*c].values[row]))
+        .collect::<Vec<String>>().join("\u{1f}")
+}
+/// Text that is the same for values `==` calls equal: whole floats and decimals key as the
+/// int they equal, and other decimals are marked apart from floats, which they never equal.
+fn key_text(value: &LiteralValue) -> String {
+    match value {
+        LiteralValue::Float(x) if x.fract() == 0.0 && x.abs() < i64::MAX as f64 => (*x as i64).to_string(),
+        LiteralValue::Decimal(x) => {
+            let text = x.to_string();
+            if !text.contains('.') { return text; }
+            match text.trim_end_matches('0').trim_end_matches('.') {
+                whole if !whole.contains('.') => whole.to_string(),
+                fraction => format!("{}d", fraction),
+            }
+        },
+        other => other.to_string(),
+    }
+}
+fn expect_table(args: &[LiteralValue], fname: &str) -> Result<Rc<Table>, String> {
+    match args.first() {
+        Some(LiteralValue::Table(table)) => Ok(table.clone()),
+        Some(other) => Err(format!("{}() expects a Table, not {}", fname, other.to_type())),
+        None => Err(format!("{}() expects a Table as its first argument", fname)),
+    }
+}
+/// Accepts either a single column name or a list of them.
+fn expect_column_names(value: Option<&LiteralValue>, fname: &str) -> Result<Vec<String>, String> {
+    match value {
+        Some(LiteralValue::StringValue(name)) => Ok(vec![name.clone()]),
+        Some(LiteralValue::List(items)) => items.borrow().iter().map(|item| match item {
+            LiteralValue::StringValue(name) => Ok(name.clone()),
+            other => Err(format!("{}() expects column names, not {}", fname, other.to_type())),
+        }).collect(),
+        Some(other) => Err(format!("{}() expects a column name or a list of them, not {}", fname, other.to_type())),
+        None => Err(format!("{}() expects a column name", fname)),
+    }
+}
+fn column_indices(table: &Table, names: &[String]) -> Result<Vec<usize>, String> {
+    names.iter().map(|name| table.column_index(name)).collect()
+}
+fn new_list(items: Vec<LiteralValue>) -> LiteralValue {
+    LiteralValue::List(Rc::new(RefCell::new(items)))
+}
+/// `table(rows)` builds a table from a list of row maps or from a map of column lists.
+pub fn table_impl(args: &[LiteralValue]) -> Result<LiteralValue, RuntimeError> {
+    match args.first() {
+        Some(LiteralValue::List(rows)) => {
+            let mut names: Vec<String> = vec![];
+            for row in rows.borrow().iter() {
+                if let LiteralValue::Map(entries) = row {
+                    for (key, _) in entries.borrow().iter() {
+                        match key {
+                            LiteralValue::StringValue(name) => if !names.contains(name) { names.push(name.clone()) },
+                            other => return Err(format!("Column names must be strings, not {}", other.to_type()).into()),
+                        }
+                    }
+                } else { return Err(format!("table() expects a list of maps, found {}", row.to_type()).into()); }
+            }
+            let mut values = vec![];
+            for row in rows.borrow().iter() {
+                if let LiteralValue::Map(entries) = row {
+                    let entries = entries.borrow();
+                    values.push(names.iter().map(|name| {
+                        entries.iter().find(|(key, _)| *key == LiteralValue::StringValue(name.clone()))
+                            .map_or(LiteralValue::Nil, |(_, value)| value.clone())
+                    }).collect());
+                }
+            }
+            Ok(LiteralValue::Table(Rc::new(Table::from_rows(&names, values)?)))
+        },
+        Some(LiteralValue::Map(entries)) => {
+            let mut columns = vec![];
+            for (key, value) in entries.borrow().iter() {
+                match (key, value) {
+                    (LiteralValue::StringValue(name), LiteralValue::List(items)) => {
+                        columns.push(Column::new(name, items.borrow().clone())?);
+                    },
+                    _ => return Err("table() expects a map from column names to lists".into()),
+                }
+            }
+            Ok(LiteralValue::Table(Rc::new(Table::new(columns)?)))
+        },
+        Some(other) => Err(format!("Cannot build a table from {}", other.to_type()).into()),
+        None => Err("table() expects 1 argument".into()),
+    }
+}
+pub fn rows_impl(args: &[LiteralValue]) -> Result<LiteralValue, RuntimeError> {
+    let table = expect_table(args, "rows")?;
+    Ok(new_list((0..table.row_count()).map(|i| table.row(i)).collect()))
+}
+pub fn columns_impl(args: &[LiteralValue]) -> Result<LiteralValue, RuntimeError> {
+    let table = expect_table(args, "columns")?;
+    Ok(new_list(table.column_names().into_iter().map(LiteralValue::StringValue).collect()))
+}
+/// `filter(data, predicate)` keeps the rows (or list items) for which the predicate is truthy.
+pub fn filter_impl(args: &[LiteralValue]) -> Result<LiteralValue, RuntimeError> {
+    let predicate = args.get(1).ok_or("filter() expects 2 arguments".to_string())?;
+    if let Some(LiteralValue::List(items)) = args.first() {
+        let mut kept = vec![];
+        for item in items.borrow().iter() {
+            if call_value(predicate, vec![item.clone()])?.is_true()? == LiteralValue::True {
+                kept.push(item.clone());
+            }
+        }
+        return Ok(new_list(kept));
+    }
+    let table = expect_table(args, "filter")?;
+    let mut indices = vec![];
+    for i in 0..table.row_count() {
+        if call_value(predicate, vec![table.row(i)])?.is_true()? == LiteralValue::True {
+            indices.push(i);
+        }
+    }
+    Ok(LiteralValue::Table(Rc::new(table.take_rows(&indices))))
+}
+/// `select(table, columns)` keeps only the named columns, in the given order.
+pub fn select_impl(args: &[LiteralValue]) -> Result<LiteralValue, RuntimeError> {
+    let table = expect_table(args, "select")?;
+    let names = if args.len() > 2 {
+        args[1..].iter().map(|arg| expect_column_names(Some(arg), "select"))
+            .collect::<Result<Vec<Vec<String>>, String>>()?.concat()
+    } else { expect_column_names(args.get(1), "select")? };
+    let mut columns = vec![];
+    for name in names.iter() { columns.push(table.column(name)?.clone()); }
+    Ok(LiteralValue::Table(Rc::new(Table::new(columns)?)))
+}
+/// `sort_by(table, columns, descending)` is a stable sort on one or more columns.
+pub fn sort_by_impl(args: &[LiteralValue]) -> Result<LiteralValue, RuntimeError> {
+    let table = expect_table(args, "sort_by")?;
+    let keys = column_indices(&table, &expect_column_names(args.get(1), "sort_by")?)?;
+    let descending = matches!(args.get(2), Some(value) if value.is_true()? == LiteralValue::True);
+    let mut indices: Vec<usize> = (0..table.row_count()).collect();
+    indices.sort_by(|a, b| {
+        let ordering = keys.iter()
+            .map(|k| compare_values(&table.columns[*k].values[*a], &table.columns[*k].values[*b]))
+            .find(|o| *o != Ordering::Equal).unwrap_or(Ordering::Equal);
+        if descending { ordering.reverse() } else { ordering }
+    });
+    Ok(LiteralValue::Table(Rc::new(table.take_rows(&indices))))
+}
+fn aggregate(op: &str, column: &Column, rows: &[usize]) -> Result<LiteralValue, String> {
+    let values: Vec<&LiteralValue> = rows.iter().map(|i| &column.values[*i])
+        .filter(|v| **v != LiteralValue::Nil).collect();
+    match op {
+        "count" => Ok(LiteralValue::Int(values.len() as i64)),
+        "first" => Ok(values.first().map_or(LiteralValue::Nil, |v| (*v).clone())),
+        "last" => Ok(values.last().map_or(LiteralValue::Nil, |v| (*v).clone())),
+        "min" => Ok(values.iter().min_by(|a, b| compare_values(a, b)).map_or(LiteralValue::Nil, |v| (*v).clone())),
+        "max" => Ok(values.iter().max_by(|a, b| compare_values(a, b)).map_or(LiteralValue::Nil, |v| (*v).clone())),
+        "sum" | "mean" => {
+            if column.kind != ColumnType::Number && column.kind != ColumnType::Nil {
+                return Err(format!("Cannot {} column '{}' of type {}", op, column.name, column.kind.name()));
+            }
+            if values.iter().any(|v| matches!(v, LiteralValue::Decimal(_))) { return decimal_aggregate(op, column, &values); }
+            // A sum of ints stays an int unless it overflows, a mean is always a float
+            let ints: Option<Vec<i64>> = values.iter().map(|v| match v { LiteralValue::Int(x) => Some(*x), _ => None }).collect();
+            let total: f64 = values.iter().map(|v| match v {
+                LiteralValue::Int(x) => *x as f64, LiteralValue::Float(x) => *x, _ => 0.0,
+            }).sum();
+            if op == "sum" {
+                match ints.map(|ints| ints.iter().try_fold(0i64, |sum, x| sum.checked_add(*x))) {
+                    Some(None) => Err(format!("Integer overflow in sum of column '{}'", column.name)),
+                    Some(Some(sum)) => Ok(LiteralValue::Int(sum)),
+                    None => Ok(LiteralValue::Float(total)),
+                }
+            }
+            else if values.is_empty() { Ok(LiteralValue::Nil) }
+            else { Ok(LiteralValue::Float(total / values.len() as f64)) }
+        },
+        other => Err(format!("Unknown aggregation '{}'", other)),
+    }
+}
+/// Sums and means of a column holding decimals, which are exact and decimals themselves.
+fn decimal_aggregate(op: &str, column: &Column, values: &[&LiteralValue]) -> Result<LiteralValue, String> {
+    let mut total = Decimal::from_i64(0);
+    for value in values {
+        match value {
+            LiteralValue::Decimal(x) => total = total.add(x),
+            LiteralValue::Int(x) => total = total.add(&Decimal::from_i64(*x)),
+            _ => return Err(format!(
+                "Cannot {} column '{}' mixing decimals and floats, convert it with decimal() or float()", op, column.name
+            )),
+        }
+    }
+    if op == "sum" { return Ok(LiteralValue::Decimal(total)); }
+    Ok(total.div(&Decimal::from_i64(values.len() as i64)).map_or(LiteralValue::Nil, LiteralValue::Decimal))
+}
+/// `group_by(table, keys, aggregations)` where aggregations maps a column name to one of
+/// count, sum, mean, min, max, first or last. Each result column is named `<column>_<op>`.
+pub fn group_by_impl(args: &[LiteralValue]) -> Result<LiteralValue, RuntimeError> {
+    let table = expect_table(args, "group_by")?;
+    let key_names = expect_column_names(args.get(1), "group_by")?;
+    let keys = column_indices(&table, &key_names)?;
+    let mut aggregations = vec![];
+    match args.get(2) {
+        Some(LiteralValue::Map(entries)) => for (column, op) in entries.borrow().iter() {
+            match (column, op) {
+                (LiteralValue::StringValue(column), LiteralValue::StringValue(op)) => aggregations.push((column.clone(), op.clone())),
+                _ => return Err("group_by() aggregations must map column names to operation names".into()),
+            }
+        },
+        None => (),
+        Some(other) => return Err(format!("group_by() expects a map of aggregations, not {}", other.to_type()).into()),
+    }
+    let mut order: Vec<String> = vec![];
+    let mut groups: HashMap<String, Vec<usize>> = HashMap::new();
+    for row in 0..table.row_count() {
+        let key = row_key(&table, &keys, row);
+        if !groups.contains_key(&key) { order.push(key.clone()); }
+        groups.entry(key).or_default().push(row);
+    }
+    let firsts: Vec<usize> = order.iter().map(|key| groups[key][0]).collect();
+    let mut columns: Vec<Column> = keys.iter().map(|k| table.columns[*k].take(&firsts)).collect();
+    for (column, op) in aggregations.iter() {
+        let source = table.column(column)?;
+        let mut values = vec![];
+        for key in order.iter() { values.push(aggregate(op, source, &groups[key])?); }
+        columns.push(Column::new(&format!("{}_{}", column, op), values)?);
+    }
+    Ok(LiteralValue::Table(Rc::new(Table::new(columns)?)))
+}
+/// `join(left, right, on, how)` joins on equal key columns; `how` is "inner" (default) or "left".
+pub fn join_impl(args: &[LiteralValue]) -> Result<LiteralValue, RuntimeError> {
+    let left = expect_table(args, "join")?;
+    let right = expect_table(&args[1..], "join")?;
+    let on = expect_column_names(args.get(2), "join")?;
+    let how = match args.get(3) {
+        None => "inner".to_string(),
+        Some(LiteralValue::StringValue(how)) if how == "inner" || how == "left" => how.clone(),
+        Some(other) => return Err(format!("join() kind must be \"inner\" or \"left\", not {}", other.to_string()).into()),
+    };
+    let (left_keys, right_keys) = (column_indices(&left, &on)?, column_indices(&right, &on)?);
+    let mut index: HashMap<String, Vec<usize>> = HashMap::new();
+    for row in 0..right.row_count() {
+        index.entry(row_key(&right, &right_keys, row)).or_default().push(row);
+    }
+    let mut pairs: Vec<(usize, Option<usize>)> = vec![];
+    for row in 0..left.row_count() {
+        match index.get(&row_key(&left, &left_keys, row)) {
+            Some(matches) => pairs.extend(matches.iter().map(|m| (row, Some(*m)))),
+            None if how == "left" => pairs.push((row, None)),
+            None => (),
+        }
+    }
+    let mut columns = vec![];
+    for column in left.columns.iter() {
+        columns.push(Column::new(&column.name, pairs.iter().map(|(l, _)| column.values[*l].clone()).collect())?);
+    }
+    for (i, column) in right.columns.iter().enumerate() {
+        if right_keys.contains(&i) { continue; }
+        let name = if left.column_index(&column.name).is_ok() { format!("{}_right", column.name) } else { column.name.clone() };
+        let values = pairs.iter().map(|(_, r)| r.map_or(LiteralValue::Nil, |r| column.values[r].clone())).collect();
+        columns.push(Column::new(&name, values)?);
+    }
+    Ok(LiteralValue::Table(Rc::new(Table::new(columns)?)))
+}
+/// `head(table, n)` keeps the first `n` rows, 5 by default.
+pub fn head_impl(args: &[LiteralValue]) -> Result<LiteralValue, RuntimeError> {
+    let table = expect_table(args, "head")?;
+    let n = match args.get(1) {
+        None => 5,
+        Some(LiteralValue::Int(n)) if *n >= 0 => *n as usize,
+        Some(other) => return Err(format!("head() expects a row count, not {}", other.to_string()).into()),
+    };
+    let indices: Vec<usize> = (0..table.row_count().min(n)).collect();
+    Ok(LiteralValue::Table(Rc::new(table.take_rows(&indices))))
+}
+/// `distinct(table, columns)` drops rows repeating an earlier row, comparing all columns by default.
+pub fn distinct_impl(args: &[LiteralValue]) -> Result<LiteralValue, RuntimeError> {
+    let table = expect_table(args, "distinct")?;
+    let keys = match args.get(1) {
+        None => (0..table.columns.len()).collect(),
+        names => column_indices(&table, &expect_column_names(names, "distinct")?)?,
+    };
+    let mut seen = HashMap::new();
+    let mut indices = vec![];
+    for row in 0..table.row_count() {
+        if seen.insert(row_key(&table, &keys, row), ()).is_none() { indices.push(row); }
+    }
+    Ok(LiteralValue::Table(Rc::new(table.take_rows(&indices))))
+}
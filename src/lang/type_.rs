@@ -1,14 +1,113 @@
+use crate::scanner::Token;
+
+/// A type annotation as written in the source, e.g. `list<number> | nil` or `fun(string) -> bool`.
+/// Names are only looked up by the checker, since they may refer to classes declared later.
+#[derive(Debug, Clone)]
+pub enum TypeAnnotation {
+    Named { name: Token, args: Vec<TypeAnnotation> },
+    /// `params` is `None` for a bare `fun`, which accepts any function.
+    Function { params: Option<Vec<TypeAnnotation>>, ret: Option<Box<TypeAnnotation>> },
+    Union(Vec<TypeAnnotation>),
+}
+/// A type parameter declared on a class or function, e.g. `T` or `T: Comparable`.
+#[derive(Debug, Clone)]
+pub struct TypeParam {
+    pub name: Token,
+    pub bound: Option<TypeAnnotation>,
+}
 #[derive(Debug, PartialEq, Clone)]
 pub enum Type {
-    Int, String, Class(Box<Type>),
+    /// Anything without an annotation. It is accepted everywhere and accepts everything.
+    Any,
+    Nil, Bool, Int, Float, Decimal, String,
+    List(Box<Type>), Map, Table,
+    /// `params` is `None` when the arity is unknown, as for natives. `generics` pairs each type
+    /// parameter with its bound and is instantiated at every call.
+    Function { generics: Vec<(String, Type)>, params: Option<Vec<Type>>, ret: Box<Type> },
+    /// The class value itself, as opposed to one of its instances.
+    Class(String),
+    /// An instance with its type arguments, which are empty when they are not known.
+    Instance(String, Vec<Type>),
+    Union(Vec<Type>),
     Generic(String),
 }
 impl Type {
-    pub fn from_str(type_str: &str) -> Result<Type, String> {
+    /// Built-in type names. `number` is either an `int` or a `float`, and `Comparable` covers
+    /// the values that `<` and `>` accept.
+    pub fn from_str(type_str: &str) -> Option<Type> {
         match type_str {
-            "int" => Ok(Type::Int),
-            "string" => Ok(Type::String),
-            _ => Ok(Type::Generic(type_str.to_string())),
+            "any" => Some(Type::Any),
+            "nil" => Some(Type::Nil),
+            "bool" => Some(Type::Bool),
+            "int" => Some(Type::Int),
+            "float" => Some(Type::Float),
+            "decimal" => Some(Type::Decimal),
+            "number" => Some(Type::number()),
+            "string" => Some(Type::String),
+            "list" => Some(Type::List(Box::new(Type::Any))),
+            "map" => Some(Type::Map),
+            "table" => Some(Type::Table),
+            "Comparable" => Some(Type::Union(vec![Type::Int, Type::Float, Type::Decimal, Type::String])),
+            _ => None,
+        }
+    }
+    pub fn number() -> Type { Type::Union(vec![Type::Int, Type::Float]) }
+    /// Builds a union, flattening nested unions and dropping duplicates. `any` absorbs everything.
+    pub fn union(types: Vec<Type>) -> Type {
+        let mut members: Vec<Type> = vec![];
+        for t in types {
+            let flattened = match t {
+                Type::Union(inner) => inner,
+                Type::Any => return Type::Any,
+                other => vec![other],
+            };
+            for member in flattened {
+                if !members.contains(&member) { members.push(member); }
+            }
         }
+        if members.len() == 1 { members.remove(0) } else { Type::Union(members) }
     }
-}
\ No newline at end of file
+    pub fn is_known(&self) -> bool { !matches!(self, Type::Any | Type::Generic(_)) }
+}
+impl std::fmt::Display for Type {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let text = match self {
+            Type::Any => "any".to_string(),
+            Type::Nil => "nil".to_string(),
+            Type::Bool => "bool".to_string(),
+            Type::Int => "int".to_string(),
+            Type::Float => "float".to_string(),
+            Type::Decimal => "decimal".to_string(),
+            Type::String => "string".to_string(),
+            Type::List(inner) if **inner == Type::Any => "list".to_string(),
+            Type::List(inner) => format!("list<{}>", inner),
+            Type::Map => "map".to_string(),
+            Type::Table => "table".to_string(),
+            Type::Function { generics: _, params: None, ret: _ } => "fun".to_string(),
+            Type::Function { generics: _, params: Some(params), ret } => format!(
+                "fun({}) -> {}", params.iter().map(|p| p.to_string()).collect::<Vec<String>>().join(", "),
+                ret
+            ),
+            Type::Class(name) => format!("class {}", name),
+            Type::Instance(name, args) if args.is_empty() => name.clone(),
+            Type::Instance(name, args) => format!(
+                "{}<{}>", name, args.iter().map(|a| a.to_string()).collect::<Vec<String>>().join(", ")
+            ),
+            // `int | float` is shown by its name, where the first of the two appears
+            Type::Union(members) if members.contains(&Type::Int) && members.contains(&Type::Float) => {
+                let mut names: Vec<String> = vec![];
+                for member in members {
+                    match member {
+                        Type::Int | Type::Float if names.iter().any(|n| n == "number") => (),
+                        Type::Int | Type::Float => names.push("number".to_string()),
+                        other => names.push(other.to_string()),
+                    }
+                }
+                names.join(" | ")
+            },
+            Type::Union(members) => members.iter().map(|m| m.to_string()).collect::<Vec<String>>().join(" | "),
+            Type::Generic(name) => name.clone(),
+        };
+        f.write_str(&text)
+    }
+}
@@ -0,0 +1,507 @@
+use crate::chunk::{Function, Op};
+use crate::environment;
+use crate::error::RuntimeError;
+use crate::expr::{self, CallableImpl, LiteralValue};
+use crate::compiler::Compiler;
+use crate::command;
+use crate::module::{Import, ModuleLoader};
+use crate::permissions::Permissions;
+use crate::scanner::Token;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+const MAX_FRAMES: usize = 10000;
+
+/// A captured variable: still living in a stack slot, or moved out once that slot went away.
+pub enum Upvalue {
+    Open(usize),
+    Closed(Box<LiteralValue>),
+}
+/// State shared by every VM running the same program, including the nested ones started
+/// when a native calls back into a closure.
+pub struct Runtime {
+    stack: RefCell<Vec<LiteralValue>>,
+    permissions: Rc<RefCell<Permissions>>,
+}
+pub type Globals = Rc<RefCell<HashMap<String, LiteralValue>>>;
+#[derive(Clone)]
+pub struct ClosureImpl {
+    pub function: Rc<Function>,
+    pub upvalues: Rc<Vec<Rc<RefCell<Upvalue>>>>,
+    /// The instance a method was bound to, passed in slot 0.
+    pub receiver: Option<Rc<LiteralValue>>,
+    runtime: Rc<Runtime>,
+    /// The globals of the file the closure was defined in.
+    globals: Globals,
+}
+impl ClosureImpl {
+    pub fn bind(&self, instance: LiteralValue) -> Self {
+        Self { receiver: Some(Rc::new(instance)), ..self.clone() }
+    }
+}
+struct Frame {
+    /// Shared so the run loop can hold on to it without cloning the closure per instruction.
+    closure: Rc<ClosureImpl>,
+    ip: usize,
+    base: usize,
+    /// Initializers return their instance whatever the body returns.
+    init: bool,
+    stashed: LiteralValue,
+}
+struct Handler {
+    frames: usize,
+    stack_len: usize,
+    target: usize,
+}
+pub struct VM {
+    runtime: Rc<Runtime>,
+    globals: Globals,
+    /// Only the VMs running a file's top level import, since imports are not allowed elsewhere.
+    loader: Option<Rc<RefCell<ModuleLoader>>>,
+    stack: Vec<LiteralValue>,
+    frames: Vec<Frame>,
+    handlers: Vec<Handler>,
+    /// Errors caught by a handler whose finally block may still have to raise them again.
+    pending: Vec<RuntimeError>,
+    open_upvalues: Vec<Rc<RefCell<Upvalue>>>,
+}
+impl VM {
+    pub fn new(loader: Rc<RefCell<ModuleLoader>>, permissions: Rc<RefCell<Permissions>>) -> Self {
+        let globals = environment::get_globals(&permissions);
+        let runtime = Runtime { stack: RefCell::new(vec![]), permissions };
+        Self { loader: Some(loader), ..Self::with_runtime(Rc::new(runtime), globals) }
+    }
+    fn with_runtime(runtime: Rc<Runtime>, globals: Globals) -> Self {
+        Self {
+            runtime, globals, loader: None,
+            stack: vec![], frames: vec![], handlers: vec![], pending: vec![], open_upvalues: vec![],
+        }
+    }
+    /// Runs a compiled script, returning its result.
+    pub fn interpret(&mut self, function: Function) -> Result<LiteralValue, RuntimeError> {
+        let closure = ClosureImpl {
+            function: Rc::new(function), upvalues: Rc::new(vec![]),
+            receiver: None, runtime: self.runtime.clone(), globals: self.globals.clone(),
+        };
+        let base = self.stack.len();
+        self.stack.push(LiteralValue::Callable(CallableImpl::Closure(closure.clone())));
+        self.push_frame(closure, base, 0, false, None)?;
+        self.run(0)
+    }
+    pub fn globals(&self) -> Globals { self.globals.clone() }
+    fn run(&mut self, entry_depth: usize) -> Result<LiteralValue, RuntimeError> {
+        loop {
+            match self.execute(entry_depth) {
+                Ok(value) => return Ok(value),
+                Err(error) => self.recover(error, entry_depth)?,
+            }
+        }
+    }
+    /// Hands the error to the innermost handler of this run, or unwinds the run and returns it.
+    fn recover(&mut self, mut error: RuntimeError, entry_depth: usize) -> Result<(), RuntimeError> {
+        if let Some(handler) = self.handlers.pop_if(|h| h.frames > entry_depth) {
+            while self.frames.len() > handler.frames { error = self.unwind_frame(error, entry_depth); }
+            self.close_upvalues(handler.stack_len);
+            self.stack.truncate(handler.stack_len);
+            self.stack.push(error.to_value());
+            self.pending.push(error);
+            if let Some(frame) = self.frames.last_mut() { frame.ip = handler.target; }
+            return Ok(());
+        }
+        while self.frames.len() > entry_depth { error = self.unwind_frame(error, entry_depth); }
+        Err(error)
+    }
+    /// Drops the innermost frame, locating the error at the call that created it if it has no line yet.
+    fn unwind_frame(&mut self, error: RuntimeError, entry_depth: usize) -> RuntimeError {
+        let Some(frame) = self.frames.pop() else { return error };
+        self.close_upvalues(frame.base);
+        self.stack.truncate(frame.base);
+        if self.frames.len() <= entry_depth { return error; }
+        match self.frames.last().and_then(|caller| caller.closure.function.chunk.tokens[caller.ip - 1].clone()) {
+            Some(token) => error.or_at(&token),
+            None => error,
+        }
+    }
+    fn current_closure(&self) -> Option<Rc<ClosureImpl>> { self.frames.last().map(|frame| frame.closure.clone()) }
+    fn pop(&mut self) -> LiteralValue { self.stack.pop().unwrap_or(LiteralValue::Nil) }
+    fn peek(&self) -> LiteralValue { self.stack.last().cloned().unwrap_or(LiteralValue::Nil) }
+    fn jump(&mut self, target: usize) {
+        if let Some(frame) = self.frames.last_mut() { frame.ip = target; }
+    }
+    fn capture_upvalue(&mut self, slot: usize) -> Rc<RefCell<Upvalue>> {
+        for upvalue in &self.open_upvalues {
+            if matches!(*upvalue.borrow(), Upvalue::Open(open) if open == slot) { return upvalue.clone(); }
+        }
+        let upvalue = Rc::new(RefCell::new(Upvalue::Open(slot)));
+        self.open_upvalues.push(upvalue.clone());
+        upvalue
+    }
+    /// Moves every captured stack slot at or above `from` into its upvalue.
+    fn close_upvalues(&mut self, from: usize) {
+        let stack = &self.stack;
+        self.open_upvalues.retain(|upvalue| {
+            let slot = match *upvalue.borrow() {
+                Upvalue::Open(slot) => slot,
+                Upvalue::Closed(_) => return false,
+            };
+            if slot < from { return true; }
+            let value = stack.get(slot).cloned().unwrap_or(LiteralValue::Nil);
+            *upvalue.borrow_mut() = Upvalue::Closed(Box::new(value));
+            false
+        });
+    }
+    fn push_frame(
+        &mut self, closure: ClosureImpl, base: usize, argc: usize, init: bool, token: Option<&Token>
+    ) -> Result<(), RuntimeError> {
+        if argc != closure.function.arity {
+            return Err(error_at(token, &format!(
+                "Callable {} expected {} arguments but got {}",
+                closure.function.name, closure.function.arity, argc
+            )));
+        }
+        if self.frames.len() >= MAX_FRAMES { return Err(error_at(token, "Stack overflow")); }
+        if let Some(receiver) = &closure.receiver { self.stack[base] = (**receiver).clone(); }
+        self.frames.push(Frame { closure: Rc::new(closure), ip: 0, base, init, stashed: LiteralValue::Nil });
+        Ok(())
+    }
+    fn call(&mut self, argc: usize, paren: &Token) -> Result<(), RuntimeError> {
+        let base = self.stack.len() - 1 - argc;
+        match self.stack[base].clone() {
+            LiteralValue::Callable(CallableImpl::Closure(closure)) => self.push_frame(closure, base, argc, false, Some(paren)),
+            LiteralValue::Callable(callable) => {
+                let arguments = self.stack.split_off(base + 1);
+                self.stack.pop();
+                // Natives may call back into closures, which run on the shared stack
+                std::mem::swap(&mut self.stack, &mut self.runtime.stack.borrow_mut());
+                let result = expr::call_value(&LiteralValue::Callable(callable), arguments);
+                std::mem::swap(&mut self.stack, &mut self.runtime.stack.borrow_mut());
+                self.stack.push(result.map_err(|e| e.or_at(paren))?);
+                Ok(())
+            },
+            class @ LiteralValue::FluxarClass { .. } => {
+                let instance = LiteralValue::FluxarInstance {
+                    class: Box::new(class.clone()),
+                    fields: Rc::new(RefCell::new(vec![])),
+                };
+                let init = match &class {
+                    LiteralValue::FluxarClass { methods, .. } => methods.get("init").cloned(),
+                    _ => None,
+                };
+                match init {
+                    Some(CallableImpl::Closure(init)) => {
+                        if init.function.arity != argc {
+                            return Err(RuntimeError::new(paren, "Invalid number of arguments in constructor"));
+                        }
+                        self.push_frame(init.bind(instance), base, argc, true, Some(paren))
+                    },
+                    _ => {
+                        self.stack.truncate(base);
+                        self.stack.push(instance);
+                        Ok(())
+                    },
+                }
+            },
+            other => Err(RuntimeError::new(paren, &format!("{} is not callable", other.to_type()))),
+        }
+    }
+    /// Pops the current frame, returning the value when it was the frame this run started with.
+    fn return_from_frame(&mut self, value: LiteralValue, entry_depth: usize) -> Option<LiteralValue> {
+        let frame = self.frames.pop()?;
+        let value = if frame.init { self.stack[frame.base].clone() } else { value };
+        self.close_upvalues(frame.base);
+        self.stack.truncate(frame.base);
+        while self.handlers.last().is_some_and(|h| h.frames > self.frames.len()) { self.handlers.pop(); }
+        if self.frames.len() <= entry_depth { return Some(value); }
+        self.stack.push(value);
+        None
+    }
+    /// Compiles and runs the module at `path` with globals of its own, unless it already ran.
+    fn import(&mut self, path: &Token) -> Result<LiteralValue, RuntimeError> {
+        let Some(loader) = self.loader.clone() else {
+            return Err(RuntimeError::new(path, "Imports are only allowed at the top level of a file"));
+        };
+        let begun = loader.borrow_mut().begin(path)?;
+        let module = match begun {
+            Import::Cached(module) => return Ok(module),
+            Import::Load(module) => module,
+        };
+        let globals = environment::get_globals(&self.runtime.permissions);
+        let mut vm = VM { loader: Some(loader.clone()), ..VM::with_runtime(self.runtime.clone(), globals.clone()) };
+        let result = Compiler::new(self.runtime.permissions.clone()).compile(&module.statements.iter().collect())
+            .map_err(|msg| RuntimeError::new(path, &msg))
+            .and_then(|function| vm.interpret(function))
+            .map(|_| LiteralValue::Module { name: module.name, globals });
+        let module = loader.borrow_mut().finish(result);
+        module
+    }
+    fn execute(&mut self, entry_depth: usize) -> Result<LiteralValue, RuntimeError> {
+        // Only calls and returns change the current frame, and they refresh this
+        let Some(mut closure) = self.current_closure() else { return Ok(LiteralValue::Nil) };
+        loop {
+            let Some(frame) = self.frames.last_mut() else { return Ok(LiteralValue::Nil) };
+            let (ip, base) = (frame.ip, frame.base);
+            frame.ip += 1;
+            let chunk = &closure.function.chunk;
+            let token = chunk.tokens[ip].as_ref();
+            match chunk.code[ip] {
+                Op::Constant(index) => self.stack.push(chunk.constants[index].clone()),
+                Op::Nil => self.stack.push(LiteralValue::Nil),
+                Op::True => self.stack.push(LiteralValue::True),
+                Op::False => self.stack.push(LiteralValue::False),
+                Op::Pop => { self.pop(); },
+                Op::Dup(count) => {
+                    let top = self.stack.len() - count;
+                    self.stack.extend_from_within(top..);
+                },
+                Op::Bury(depth) => {
+                    let value = self.pop();
+                    self.stack.insert(self.stack.len() - depth, value);
+                },
+                Op::Truncate(slots) => {
+                    self.close_upvalues(base + slots);
+                    self.stack.truncate(base + slots);
+                },
+                Op::GetLocal(slot) => self.stack.push(self.stack[base + slot].clone()),
+                Op::SetLocal(slot) => self.stack[base + slot] = self.peek(),
+                Op::GetGlobal => {
+                    let name = named(token)?;
+                    let value = closure.globals.borrow().get(&name.lexeme).cloned();
+                    match value {
+                        Some(value) => self.stack.push(value),
+                        None => return Err(RuntimeError::new(name, &format!(
+                            "Variable '{}' has not been declared at distance None", name.lexeme
+                        ))),
+                    }
+                },
+                Op::SetGlobal => {
+                    let name = named(token)?;
+                    let mut globals = closure.globals.borrow_mut();
+                    match globals.get_mut(&name.lexeme) {
+                        Some(value) => *value = self.peek(),
+                        None => return Err(RuntimeError::new(name, &format!(
+                            "Variable '{}' has not been declared.", name.lexeme
+                        ))),
+                    }
+                },
+                Op::DefineGlobal => {
+                    let value = self.pop();
+                    closure.globals.borrow_mut().insert(named(token)?.lexeme.clone(), value);
+                },
+                Op::GetUpvalue(index) => {
+                    let value = match &*closure.upvalues[index].borrow() {
+                        Upvalue::Open(slot) => self.stack[*slot].clone(),
+                        Upvalue::Closed(value) => (**value).clone(),
+                    };
+                    self.stack.push(value);
+                },
+                Op::SetUpvalue(index) => {
+                    let value = self.peek();
+                    match &mut *closure.upvalues[index].borrow_mut() {
+                        Upvalue::Open(slot) => self.stack[*slot] = value,
+                        Upvalue::Closed(closed) => **closed = value,
+                    }
+                },
+                Op::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len().saturating_sub(1));
+                    self.pop();
+                },
+                Op::GetProperty => {
+                    let object = self.pop();
+                    self.stack.push(expr::get_property(named(token)?, object)?);
+                },
+                Op::SetProperty => {
+                    let value = self.pop();
+                    let object = self.pop();
+                    self.stack.push(expr::set_property(named(token)?, object, value)?);
+                },
+                Op::GetSuper => {
+                    let method = named(token)?;
+                    let superclass = self.pop();
+                    let instance = self.pop();
+                    match &superclass {
+                        LiteralValue::FluxarClass { methods, .. } => match methods.get(&method.lexeme) {
+                            Some(method_value) => self.stack.push(expr::bind_method(method_value, &instance)),
+                            None => return Err(RuntimeError::new(method, &format!(
+                                "No method named {} on superclass {}", method.lexeme, superclass.to_type()
+                            ))),
+                        },
+                        other => return Err(RuntimeError::new(method, &format!(
+                            "Superclass must be a class, not {}", other.to_type()
+                        ))),
+                    }
+                },
+                Op::Index => {
+                    let index = self.pop();
+                    let object = self.pop();
+                    self.stack.push(expr::index_op(named(token)?, object, index)?);
+                },
+                Op::IndexSet => {
+                    let value = self.pop();
+                    let index = self.pop();
+                    let object = self.pop();
+                    self.stack.push(expr::index_set_op(named(token)?, object, index, value)?);
+                },
+                Op::Slice => {
+                    let end = self.pop();
+                    let start = self.pop();
+                    let object = self.pop();
+                    self.stack.push(expr::slice_op(named(token)?, object, Some(start), Some(end))?);
+                },
+                Op::Interpolate(count) => {
+                    let parts = self.stack.split_off(self.stack.len() - count);
+                    self.stack.push(LiteralValue::StringValue(parts.iter().map(LiteralValue::to_text).collect()));
+                },
+                Op::List(count) => {
+                    let items = self.stack.split_off(self.stack.len() - count);
+                    self.stack.push(LiteralValue::List(Rc::new(RefCell::new(items))));
+                },
+                Op::Map(count) => {
+                    let flat = self.stack.split_off(self.stack.len() - 2 * count);
+                    let mut entries = vec![];
+                    let mut flat = flat.into_iter();
+                    while let (Some(key), Some(value)) = (flat.next(), flat.next()) {
+                        if !key.is_valid_key() {
+                            return Err(RuntimeError::new(named(token)?, &format!(
+                                "{} cannot be used as a map key", key.to_type()
+                            )));
+                        }
+                        expr::map_insert(&mut entries, key, value);
+                    }
+                    self.stack.push(LiteralValue::new_map(entries));
+                },
+                Op::Binary => {
+                    let right = self.pop();
+                    let left = self.pop();
+                    self.stack.push(expr::binary_op(named(token)?, left, right)?);
+                },
+                Op::Unary => {
+                    let right = self.pop();
+                    self.stack.push(expr::unary_op(named(token)?, right)?);
+                },
+                Op::Print => println!("{}", self.pop().to_string()),
+                Op::Jump(target) => self.jump(target),
+                Op::JumpIfFalse(target) => {
+                    if self.pop().is_true()? == LiteralValue::False { self.jump(target); }
+                },
+                Op::And(target) => {
+                    let truth = self.peek().is_true().map_err(|e| or_at(e, token))?;
+                    if truth == LiteralValue::False {
+                        self.pop();
+                        self.stack.push(LiteralValue::False);
+                        self.jump(target);
+                    } else { self.pop(); }
+                },
+                Op::Or(target) => {
+                    let truth = self.peek().is_true().map_err(|e| or_at(e, token))?;
+                    if truth == LiteralValue::True { self.jump(target); } else { self.pop(); }
+                },
+                Op::Call(argc) => {
+                    self.call(argc, named(token)?)?;
+                    if let Some(current) = self.current_closure() { closure = current; }
+                },
+                Op::Closure(index) => {
+                    let function = chunk.functions[index].clone();
+                    let upvalues = function.upvalues.iter().map(|desc| {
+                        if desc.is_local { self.capture_upvalue(base + desc.index) }
+                        else { closure.upvalues[desc.index].clone() }
+                    }).collect();
+                    self.stack.push(LiteralValue::Callable(CallableImpl::Closure(ClosureImpl {
+                        function, upvalues: Rc::new(upvalues),
+                        receiver: None, runtime: self.runtime.clone(), globals: closure.globals.clone(),
+                    })));
+                },
+                Op::Class(template, count, has_super) => {
+                    let closures = self.stack.split_off(self.stack.len() - count);
+                    let superclass = if has_super {
+                        match self.peek() {
+                            superclass @ LiteralValue::FluxarClass { .. } => Some(Box::new(superclass)),
+                            other => return Err(RuntimeError::new(named(token)?, &format!(
+                                "Superclass must be a class, not {}", other.to_type()
+                            ))),
+                        }
+                    } else { None };
+                    let mut class = chunk.constants[template].clone();
+                    if let LiteralValue::FluxarClass { methods, superclass: class_superclass, .. } = &mut class {
+                        for method in closures {
+                            if let LiteralValue::Callable(CallableImpl::Closure(method)) = method {
+                                methods.insert(method.function.name.clone(), CallableImpl::Closure(method));
+                            }
+                        }
+                        *class_superclass = superclass;
+                    }
+                    self.stack.push(class);
+                },
+                Op::PipeSource => {
+                    let value = self.pop();
+                    self.stack.push(command::pipe_source(value).map_err(|e| or_at(e, token))?);
+                },
+                Op::Import => {
+                    let module = self.import(named(token)?)?;
+                    self.stack.push(module);
+                },
+                Op::Return => {
+                    let value = self.pop();
+                    if let Some(value) = self.return_from_frame(value, entry_depth) { return Ok(value); }
+                    if let Some(current) = self.current_closure() { closure = current; }
+                },
+                Op::StashReturn => {
+                    let value = self.pop();
+                    if let Some(frame) = self.frames.last_mut() { frame.stashed = value; }
+                },
+                Op::ReturnStashed => {
+                    let value = match self.frames.last_mut() {
+                        Some(frame) => std::mem::replace(&mut frame.stashed, LiteralValue::Nil),
+                        None => LiteralValue::Nil,
+                    };
+                    if let Some(value) = self.return_from_frame(value, entry_depth) { return Ok(value); }
+                    if let Some(current) = self.current_closure() { closure = current; }
+                },
+                Op::Throw => {
+                    let value = self.pop();
+                    return Err(RuntimeError::thrown(named(token)?, value));
+                },
+                Op::PushHandler(target) => self.handlers.push(Handler {
+                    frames: self.frames.len(), stack_len: self.stack.len(), target,
+                }),
+                Op::PopHandler => { self.handlers.pop(); },
+                Op::PopPending => { self.pending.pop(); },
+                Op::Rethrow => return Err(self.pending.pop().unwrap_or_else(|| "Nothing to rethrow".into())),
+            }
+        }
+    }
+}
+/// Calls a closure from outside the VM, e.g. as a callback given to a native.
+pub fn call_closure(closure: &ClosureImpl, arguments: Vec<LiteralValue>) -> Result<LiteralValue, RuntimeError> {
+    if arguments.len() != closure.function.arity {
+        return Err(format!(
+            "Callable {} expected {} arguments but got {}",
+            closure.function.name, closure.function.arity, arguments.len()
+        ).into());
+    }
+    let mut vm = VM::with_runtime(closure.runtime.clone(), closure.globals.clone());
+    vm.stack = std::mem::take(&mut closure.runtime.stack.borrow_mut());
+    let (base, argc) = (vm.stack.len(), arguments.len());
+    vm.stack.push(LiteralValue::Callable(CallableImpl::Closure(closure.clone())));
+    vm.stack.extend(arguments);
+    let result = vm.push_frame(closure.clone(), base, argc, false, None).and_then(|_| vm.run(0));
+    vm.stack.truncate(base);
+    *closure.runtime.stack.borrow_mut() = std::mem::take(&mut vm.stack);
+    result
+}
+fn named(token: Option<&Token>) -> Result<&Token, RuntimeError> {
+    token.ok_or_else(|| "Instruction is missing its token".into())
+}
+fn or_at(error: RuntimeError, token: Option<&Token>) -> RuntimeError {
+    match token {
+        Some(token) => error.or_at(token),
+        None => error,
+    }
+}
+fn error_at(token: Option<&Token>, message: &str) -> RuntimeError {
+    match token {
+        Some(token) => RuntimeError::new(token, message),
+        None => message.into(),
+    }
+}
@@ -10,7 +10,8 @@ mod tests {
         let mut msgs = vec![];
         for case in cases {
             let (case, name) = (case.unwrap(), case.path().display().to_string());
-            if name.contains("~") { continue; }
+            // Directories hold modules imported by the cases
+            if name.contains("~") || case.path().is_dir() { continue; }
             match run_test(case) { 
                 Ok(_) => { msgs.push(format!("Running {name:.<85}...ok")); },
                 Err(msg) => {
@@ -49,6 +50,7 @@ mod tests {
         }
         let input = test_code.join("\n");
         let output = Command::new("cargo").arg("run")
+            .env("FLUXAR_PATH", "/Users/alex/Documents/GitHub/Fluxar/src/tests/cases/modules")
             .arg("e").arg(input).output().unwrap()
             .split("\n").collect::<Vec<&str>>();
         if !(lines.len() == expected_output.len() || lines.len() == expected_output.len() + 1) {